@@ -1,4 +1,11 @@
+use std::{env, path::PathBuf};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/chat.proto")?;
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    // served by the reflection service
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("chat_descriptor.bin"))
+        .compile(&["proto/chat.proto"], &["proto"])?;
+    tonic_build::compile_protos("proto/reflection.proto")?;
     Ok(())
 }
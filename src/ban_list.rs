@@ -0,0 +1,91 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+
+/// Names banned by `!ban`, persisted one per line so bans survive a restart.
+///
+/// Users choose their own names, so a banned user can simply come back under another one. This
+/// keeps out the careless, not the determined.
+pub struct BanList {
+    path: Option<PathBuf>,
+    names: BTreeSet<String>,
+}
+
+impl BanList {
+    /// Reads the bans saved at `path`, a missing file holds none. Without a path bans only last
+    /// until the server stops.
+    pub fn open(path: Option<PathBuf>) -> io::Result<BanList> {
+        let names = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(text) => text
+                    .lines()
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                Err(e) if e.kind() == ErrorKind::NotFound => BTreeSet::new(),
+                Err(e) => return Err(e),
+            },
+            None => BTreeSet::new(),
+        };
+        Ok(BanList { path, names })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    /// Bans `name`, false if it already was. The ban holds even if saving it fails.
+    pub fn ban(&mut self, name: &str) -> io::Result<bool> {
+        if !self.names.insert(name.to_string()) {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Lifts the ban on `name`, false if there was none.
+    pub fn unban(&mut self, name: &str) -> io::Result<bool> {
+        if !self.names.remove(name) {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    /// Rewrites the whole file through a temporary one, so a crash never leaves half a list.
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut text = String::new();
+        for name in &self.names {
+            text.push_str(name);
+            text.push('\n');
+        }
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, text)?;
+        fs::rename(&temporary, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("nc_bans_{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut bans = BanList::open(Some(path.clone())).unwrap();
+        assert!(bans.ban("mallory").unwrap());
+        assert!(bans.ban("trudy").unwrap());
+        assert!(!bans.ban("mallory").unwrap());
+        assert!(bans.unban("trudy").unwrap());
+        assert!(!bans.unban("trudy").unwrap());
+
+        let bans = BanList::open(Some(path.clone())).unwrap();
+        assert!(bans.contains("mallory"));
+        assert!(!bans.contains("trudy"));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+/// Evaluates the arithmetic expressions of `!calc`: numbers, `+ - * / %`, `^` for powers,
+/// unary minus and parentheses, with the usual precedence. Powers are right-associative.
+pub fn evaluate(expression: &str) -> Result<f64, String> {
+    let mut parser = Parser {
+        chars: expression.chars().collect(),
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.expression()?;
+    if let Some(c) = parser.peek() {
+        return Err(format!("Unexpected {}", c));
+    }
+    // like the root of a negative number, or infinities cancelling out
+    if value.is_nan() {
+        return Err(String::from("The result is undefined"));
+    }
+    if value.is_infinite() {
+        return Err(String::from("The result is too large"));
+    }
+    Ok(value)
+}
+
+/// How deeply parentheses and unary minuses may nest, so an expression can't overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    /// The next character that isn't whitespace.
+    fn peek(&mut self) -> Option<char> {
+        while self
+            .chars
+            .get(self.position)
+            .is_some_and(|c| c.is_whitespace())
+        {
+            self.position += 1;
+        }
+        self.chars.get(self.position).copied()
+    }
+
+    fn next_if(&mut self, expected: &[char]) -> Option<char> {
+        let c = self.peek().filter(|c| expected.contains(c))?;
+        self.position += 1;
+        Some(c)
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        while let Some(operator) = self.next_if(&['+', '-']) {
+            let rhs = self.term()?;
+            value = if operator == '+' {
+                value + rhs
+            } else {
+                value - rhs
+            };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        while let Some(operator) = self.next_if(&['*', '/', '%']) {
+            let rhs = self.unary()?;
+            value = match operator {
+                '*' => value * rhs,
+                _ if rhs == 0.0 => return Err(String::from("Division by zero")),
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    /// A minus binds looser than a power, `-2^2` is `-4`.
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.next_if(&['-']).is_some() {
+            return Ok(-self.nested(Parser::unary)?);
+        }
+        self.power()
+    }
+
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.primary()?;
+        if self.next_if(&['^']).is_some() {
+            let exponent = self.nested(Parser::unary)?;
+            return Ok(base.powf(exponent));
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let value = self.nested(Parser::expression)?;
+                self.next_if(&[')'])
+                    .ok_or_else(|| String::from("Missing )"))?;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) => Err(format!("Unexpected {}", c)),
+            None => Err(String::from("Unexpected end of the expression")),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let start = self.position;
+        while self
+            .chars
+            .get(self.position)
+            .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+        {
+            self.position += 1;
+        }
+        let number: String = self.chars[start..self.position].iter().collect();
+        number
+            .parse()
+            .map_err(|_| format!("{} isn't a number", number))
+    }
+
+    fn nested(&mut self, parse: fn(&mut Parser) -> Result<f64, String>) -> Result<f64, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(String::from("The expression is nested too deeply"));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_with_precedence() {
+        assert_eq!(evaluate("(3+4)*2"), Ok(14.0));
+        assert_eq!(evaluate("3 + 4 * 2"), Ok(11.0));
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3.0));
+        assert_eq!(evaluate("2^3^2"), Ok(512.0));
+        assert_eq!(evaluate("-2^2"), Ok(-4.0));
+        assert_eq!(evaluate("2^-1"), Ok(0.5));
+        assert_eq!(evaluate("7 % 4 / 2"), Ok(1.5));
+        assert_eq!(evaluate("--1.5"), Ok(1.5));
+        assert_eq!(evaluate(".5*4"), Ok(2.0));
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(evaluate("1/0"), Err(String::from("Division by zero")));
+        assert_eq!(evaluate("1 % (2-2)"), Err(String::from("Division by zero")));
+        assert_eq!(evaluate("(1+2"), Err(String::from("Missing )")));
+        assert_eq!(evaluate("1+2)"), Err(String::from("Unexpected )")));
+        assert_eq!(
+            evaluate("1+"),
+            Err(String::from("Unexpected end of the expression"))
+        );
+        assert_eq!(evaluate("2*x"), Err(String::from("Unexpected x")));
+        assert_eq!(evaluate("1 2"), Err(String::from("Unexpected 2")));
+        assert_eq!(evaluate("1.2.3"), Err(String::from("1.2.3 isn't a number")));
+        assert_eq!(
+            evaluate("10^1000"),
+            Err(String::from("The result is too large"))
+        );
+        assert_eq!(
+            evaluate("(-8)^0.5"),
+            Err(String::from("The result is undefined"))
+        );
+        assert_eq!(
+            evaluate("10^400 - 10^400"),
+            Err(String::from("The result is undefined"))
+        );
+        assert!(evaluate(&"(".repeat(1000)).is_err());
+    }
+}
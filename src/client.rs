@@ -1,128 +1,1431 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::env;
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use chat::chat_client::ChatClient;
-use chat::{ChatMessage, NameCheckRequest};
+use chat::{ChatMessage, GetHistoryRequest, Kind, NameCheckRequest, Style};
+use compression::Gzip;
 
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::sync::Mutex;
-use tonic::Request;
+use config_file::ConfigFile;
+use line_editor::{Completer, Input, LineEditor};
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Status};
 
-use chrono::naive::NaiveDateTime;
+use chrono::{Local, TimeZone};
 use colored::*;
 
+mod compression;
+mod config_file;
+mod emoji;
+mod gzip;
+mod line_editor;
+
 pub mod chat {
     tonic::include_proto!("chat");
 }
 
+/// Marker the server puts in front of `content` for `!me` action messages.
+const ACTION_MARKER: &str = "\u{1}ACTION ";
+/// Content of the typing events the server relays for `!typing`, any other typing event is
+/// for `!typing stop`.
+const TYPING_MARKER: &str = "\u{1}TYPING";
+/// How long a typing indicator stays up without a fresh typing event.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+/// HTTP/2 keepalive pings, so a server that vanished without closing the connection is noticed
+/// even while nothing is being sent.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Reconnect attempts after losing the server unless `NC_RECONNECT_ATTEMPTS` or the config
+/// file say otherwise, waiting twice as long after every failed one.
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 8;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Largest message accepted from the server once decompressed, tonic's default decoding limit.
+const MAX_DECOMPRESSED: usize = 4 * 1024 * 1024;
+
+/// Narrowest column messages are wrapped to, however little room the terminal leaves.
+const MIN_WRAP_WIDTH: usize = 20;
+
+/// How soon after a message the next one from the same sender is grouped with it.
+const GROUPING_WINDOW: Duration = Duration::from_secs(2 * 60);
+
+/// Number of messages kept for scrolling back with Page Up.
+const MAX_SCROLLBACK: usize = 1000;
+
+/// Written to the config file when there is none yet.
+const DEFAULT_CONFIG: &str = r#"# novachat settings, options given on the command line take precedence.
+
+# server to connect to unless one is given on the command line
+server = "http://[::1]:50051"
+# set to false to print without colors, like --no-color
+color = true
+# how message times are shown: "24h", "12h" (like --12h) or "relative" (like --relative-time)
+time_format = "24h"
+# room to join once a name is chosen
+room = "public"
+# how often to try reconnecting after losing the server
+reconnect_attempts = 8
+# users whose messages aren't shown, kept up to date by !ignore and !unignore
+ignored = ""
+"#;
+
+/// Commands offered by tab completion.
+const COMMANDS: &[&str] = &[
+    "!join",
+    "!user",
+    "!value",
+    "!topic",
+    "!history",
+    "!search",
+    "!kick",
+    "!me",
+    "!dm",
+    "!whisper",
+    "!reply",
+    "!edit",
+    "!delete",
+    "!react",
+    "!news",
+    "!status",
+    "!roll",
+    "!8ball",
+    "!weather",
+    "!calc",
+    "!uptime",
+    "!stats",
+    "!ping",
+    "!joke",
+    "!wiki",
+    "!crates",
+    "!announce",
+    "!ban",
+    "!unban",
+    "!block",
+    "!unblock",
+    "!help",
+    "!ignore",
+    "!unignore",
+    "!clear",
+];
+/// Commands whose first argument is a user name.
+const USER_COMMANDS: &[&str] = &[
+    "!dm",
+    "!whisper",
+    "!kick",
+    "!ban",
+    "!unban",
+    "!block",
+    "!unblock",
+    "!ignore",
+    "!unignore",
+];
+
+/// Defaults read from the config file, which is `~/.config/convers/config.toml` unless
+/// `--config=<path>` points elsewhere.
+struct ClientConfig {
+    server: String,
+    color: bool,
+    time_format: TimeFormat,
+    room: String,
+    reconnect_attempts: u32,
+    /// Users whose messages aren't shown.
+    ignored: BTreeSet<String>,
+    /// Where the config was read from, for saving changes to `ignored`.
+    path: Option<PathBuf>,
+}
+
+impl ClientConfig {
+    /// Reads the config file, writing one with the defaults first if there is none. Only a
+    /// file that can't be parsed is an error.
+    fn load() -> Result<ClientConfig, String> {
+        let path = env::args()
+            .find_map(|arg| arg.strip_prefix("--config=").map(PathBuf::from))
+            .or_else(|| {
+                env::var_os("XDG_CONFIG_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+                    .map(|config| config.join("convers").join("config.toml"))
+            });
+        let Some(path) = path else {
+            return ClientConfig::parse(DEFAULT_CONFIG);
+        };
+        let text = fs::read_to_string(&path).unwrap_or_else(|_| {
+            // a config that can't be written just leaves the defaults in effect
+            let _ = path
+                .parent()
+                .map(fs::create_dir_all)
+                .transpose()
+                .and_then(|_| fs::write(&path, DEFAULT_CONFIG));
+            DEFAULT_CONFIG.to_string()
+        });
+        let config = ClientConfig::parse(&text)
+            .map_err(|e| format!("invalid config {}: {}", path.display(), e))?;
+        Ok(ClientConfig {
+            path: Some(path),
+            ..config
+        })
+    }
+
+    fn parse(text: &str) -> Result<ClientConfig, String> {
+        let file = ConfigFile::parse(text)?;
+        let unknown = file.unknown_keys(&[
+            "server",
+            "color",
+            "time_format",
+            "room",
+            "reconnect_attempts",
+            "ignored",
+        ]);
+        if !unknown.is_empty() {
+            return Err(format!("unknown settings {}", unknown.join(", ")));
+        }
+        let time_format = match file.string("time_format")?.as_deref() {
+            None | Some("24h") => TimeFormat::Clock24,
+            Some("12h") => TimeFormat::Clock12,
+            Some("relative") => TimeFormat::Relative,
+            Some(other) => {
+                return Err(format!(
+                    "time_format is {:?}, it must be \"24h\", \"12h\" or \"relative\"",
+                    other
+                ))
+            }
+        };
+        Ok(ClientConfig {
+            server: file
+                .string("server")?
+                .unwrap_or_else(|| String::from("http://[::1]:50051")),
+            color: file.boolean("color")?.unwrap_or(true),
+            time_format,
+            room: file
+                .string("room")?
+                .filter(|room| !room.trim().is_empty())
+                .unwrap_or_else(|| String::from("public")),
+            reconnect_attempts: match file.integer("reconnect_attempts")? {
+                Some(attempts) => u32::try_from(attempts)
+                    .map_err(|_| String::from("reconnect_attempts can't be negative"))?,
+                None => DEFAULT_RECONNECT_ATTEMPTS,
+            },
+            // a comma-separated list, as the server takes its admins
+            ignored: file
+                .string("ignored")?
+                .map(|names| {
+                    names
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            path: None,
+        })
+    }
+}
+
+/// Writes `ignored` to the config file at `path`, leaving the rest of it as it is.
+fn save_ignored(path: &Path, ignored: &BTreeSet<String>) -> io::Result<()> {
+    let text = fs::read_to_string(path)?;
+    fs::write(path, with_ignored(&text, ignored))
+}
+
+/// `text` of a config file with its `ignored` setting set to `ignored`, added at the end if the
+/// file is from before there was one.
+fn with_ignored(text: &str, ignored: &BTreeSet<String>) -> String {
+    let names: Vec<String> = ignored
+        .iter()
+        .map(|name| name.replace('\\', "\\\\").replace('"', "\\\""))
+        .collect();
+    let setting = format!("ignored = \"{}\"", names.join(", "));
+    let mut found = false;
+    let mut lines: Vec<&str> = text
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((key, _)) if key.trim() == "ignored" => {
+                found = true;
+                setting.as_str()
+            }
+            _ => line,
+        })
+        .collect();
+    if !found {
+        lines.push("# users whose messages aren't shown, kept up to date by !ignore and !unignore");
+        lines.push(&setting);
+    }
+    lines.join("\n") + "\n"
+}
+
+/// The users in the room that are currently typing.
+#[derive(Default)]
+struct TypingIndicator {
+    typing: HashMap<String, Instant>,
+}
+
+impl TypingIndicator {
+    fn start(&mut self, user: String) {
+        self.typing.insert(user, Instant::now());
+    }
+
+    fn stop(&mut self, user: &str) {
+        self.typing.remove(user);
+    }
+
+    fn expire(&mut self) -> bool {
+        let before = self.typing.len();
+        self.typing
+            .retain(|_, since| since.elapsed() < TYPING_TIMEOUT);
+        before != self.typing.len()
+    }
+
+    fn status(&self) -> String {
+        if self.typing.is_empty() {
+            return String::new();
+        }
+        let mut names = self.typing.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        let verb = if names.len() == 1 { "is" } else { "are" };
+        format!("{} {} typing…", names.join(", "), verb)
+    }
+}
+
+/// Completes command names, and user names seen so far as the argument of [`USER_COMMANDS`].
+struct CommandCompleter {
+    seen_users: Arc<Mutex<HashSet<String>>>,
+    /// What commands start with on the server, known once it accepted our name.
+    prefix: Arc<Mutex<char>>,
+}
+
+impl Completer for CommandCompleter {
+    fn complete(&self, line: &str) -> (usize, Vec<String>) {
+        let start = line
+            .rfind(' ')
+            .map(|i| line[..=i].chars().count())
+            .unwrap_or(0);
+        let prefix = *self.prefix.lock().unwrap();
+        let mut words = line.split(' ');
+        let command = words.next().unwrap_or_default();
+        let candidates = match words.count() {
+            0 if command.starts_with(prefix) => {
+                COMMANDS.iter().map(|c| with_prefix(c, prefix)).collect()
+            }
+            1 if USER_COMMANDS
+                .iter()
+                .any(|c| with_prefix(c, prefix) == command) =>
+            {
+                self.seen_users.lock().unwrap().iter().cloned().collect()
+            }
+            _ => Vec::new(),
+        };
+        (start, candidates)
+    }
+
+    fn is_command(&self, line: &str) -> bool {
+        line.starts_with(*self.prefix.lock().unwrap())
+    }
+}
+
+/// `command`, written with `!` as the lists above do, as it's typed with `prefix`.
+fn with_prefix(command: &str, prefix: char) -> String {
+    format!("{}{}", prefix, &command[1..])
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = ChatClient::connect(resolve_server_ip()).await?;
+    let config = ClientConfig::load()?;
+    if env::var_os("NO_COLOR").is_some()
+        || env::args().any(|arg| arg == "--no-color")
+        || !config.color
+        || !atty::is(atty::Stream::Stdout)
+    {
+        colored::control::set_override(false);
+    }
+
+    let endpoint = Endpoint::from_shared(resolve_server_ip(&config))?
+        .http2_keep_alive_interval(KEEPALIVE_INTERVAL)
+        .keep_alive_timeout(KEEPALIVE_TIMEOUT)
+        .keep_alive_while_idle(true);
+    let compression = !env::args().any(|arg| arg == "--no-compression");
+    let channel = endpoint.connect().await?;
+    let mut client = ChatClient::new(Gzip::new(channel, compression, MAX_DECOMPRESSED));
 
-    print!("\x1B[2J\x1B[1;1H");
+    let json = env::args().any(|arg| arg == "--json");
+    let bell = env::args().any(|arg| arg == "--bell");
+    let desktop_notifications = env::args().any(|arg| arg == "--notify");
+    let show_ids = env::args().any(|arg| arg == "--ids");
+    let time_format = if env::args().any(|arg| arg == "--relative-time") {
+        TimeFormat::Relative
+    } else if env::args().any(|arg| arg == "--12h") {
+        TimeFormat::Clock12
+    } else {
+        config.time_format
+    };
+    if !json {
+        print!("\x1B[2J\x1B[1;1H");
+    }
 
-    let stdin = tokio::io::stdin();
-    let mut reader = BufReader::new(stdin).lines();
+    let seen_users = Arc::new(Mutex::new(HashSet::new()));
+    let prefix = Arc::new(Mutex::new('!'));
+    let (editor, mut lines) = LineEditor::start(CommandCompleter {
+        seen_users: seen_users.clone(),
+        prefix: prefix.clone(),
+    });
     let mut user: String;
-    println!("Choose a temporary user name:");
+    notify(&editor, json, "Choose a temporary user name:");
 
     loop {
-        let name = reader.next_line().await?.unwrap();
+        let name = match lines.recv().await.ok_or("stdin closed")? {
+            Input::Line(name) => name,
+            Input::Typing(_) | Input::PageUp | Input::PageDown | Input::Focus(_) => continue,
+        };
         user = name.as_str().trim_end().to_string();
         if let Ok(response) = client
             .check_for_name(NameCheckRequest { name: user.clone() })
             .await
         {
-            if response.into_inner().available {
+            let response = response.into_inner();
+            if response.available {
+                // servers from before the prefix was configurable don't say
+                *prefix.lock().unwrap() = response.command_prefix.chars().next().unwrap_or('!');
                 break;
             };
         };
-        println!("{}", String::from("That name is currently in use.").red());
+        notify(
+            &editor,
+            json,
+            String::from("That name is currently in use.").red(),
+        );
     }
 
-    let shared_room = Arc::new(Mutex::new(String::new()));
-    let room_copy = shared_room.clone();
-
-    let join_default = ChatMessage {
-        sender: user.to_string(),
-        timestamp: get_time_as_millis(),
-        chatroom: String::new(),
-        content: String::from("!join public"),
-        target: String::new(),
+    let prefix = *prefix.lock().unwrap();
+    let mut session = Session {
+        own_name: user,
+        room: String::new(),
+        last_join: format!("{}join {}", prefix, config.room),
+        prefix,
+        ignored: config.ignored,
+        config_path: config.path,
+        indicator: TypingIndicator::default(),
+        editor,
+        seen_users,
+        json,
+        bell,
+        compression,
+        desktop_notifications,
+        show_ids,
+        delivered: HashSet::new(),
+        focused: true,
+        last_speaker: None,
+        time_format,
+        scrollback: VecDeque::new(),
+        scroll: 0,
+        draft: String::new(),
+        reconnect_attempts: config.reconnect_attempts,
     };
 
-    let outbound = async_stream::stream! {
-        
-        yield join_default;
+    while let Err(status) = session.chat(&mut client, &mut lines).await {
+        session.indicator = TypingIndicator::default();
+        session.editor.set_status(String::new());
+        session.notify(format!("Lost connection to the server: {}", status.message()).red());
+        client = match reconnect(&endpoint, &session).await {
+            Some(client) => client,
+            None => {
+                session.notify(String::from("Giving up on reconnecting.").red());
+                break;
+            }
+        };
+    }
+
+    Ok(())
+}
+
+/// What survives a reconnect: who we are and which room to go back to.
+struct Session {
+    own_name: String,
+    room: String,
+    /// The last `!join` sent, reused on reconnect if it was for the current room so a
+    /// password protected room is re-entered with its password.
+    last_join: String,
+    /// What commands start with on the server.
+    prefix: char,
+    /// Users whose messages we don't show, set with `!ignore`.
+    ignored: BTreeSet<String>,
+    /// The config file `ignored` is saved to, none if there's no home to keep it in.
+    config_path: Option<PathBuf>,
+    indicator: TypingIndicator,
+    editor: LineEditor,
+    /// Everyone we've seen a message from or to, for completing user names.
+    seen_users: Arc<Mutex<HashSet<String>>>,
+    /// Print messages as JSON lines instead of rendering them, for scripts.
+    json: bool,
+    /// Ring the terminal bell when someone mentions us.
+    bell: bool,
+    /// Compress messages to a server that accepts it, off with `--no-compression`.
+    compression: bool,
+    /// Show a desktop notification for direct messages that arrive while the terminal isn't
+    /// focused.
+    desktop_notifications: bool,
+    /// Show the id of each chat message in front of it, for `!reply`.
+    show_ids: bool,
+    /// Ids of the direct messages in the scrollback that reached their recipient.
+    delivered: HashSet<String>,
+    /// Whether the terminal has focus, as far as it reports focus changes.
+    focused: bool,
+    /// Sender and timestamp of the last message shown, if it was a chat message, so the next
+    /// one from the same sender can be grouped with it.
+    last_speaker: Option<(String, i64)>,
+    time_format: TimeFormat,
+    /// The last `MAX_SCROLLBACK` messages shown, oldest first.
+    scrollback: VecDeque<ChatMessage>,
+    /// How many of the newest messages are below the page on screen, zero while following the
+    /// chat.
+    scroll: usize,
+    /// The lines of a message typed so far, each of them ended with a backslash to continue the
+    /// message on the next one.
+    draft: String,
+    reconnect_attempts: u32,
+}
 
-        while let Ok(Some(line)) = reader.next_line().await {
+impl Session {
+    /// Streams messages until stdin or the server ends the chat, or the connection fails.
+    async fn chat(
+        &mut self,
+        client: &mut ChatClient<Gzip<Channel>>,
+        lines: &mut mpsc::UnboundedReceiver<Input>,
+    ) -> Result<(), Status> {
+        let (outbound_tx, outbound_rx) = mpsc::unbounded_channel();
+        let _ = outbound_tx.send(self.message(self.rejoin_command()));
+        let mut outbound_tx = Some(outbound_tx);
 
-            let mut room;
-            {
-                let mut guard = room_copy.lock().await;
-                room = guard.clone();
+        let response = client
+            .live_chat(Request::new(UnboundedReceiverStream::new(outbound_rx)))
+            .await?;
+        let mut inbound = response.into_inner();
+        let mut expiry = interval(Duration::from_secs(1));
+
+        loop {
+            tokio::select! {
+                input = lines.recv(), if outbound_tx.is_some() => match input {
+                    Some(Input::Typing(typing)) => {
+                        // outside a room the server would only answer with a reminder to join
+                        if !self.room.is_empty() {
+                            let command = if typing { "typing" } else { "typing stop" };
+                            let message = self.message(format!("{}{}", self.prefix, command));
+                            if let Some(tx) = &outbound_tx {
+                                let _ = tx.send(message);
+                            }
+                        }
+                    }
+                    Some(Input::PageUp) => self.page(true),
+                    Some(Input::PageDown) => self.page(false),
+                    Some(Input::Focus(focused)) => self.focused = focused,
+                    Some(Input::Line(line)) if self.is_command(&line, "clear") => {
+                        self.scroll = 0;
+                        self.clear_screen();
+                        self.update_status();
+                    }
+                    Some(Input::Line(line)) => {
+                        let Some(line) = continue_draft(&mut self.draft, &line) else {
+                            self.update_status();
+                            continue;
+                        };
+                        // whatever we send is answered at the bottom
+                        if self.scroll > 0 {
+                            self.scroll = 0;
+                            self.redraw();
+                        }
+                        let mut line = line.trim_end().to_string();
+                        if self.ignore_command(&line) {
+                            continue;
+                        }
+                        // the server measures the delay against the time we sent the ping at
+                        if self.is_command(&line, "ping") {
+                            line = format!("{}ping {}", self.prefix, get_time_as_millis());
+                        }
+                        if line.starts_with(&format!("{}join ", self.prefix)) {
+                            self.last_join = line.clone();
+                        }
+                        // commands can take colons, like times, passwords or `!calc` expressions
+                        if !line.trim_start().starts_with(self.prefix) {
+                            line = emoji::expand_shortcodes(&line);
+                        }
+                        let message = self.message(line.trim().to_string());
+                        if let Some(tx) = &outbound_tx {
+                            let _ = tx.send(message);
+                        }
+                    }
+                    // closing the outbound stream lets the server end the chat
+                    None => outbound_tx = None,
+                },
+                message = inbound.message() => match message? {
+                    Some(message) if message.kind() == Kind::Resync => {
+                        self.show(message);
+                        self.resync(client).await;
+                    }
+                    Some(message) => self.show(message),
+                    None => return Ok(()),
+                },
+                _ = expiry.tick() => {
+                    if self.indicator.expire() {
+                        self.update_status();
+                    }
+                }
             }
+        }
+    }
 
-            let line = line.trim_end().to_string();
+    /// Fills the gap a lagging connection left in the scrollback with the room's latest messages
+    /// from the server's history, some of which we may have seen already.
+    async fn resync(&mut self, client: &mut ChatClient<Gzip<Channel>>) {
+        if self.room.is_empty() {
+            return;
+        }
+        let request = GetHistoryRequest {
+            room: self.room.clone(),
+            password: self.room_password().unwrap_or_default().to_string(),
+            ..Default::default()
+        };
+        let messages = match client.get_history(request).await {
+            Ok(response) => response.into_inner().messages,
+            Err(status) => {
+                self.notify(format!("Couldn't catch up: {}", status.message()).red());
+                return;
+            }
+        };
+        let messages: Vec<ChatMessage> = messages
+            .into_iter()
+            .filter(|message| !self.is_ignored(message))
+            .collect();
+        if self.json {
+            let seen: HashSet<String> = self.scrollback.iter().map(|m| m.id.clone()).collect();
+            for message in messages.into_iter().filter(|m| !seen.contains(&m.id)) {
+                self.show(message);
+            }
+            return;
+        }
+        if merge_missed(&mut self.scrollback, messages) > 0 {
+            while self.scrollback.len() > MAX_SCROLLBACK {
+                if let Some(dropped) = self.scrollback.pop_front() {
+                    self.delivered.remove(&dropped.id);
+                }
+            }
+            self.redraw();
+        }
+    }
+
+    /// The password the current room was joined with, if it needed one.
+    fn room_password(&self) -> Option<&str> {
+        let mut words = self.last_join.split_whitespace().skip(1);
+        match words.next() {
+            Some(room) if room.to_lowercase() == self.room => words.next(),
+            _ => None,
+        }
+    }
 
-            let message = ChatMessage {
-                sender: user.to_string(),
-                timestamp: get_time_as_millis(),
-                chatroom: room,
-                content: line.trim().to_string(),
-                target: String::new()
+    fn rejoin_command(&self) -> String {
+        if self.room.is_empty() {
+            return self.last_join.clone();
+        }
+        match self.last_join.split_whitespace().nth(1) {
+            Some(room) if room == self.room => self.last_join.clone(),
+            _ => format!("{}join {}", self.prefix, self.room),
+        }
+    }
+
+    /// Carries out `!ignore` and `!unignore`, which never reach the server. False if `line` is
+    /// neither.
+    fn ignore_command(&mut self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        let ignore = match words
+            .next()
+            .and_then(|command| command.strip_prefix(self.prefix))
+        {
+            Some(command) if command.eq_ignore_ascii_case("ignore") => true,
+            Some(command) if command.eq_ignore_ascii_case("unignore") => false,
+            _ => return false,
+        };
+        let Some(user) = words.next() else {
+            let notice = if self.ignored.is_empty() {
+                String::from("You aren't ignoring anyone.")
+            } else {
+                let ignored: Vec<&str> = self.ignored.iter().map(String::as_str).collect();
+                format!("Ignoring {}.", ignored.join(", "))
             };
-            yield message;
+            self.notify(notice);
+            return true;
+        };
+        let changed = if !ignore {
+            self.ignored.remove(user)
+        } else if user == "server" || user == self.own_name {
+            false
+        } else {
+            self.ignored.insert(user.to_string())
+        };
+        let notice = match (ignore, changed) {
+            (true, true) => format!(
+                "Ignoring {}, {}unignore {} to see their messages again.",
+                user, self.prefix, user
+            ),
+            (false, true) => format!("No longer ignoring {}.", user),
+            (true, false) if user == "server" => String::from("The server can't be ignored."),
+            (true, false) if user == self.own_name => String::from("You can't ignore yourself."),
+            (true, false) => format!("You're already ignoring {}.", user),
+            (false, false) => format!("You aren't ignoring {}.", user),
+        };
+        self.notify(notice);
+        if let (true, Some(path)) = (changed, &self.config_path) {
+            if let Err(e) = save_ignored(path, &self.ignored) {
+                self.notify(format!("Couldn't save the ignore list: {}", e).red());
+            }
         }
-    };
+        true
+    }
+
+    /// Whether `message` is from a user we ignore. Server messages never are.
+    fn is_ignored(&self, message: &ChatMessage) -> bool {
+        let from_user = matches!(
+            message.kind(),
+            Kind::Chat | Kind::Action | Kind::DirectMessage | Kind::Typing
+        );
+        from_user && message.sender != "server" && self.ignored.contains(&message.sender)
+    }
+
+    /// Whether `line` is just the command `name`, which takes no arguments.
+    fn is_command(&self, line: &str, name: &str) -> bool {
+        line.trim()
+            .strip_prefix(self.prefix)
+            .is_some_and(|command| command.eq_ignore_ascii_case(name))
+    }
+
+    fn message(&self, content: String) -> ChatMessage {
+        ChatMessage {
+            sender: self.own_name.clone(),
+            timestamp: get_time_as_millis(),
+            chatroom: self.room.clone(),
+            content,
+            target: String::new(),
+            style: Style::Plain as i32,
+            kind: Kind::Chat as i32,
+            ..Default::default()
+        }
+    }
+
+    fn show(&mut self, mut message: ChatMessage) {
+        if self.json || !colored::control::SHOULD_COLORIZE.should_colorize() {
+            // content can still carry escape codes, e.g. pasted by users or from older servers
+            message.content = strip_ansi(&message.content);
+        }
+        if self.is_ignored(&message) {
+            return;
+        }
+        if self.json {
+            self.follow_room(&message);
+            let line = serde_json::json!({
+                "sender": message.sender,
+                "target": message.target,
+                "chatroom": message.chatroom,
+                "content": message.content,
+                "timestamp": message.timestamp,
+                "id": message.id,
+                "reply_to": message.reply_to.as_ref().map(|quote| &quote.id),
+                "edited": message.edited,
+                "reactions": message.reactions.iter().map(|reaction| serde_json::json!({
+                    "emoji": reaction.emoji,
+                    "users": reaction.users,
+                })).collect::<Vec<_>>(),
+            });
+            self.editor.println(line);
+            return;
+        }
+        if message.kind() == Kind::Typing {
+            if message.sender != self.own_name {
+                if message.content == TYPING_MARKER {
+                    self.indicator.start(message.sender);
+                } else {
+                    self.indicator.stop(&message.sender);
+                }
+                self.update_status();
+            }
+            return;
+        }
+        let kind = message.kind();
+        if kind == Kind::Edit {
+            self.apply_edit(message);
+            return;
+        }
+        if kind == Kind::Delete {
+            self.apply_delete(&message.id);
+            return;
+        }
+        if kind == Kind::Reaction {
+            self.apply_reactions(message);
+            return;
+        }
+        if kind == Kind::Delivered && self.mark_delivered(&message.id) {
+            return;
+        }
+        if matches!(kind, Kind::Chat | Kind::Action | Kind::DirectMessage) {
+            let mut seen_users = self.seen_users.lock().unwrap();
+            for user in [&message.sender, &message.target] {
+                if !user.is_empty() && *user != self.own_name {
+                    seen_users.insert(user.clone());
+                }
+            }
+        }
+        self.indicator.stop(&message.sender);
+        if self.is_dm_to_us(&message) && self.desktop_notifications && !self.focused {
+            desktop_notification(&message.sender, &strip_ansi(&message.content));
+        }
+        let entered = self.follow_room(&message);
+        self.scrollback.push_back(message.clone());
+        if self.scrollback.len() > MAX_SCROLLBACK {
+            if let Some(dropped) = self.scrollback.pop_front() {
+                self.delivered.remove(&dropped.id);
+            }
+        }
+        if self.scroll > 0 {
+            // keep the page we're reading in place, the message is shown when scrolling down
+            self.scroll = (self.scroll + 1).min(self.scrollback.len());
+            self.update_status();
+            return;
+        }
+        self.update_status();
+        if entered {
+            self.clear_screen();
+        }
+        self.render(message, true);
+    }
+
+    /// Prints a message below the last one. A `live` message that was just received rings the
+    /// terminal bell if it's a direct message to us, or mentions us and `--bell` is set.
+    fn render(&mut self, message: ChatMessage, live: bool) {
+        let kind = message.kind();
+        let grouped = kind == Kind::Chat
+            && self.last_speaker.as_ref().is_some_and(|(sender, at)| {
+                *sender == message.sender
+                    && message.timestamp - at < GROUPING_WINDOW.as_millis() as i64
+            });
+        self.last_speaker =
+            (kind == Kind::Chat).then(|| (message.sender.clone(), message.timestamp));
+        let direct = self.is_dm_to_us(&message);
+        let delivered = self.delivered.contains(&message.id);
+        let _output = self.editor.output();
+        match kind {
+            Kind::System | Kind::Join | Kind::Leave | Kind::Delivered | Kind::Resync => {
+                print_server_message(message, self.prefix)
+            }
+            Kind::Announcement => {
+                print_announcement(&message);
+                if live {
+                    print!("\x07");
+                }
+            }
+            // edits, deletions and reactions are applied to the scrollback in `show`
+            Kind::Edit | Kind::Delete | Kind::Reaction => {}
+            Kind::Chat | Kind::DirectMessage | Kind::Action | Kind::Typing => {
+                let mentioned = print_user_message(
+                    message,
+                    &self.own_name,
+                    grouped,
+                    self.time_format,
+                    self.show_ids,
+                    delivered,
+                );
+                if live && (direct || mentioned && self.bell) {
+                    print!("\x07");
+                }
+            }
+        }
+    }
+
+    /// Swaps an edited message for its new text, redrawing the page if it's on it. Edits of
+    /// messages from before we came are dropped.
+    fn apply_edit(&mut self, edit: ChatMessage) {
+        let Some(at) = self
+            .scrollback
+            .iter()
+            .rposition(|message| message.id == edit.id)
+        else {
+            return;
+        };
+        self.scrollback[at].content = edit.content;
+        self.scrollback[at].edited = true;
+        if page_window(self.scrollback.len(), self.scroll, page_rows()).contains(&at) {
+            self.redraw();
+        }
+    }
+
+    /// Shows the reactions a message has now under it, if we have it.
+    fn apply_reactions(&mut self, reaction: ChatMessage) {
+        let Some(at) = self
+            .scrollback
+            .iter()
+            .rposition(|message| message.id == reaction.id)
+        else {
+            return;
+        };
+        self.scrollback[at].reactions = reaction.reactions;
+        if page_window(self.scrollback.len(), self.scroll, page_rows()).contains(&at) {
+            self.redraw();
+        }
+    }
+
+    /// Marks one of our direct messages as delivered, false if it isn't in the scrollback, e.g.
+    /// because it was queued while we were connected before.
+    fn mark_delivered(&mut self, id: &str) -> bool {
+        let Some(at) = self.scrollback.iter().rposition(|message| message.id == id) else {
+            return false;
+        };
+        self.delivered.insert(id.to_string());
+        if page_window(self.scrollback.len(), self.scroll, page_rows()).contains(&at) {
+            self.redraw();
+        }
+        true
+    }
 
-    let response = client.live_chat(Request::new(outbound)).await?;
-    let mut inbound = response.into_inner();
+    /// Drops a deleted message from the scrollback, so scrolling back doesn't bring it back
+    /// either, redrawing the page if it was on it.
+    fn apply_delete(&mut self, id: &str) {
+        let Some(at) = self.scrollback.iter().rposition(|message| message.id == id) else {
+            return;
+        };
+        let shown = page_window(self.scrollback.len(), self.scroll, page_rows()).contains(&at);
+        self.scrollback.remove(at);
+        self.scroll = self.scroll.min(self.scrollback.len());
+        if shown {
+            self.redraw();
+        }
+    }
 
-    while let Some(message) = inbound.message().await? {
+    /// Whether `message` is a DM someone else sent us, our own are echoed back to us too.
+    fn is_dm_to_us(&self, message: &ChatMessage) -> bool {
+        message.kind() == Kind::DirectMessage
+            && message.target == self.own_name
+            && message.sender != self.own_name
+    }
+
+    /// Scrolls a page towards older (`up`) or newer messages.
+    fn page(&mut self, up: bool) {
+        if self.json {
+            return;
+        }
+        let rows = page_rows();
+        let oldest = self.scrollback.len().saturating_sub(rows);
+        self.scroll = if up {
+            (self.scroll + rows).min(oldest)
+        } else {
+            self.scroll.saturating_sub(rows)
+        };
+        self.redraw();
+        self.update_status();
+    }
+
+    /// Replaces the screen with the page of the scrollback that `scroll` points at. A page
+    /// holds as many messages as the terminal has rows, so long messages that wrap push its
+    /// first ones off the top.
+    fn redraw(&mut self) {
         {
-            let mut guard = shared_room.lock().await;
-            if message.chatroom != *guard && message.target.is_empty() {
-                *guard = message.chatroom.clone();
-                print!("\x1B[2J\x1B[1;1H");
-                print_command_legend();
-                println!("\n<{}>", message.chatroom.truecolor(100, 248, 140));
+            let _output = self.editor.output();
+            print!("\x1B[2J\x1B[1;1H");
+            if !self.room.is_empty() {
+                println!("<{}>", self.room.truecolor(100, 248, 140));
             }
         }
-        match message.sender.as_str() {
-            "server" => println!("{}", message.content),
-            _ => print_user_message(message),
+        let window = page_window(self.scrollback.len(), self.scroll, page_rows());
+        let page: Vec<ChatMessage> = self.scrollback.range(window).cloned().collect();
+        self.last_speaker = None;
+        for message in page {
+            self.render(message, false);
         }
     }
 
-    Ok(())
+    /// Shows who's typing, or how far we've scrolled back while we aren't at the newest messages.
+    fn update_status(&self) {
+        let status = if self.scroll > 0 {
+            format!(
+                "-- {} newer messages below, Page Down to scroll --",
+                self.scroll
+            )
+        } else if !self.draft.is_empty() {
+            format!(
+                "-- {} lines so far, end one without \\ to send the message --",
+                self.draft.lines().count()
+            )
+        } else {
+            self.indicator.status()
+        };
+        self.editor.set_status(status);
+    }
+
+    /// Clears the terminal and shows the command legend and the room we're in again.
+    fn clear_screen(&self) {
+        let _output = self.editor.output();
+        print!("\x1B[2J\x1B[1;1H");
+        print_command_legend(self.prefix);
+        if !self.room.is_empty() {
+            println!("\n<{}>", self.room.truecolor(100, 248, 140));
+        }
+    }
+
+    /// Keeps track of the room we're in, true if `message` is from a room we just entered.
+    fn follow_room(&mut self, message: &ChatMessage) -> bool {
+        if message.kind() == Kind::Join && message.chatroom != self.room {
+            self.room = message.chatroom.clone();
+            return true;
+        }
+        if message.kind() == Kind::System && message.chatroom.is_empty() {
+            // the server moved us back to the lobby, e.g. after a kick
+            self.room.clear();
+        }
+        false
+    }
+
+    fn notify(&self, notice: impl std::fmt::Display) {
+        notify(&self.editor, self.json, notice);
+    }
 }
 
-fn print_user_message(message: ChatMessage) {
-    if message.target.is_empty() {
-        println!(
-            "{} {}: {}",
-            NaiveDateTime::from_timestamp_millis(message.timestamp)
-                .unwrap()
-                .format("%H:%M:%S")
-                .to_string()
-                .truecolor(153, 140, 139),
-            message.sender.truecolor(123, 201, 107),
-            message.content.white()
+/// Shows a notice of the client itself, kept out of stdout in `--json` mode so that only
+/// carries messages.
+fn notify(editor: &LineEditor, json: bool, notice: impl std::fmt::Display) {
+    if json {
+        let _output = editor.output();
+        eprintln!("{}", strip_ansi(&notice.to_string()));
+    } else {
+        editor.println(notice);
+    }
+}
+
+/// Reconnects with exponential backoff and reclaims `user`, giving up after
+/// `NC_RECONNECT_ATTEMPTS` tries.
+async fn reconnect(endpoint: &Endpoint, session: &Session) -> Option<ChatClient<Gzip<Channel>>> {
+    let user = &session.own_name;
+    let attempts = env::var("NC_RECONNECT_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(session.reconnect_attempts);
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=attempts {
+        session.notify(
+            format!("Reconnecting… (attempt {}/{})", attempt, attempts).truecolor(153, 140, 139),
         );
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        let mut client = match endpoint.connect().await {
+            Ok(channel) => {
+                ChatClient::new(Gzip::new(channel, session.compression, MAX_DECOMPRESSED))
+            }
+            Err(_) => continue,
+        };
+        // the name may still be held until the server notices the old connection is gone
+        match client
+            .check_for_name(NameCheckRequest {
+                name: user.to_string(),
+            })
+            .await
+        {
+            Ok(response) if response.get_ref().available => return Some(client),
+            Ok(_) => session
+                .notify(format!("The name {} is still in use.", user).truecolor(153, 140, 139)),
+            Err(_) => {}
+        }
+    }
+    None
+}
+
+/// Shows a desktop notification of a DM from `sender` with `notify-send`, or `osascript` on
+/// macOS. Where neither is around nothing happens.
+fn desktop_notification(sender: &str, content: &str) {
+    let title = format!("{} sent you a message", sender);
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification {:?} with title {:?}",
+            content, title
+        ));
+        command
     } else {
+        let mut command = Command::new("notify-send");
+        command.arg("--app-name=novachat").arg(title).arg(content);
+        command
+    };
+    // waiting on a thread of its own reaps the process without holding up the chat
+    std::thread::spawn(move || command.output());
+}
+
+/// Number of messages on a page of the scrollback, leaving room in the message pane for the
+/// room header and the empty line the cursor waits on.
+fn page_rows() -> usize {
+    line_editor::pane_height().saturating_sub(2).max(1)
+}
+
+/// Indices of the scrollback messages on the page `scroll` messages above the newest, for a
+/// scrollback of `len` messages and pages of `rows`.
+fn page_window(len: usize, scroll: usize, rows: usize) -> Range<usize> {
+    let end = len.saturating_sub(scroll);
+    end.saturating_sub(rows)..end
+}
+
+/// Colors a server message by its style, with the commands it mentions picked out. Joins and
+/// leaves are dimmed so they stay out of the way of the conversation.
+fn print_server_message(message: ChatMessage, prefix: char) {
+    let presence = matches!(message.kind(), Kind::Join | Kind::Leave);
+    let content = message
+        .content
+        // keep the original whitespace, multi-line messages like the !news list rely on it
+        .split_inclusive(char::is_whitespace)
+        .map(|piece| {
+            let word = piece.trim_end();
+            let styled = match (word.starts_with(prefix), message.style()) {
+                (true, _) => word.bright_yellow(),
+                // price changes like `+2.31%`, rising ones in green and falling ones in red
+                _ if is_change(word, '+') => word.green(),
+                _ if is_change(word, '-') => word.red(),
+                _ if temperature(word).is_some() => color_temperature(word),
+                (false, Style::Plain) => word.normal(),
+                (false, Style::Notice) => word.truecolor(153, 140, 139),
+                (false, Style::Error) => word.red(),
+                (false, Style::Highlight) => word.bright_cyan(),
+            };
+            let styled = if presence { styled.dimmed() } else { styled };
+            format!("{}{}", hyperlink(word, styled), &piece[word.len()..])
+        })
+        .collect::<String>();
+    println!("{}", content);
+}
+
+/// Announcements stand out from every other message, they are rare and meant for everyone.
+fn print_announcement(message: &ChatMessage) {
+    for line in message.content.lines() {
+        println!("{}", line.bold().black().on_bright_yellow());
+    }
+}
+
+/// Wraps URLs in an OSC 8 hyperlink so terminals supporting it make them clickable,
+/// anything else and plain output get the bare `styled` word.
+fn hyperlink(word: &str, styled: ColoredString) -> String {
+    let is_url = word.starts_with("https://") || word.starts_with("http://");
+    if is_url && colored::control::SHOULD_COLORIZE.should_colorize() {
+        format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", word, styled)
+    } else {
+        styled.to_string()
+    }
+}
+
+/// Whether `word` is a percentage like `+2.31%` with the given leading sign.
+fn is_change(word: &str, sign: char) -> bool {
+    word.strip_prefix(sign)
+        .and_then(|word| word.strip_suffix('%'))
+        .is_some_and(|number| number.parse::<f64>().is_ok())
+}
+
+/// The value of a temperature like `12.3°C`, which may be followed by a comma.
+fn temperature(word: &str) -> Option<f64> {
+    word.trim_end_matches(',')
+        .strip_suffix("°C")
+        .and_then(|number| number.parse().ok())
+}
+
+/// Colors a temperature from blue for freezing to red for hot.
+fn color_temperature(word: &str) -> ColoredString {
+    match temperature(word).unwrap_or_default() {
+        t if t <= 0.0 => word.bright_blue(),
+        t if t < 15.0 => word.cyan(),
+        t if t < 25.0 => word.yellow(),
+        _ => word.red(),
+    }
+}
+
+/// Colors user names are drawn from, mid-tones that stay readable on dark and light backgrounds.
+const NAME_COLORS: &[(u8, u8, u8)] = &[
+    (123, 201, 107),
+    (97, 175, 239),
+    (229, 192, 123),
+    (198, 120, 221),
+    (86, 182, 194),
+    (224, 108, 117),
+    (209, 154, 102),
+    (152, 195, 121),
+    (255, 121, 198),
+    (80, 160, 220),
+    (230, 145, 56),
+    (189, 147, 249),
+];
+
+/// The color of `name`, picked by its FNV-1a hash so every user keeps the same color across
+/// messages, rooms and sessions.
+fn name_color(name: &str) -> (u8, u8, u8) {
+    let hash = name.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    NAME_COLORS[(hash % NAME_COLORS.len() as u64) as usize]
+}
+
+fn color_name(name: &str) -> ColoredString {
+    let (r, g, b) = name_color(name);
+    name.truecolor(r, g, b)
+}
+
+/// Prints a message of a user, true if it mentions `own_name`. A `grouped` message continues
+/// the previous one of the same sender, so it's indented under its content instead of
+/// repeating the time and name.
+fn print_user_message(
+    message: ChatMessage,
+    own_name: &str,
+    grouped: bool,
+    time_format: TimeFormat,
+    show_ids: bool,
+    delivered: bool,
+) -> bool {
+    let id = if show_ids && !message.id.is_empty() {
+        format!("{} ", message.id)
+    } else {
+        String::new()
+    };
+    let time = time_format.format(message.timestamp);
+    // quotes and wrapped lines line up with the sender and the content
+    let time_width = id.chars().count() + time.chars().count();
+    let indent = time_width + 1 + message.sender.chars().count();
+    let time = format!("{}{}", id.dimmed(), time);
+    if message.kind() == Kind::Action {
+        let action = message
+            .content
+            .strip_prefix(ACTION_MARKER)
+            .unwrap_or(&message.content);
+        let (mut action, mentioned) =
+            layout(action, indent + 3, own_name, |word| word.white().italic());
+        if message.edited {
+            action += &edited_marker();
+        }
+        let (r, g, b) = name_color(&message.sender);
+        println!(
+            "{} {} {} {}",
+            time,
+            "*".truecolor(r, g, b),
+            color_name(&message.sender).italic(),
+            action
+        );
+        print_reactions(&message, indent + 3, own_name);
+        return mentioned;
+    }
+    // whispers are DMs the server highlights, our own ones confirm who they went to
+    if message.kind() == Kind::DirectMessage && message.style() == Style::Highlight {
+        let (label, width) = if message.sender == own_name {
+            let label = format!(
+                "{} {}",
+                "whispered to".dimmed(),
+                color_name(&message.target)
+            );
+            (label, 13 + message.target.chars().count())
+        } else {
+            let label = format!("{} {}", color_name(&message.sender), "whispers".dimmed());
+            (label, message.sender.chars().count() + 9)
+        };
+        let indent = time_width + 1 + width + 2;
+        let (mut content, mentioned) = layout(&message.content, indent, own_name, |word| {
+            word.white().italic()
+        });
+        if delivered {
+            content += &format!(" {}", "✓".dimmed());
+        }
+        println!("{} {}: {}", time, label, content);
+        return mentioned;
+    }
+    if message.kind() == Kind::DirectMessage {
+        let indent = indent + 4 + message.target.chars().count() + 2;
+        let (mut content, mentioned) =
+            layout(&message.content, indent, own_name, |word| word.white());
+        if delivered {
+            content += &format!(" {}", "✓".dimmed());
+        }
         println!(
             "{} {} -> {}: {}",
-            NaiveDateTime::from_timestamp_millis(message.timestamp)
-                .unwrap()
-                .format("%H:%M:%S")
-                .to_string()
-                .truecolor(153, 140, 139),
-            message.sender.truecolor(123, 201, 107),
-            message.target.truecolor(123, 201, 107),
-            message.content.white()
+            time,
+            color_name(&message.sender),
+            color_name(&message.target),
+            content
         );
+        return mentioned;
+    }
+    let indent = indent + 2;
+    let (mut content, mentioned) = layout(&message.content, indent, own_name, |word| word.white());
+    if message.edited {
+        content += &edited_marker();
+    }
+    if let Some(quote) = &message.reply_to {
+        let quote = format!("╭ {}: {}", quote.sender, quote.content);
+        println!("{}{}", " ".repeat(time_width + 1), quote.dimmed());
+    }
+    // a grouped line would hide the id, and a reply reads better under its own name
+    if grouped && !show_ids && message.reply_to.is_none() {
+        println!("{}{}", " ".repeat(indent), content);
+    } else {
+        println!("{} {}: {}", time, color_name(&message.sender), content);
+    }
+    print_reactions(&message, indent, own_name);
+    mentioned
+}
+
+/// Counts of the reactions to a message, on a line under it starting at `indent`. Those we
+/// reacted with ourselves are highlighted.
+fn print_reactions(message: &ChatMessage, indent: usize, own_name: &str) {
+    if message.reactions.is_empty() {
+        return;
+    }
+    let reactions: Vec<String> = message
+        .reactions
+        .iter()
+        .map(|reaction| {
+            let count = format!("{} {}", reaction.emoji, reaction.users.len());
+            if reaction.users.iter().any(|user| user == own_name) {
+                count.bold().to_string()
+            } else {
+                count.dimmed().to_string()
+            }
+        })
+        .collect();
+    println!("{}{}", " ".repeat(indent), reactions.join("  "));
+}
+
+fn edited_marker() -> String {
+    format!(" {}", "(edited)".dimmed())
+}
+
+/// Wraps `content` to the terminal width as it is now, so resizes apply from the next message
+/// on, and highlights mentions like [`highlight_mentions`]. Lines after the first are indented
+/// by `indent` columns, which is where the first one starts.
+fn layout(
+    content: &str,
+    indent: usize,
+    own_name: &str,
+    style: impl Fn(&str) -> ColoredString,
+) -> (String, bool) {
+    let width = line_editor::terminal_width()
+        .saturating_sub(indent)
+        .max(MIN_WRAP_WIDTH);
+    let mut mentioned = false;
+    let lines = wrap(content, width)
+        .iter()
+        .map(|line| {
+            let (line, mention) = highlight_mentions(line, own_name, &style);
+            mentioned |= mention;
+            line
+        })
+        .collect::<Vec<_>>();
+    (lines.join(&format!("\n{}", " ".repeat(indent))), mentioned)
+}
+
+/// Breaks `text` into lines of at most `width` characters between words, splitting words that
+/// don't fit on a line of their own. Line breaks already in `text` are kept as they are.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        let mut line_width = 0;
+        for (i, word) in paragraph.split(' ').enumerate() {
+            let mut word = word;
+            let mut word_width = word.chars().count();
+            if i > 0 && line_width + 1 + word_width <= width {
+                line.push(' ');
+                line_width += 1;
+            } else if i > 0 {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+            }
+            while line_width + word_width > width {
+                let split = word
+                    .char_indices()
+                    .nth(width - line_width)
+                    .map_or(word.len(), |(at, _)| at);
+                line.push_str(&word[..split]);
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+                word = &word[split..];
+                word_width = word.chars().count();
+            }
+            line.push_str(word);
+            line_width += word_width;
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Styles every word of `content` with `style`, except mentions of `own_name`, which stand out
+/// in reverse video. A mention is `@name` at the start of a word, optionally followed by
+/// punctuation, so `@bobby` or `mail@bob` don't count as mentioning bob. Also returns whether
+/// there was any mention.
+fn highlight_mentions(
+    content: &str,
+    own_name: &str,
+    style: impl Fn(&str) -> ColoredString,
+) -> (String, bool) {
+    let mut mentioned = false;
+    let highlighted = content
+        .split(' ')
+        .map(|word| {
+            let name = word
+                .strip_prefix('@')
+                .map(|name| name.trim_end_matches(|c: char| c.is_ascii_punctuation()));
+            if name == Some(own_name) {
+                mentioned = true;
+                style(word).bold().reversed().to_string()
+            } else {
+                style(word).to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+    (highlighted, mentioned)
+}
+
+/// How the times of messages are shown, 24-hour clock unless `--12h` or `--relative-time`
+/// is passed.
+#[derive(Clone, Copy)]
+enum TimeFormat {
+    Clock24,
+    Clock12,
+    /// How long ago a message was sent as of printing it, like `2m ago`.
+    Relative,
+}
+
+impl TimeFormat {
+    /// Formats a millisecond timestamp as wall-clock time in the local timezone, which honors
+    /// `TZ`.
+    fn format(self, millis: i64) -> ColoredString {
+        let pattern = match self {
+            TimeFormat::Clock24 => "%H:%M:%S",
+            TimeFormat::Clock12 => "%I:%M:%S %p",
+            TimeFormat::Relative => {
+                return humanize(get_time_as_millis() - millis).truecolor(153, 140, 139)
+            }
+        };
+        Local
+            .timestamp_millis_opt(millis)
+            .single()
+            .map(|time| time.format(pattern).to_string())
+            .unwrap_or_else(|| String::from("--:--:--"))
+            .truecolor(153, 140, 139)
+    }
+}
+
+/// Describes an age in milliseconds in the largest whole unit, anything under a minute (or in
+/// the future, if clocks disagree) is `just now`.
+fn humanize(age: i64) -> String {
+    let minutes = age / 60_000;
+    match minutes {
+        _ if minutes < 1 => String::from("just now"),
+        1..=59 => format!("{}m ago", minutes),
+        60..=1439 => format!("{}h ago", minutes / 60),
+        _ => format!("{}d ago", minutes / 1440),
     }
 }
 
@@ -134,38 +1437,385 @@ fn get_time_as_millis() -> i64 {
     time.as_millis() as i64
 }
 
-fn resolve_server_ip() -> String {
-    let args: Vec<String> = env::args().collect();
-    //let default_server = "http://82.165.184.129:50051".to_string();
-    let default_server = "http://[::1]:50051".to_string();
-    let server_ip = args.get(1).unwrap_or(&default_server);
-    server_ip.to_string()
+/// Removes ANSI escape sequences, both CSI (colors, cursor movement) and OSC (e.g. links).
+fn strip_ansi(content: &str) -> String {
+    let mut stripped = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1B' {
+            stripped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7E').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' || (c == '\x1B' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    stripped
+}
+
+fn resolve_server_ip(config: &ClientConfig) -> String {
+    env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .unwrap_or_else(|| config.server.clone())
+}
+
+/// Puts the messages from `history` that the scrollback lacks in their place by time, the
+/// number of messages added.
+fn merge_missed(scrollback: &mut VecDeque<ChatMessage>, history: Vec<ChatMessage>) -> usize {
+    let seen: HashSet<String> = scrollback
+        .iter()
+        .map(|message| message.id.clone())
+        .collect();
+    let mut added = 0;
+    for message in history
+        .into_iter()
+        .filter(|message| !seen.contains(&message.id))
+    {
+        let at = scrollback.partition_point(|shown| shown.timestamp <= message.timestamp);
+        scrollback.insert(at, message);
+        added += 1;
+    }
+    added
+}
+
+/// Adds `line` to the message being typed. A line ending with a backslash continues the
+/// message on the next one, any other line ends it and gives the whole message back.
+fn continue_draft(draft: &mut String, line: &str) -> Option<String> {
+    match line.trim_end().strip_suffix('\\') {
+        Some(continued) => {
+            draft.push_str(continued);
+            draft.push('\n');
+            None
+        }
+        None => Some(std::mem::take(draft) + line),
+    }
 }
 
-fn print_command_legend() {
+fn print_command_legend(prefix: char) {
+    println!(
+        "{} {}.",
+        with_prefix("!join <room> [password]", prefix).bright_yellow(),
+        "to join a room, the first to join with a password protects it".truecolor(153, 140, 139)
+    );
     println!(
         "{} {}.",
-        "!join <room>".bright_yellow(),
-        "to join a room".truecolor(153, 140, 139)
+        with_prefix("!user [page]", prefix).bright_yellow(),
+        "to list the users in this room, a page at a time".truecolor(153, 140, 139)
     );
     println!(
         "{} {}.",
-        "!user".bright_yellow(),
-        "to list all users in this room".truecolor(153, 140, 139)
+        with_prefix("!value <tag> [amount]", prefix).bright_yellow(),
+        "to see what a currency, or an amount of it, is worth in euros".truecolor(153, 140, 139)
     );
     println!(
         "{} {}.",
-        "!value <tag>".bright_yellow(),
-        "to list all users in this room".truecolor(153, 140, 139)
+        with_prefix("!topic [topic]", prefix).bright_yellow(),
+        "to show or set the topic of this room".truecolor(153, 140, 139)
     );
     println!(
         "{} {}.",
-        "!dm <user> <message>".bright_yellow(),
+        with_prefix("!history [count]", prefix).bright_yellow(),
+        "to show the latest messages of this room".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!search <text>", prefix).bright_yellow(),
+        "to search the history of this room".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!kick <user>", prefix).bright_yellow(),
+        "to remove a user from this room if you are its moderator or an admin"
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!me <action>", prefix).bright_yellow(),
+        format!("to describe an action, e.g. {}me waves", prefix)
+            .as_str()
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!dm <user> <message>", prefix).bright_yellow(),
         "to send a private message to another user".truecolor(153, 140, 139)
     );
     println!(
         "{} {}.",
-        "!news".bright_yellow(),
-        "to see a list of the top 10 posts on HN".truecolor(153, 140, 139)
+        with_prefix("!whisper <user> <message>", prefix).bright_yellow(),
+        "to whisper to a user in any room, shown to them as an aside".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!reply <id> <message>", prefix).bright_yellow(),
+        "to reply to a recent message, start with --ids to see their ids".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!edit <id> <message>", prefix).bright_yellow(),
+        "to change what one of your recent messages says".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!delete <id>", prefix).bright_yellow(),
+        "to delete one of your recent messages, admins can delete anyone's"
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!react <id> <emoji>", prefix).bright_yellow(),
+        "to react to a recent message, reacting again takes it back".truecolor(153, 140, 139)
     );
+    println!(
+        "{} {}.",
+        with_prefix("!news [feed] [count]", prefix).bright_yellow(),
+        "to see 10 (or up to 30) HN stories from top, new, ask, show or jobs"
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!status [status]", prefix).bright_yellow(),
+        "to let others know you're away or busy, without one you're back online"
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!roll [dice]", prefix).bright_yellow(),
+        format!("to roll dice for the room, e.g. {}roll 2d6+3", prefix)
+            .as_str()
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!8ball <question>", prefix).bright_yellow(),
+        "to ask the magic 8-ball".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!weather <city>", prefix).bright_yellow(),
+        "to see the current weather of a city or of coordinates like 52.52,13.41"
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!calc <expression>", prefix).bright_yellow(),
+        format!("to do some arithmetic, e.g. {}calc (3+4)*2", prefix)
+            .as_str()
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!uptime", prefix).bright_yellow(),
+        "to see how long the server has been up and how many are online".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!stats", prefix).bright_yellow(),
+        "to see how many messages were sent here and in all rooms, and the commands used most"
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!ping", prefix).bright_yellow(),
+        "to check the connection and how long messages take to reach the server"
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!joke", prefix).bright_yellow(),
+        "to tell the room a joke".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!wiki <topic>", prefix).bright_yellow(),
+        "to show the room the summary of a Wikipedia article".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!crates <crate>", prefix).bright_yellow(),
+        "to look up the latest version of a crate on crates.io".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!announce <message>", prefix).bright_yellow(),
+        "to make an announcement to every room, admins only".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!ban <user>", prefix).bright_yellow(),
+        "to disconnect a user and keep their name out for good, admins only"
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!unban <user>", prefix).bright_yellow(),
+        "to lift a ban, admins only".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!block <user>", prefix).bright_yellow(),
+        "to stop the direct messages and whispers of a user at the server".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!unblock <user>", prefix).bright_yellow(),
+        "to let them through again".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!help", prefix).bright_yellow(),
+        "to list all commands".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!ignore [user]", prefix).bright_yellow(),
+        "to hide the messages of a user, or list the ones hidden, on this computer only"
+            .truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!unignore <user>", prefix).bright_yellow(),
+        "to see the messages of an ignored user again".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        with_prefix("!clear", prefix).bright_yellow(),
+        "to clear the screen".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        "Page Up/Page Down".bright_yellow(),
+        "to scroll through earlier messages".truecolor(153, 140, 139)
+    );
+    println!(
+        "{} {}.",
+        "\\ at the end of a line".bright_yellow(),
+        "to continue the message on the next line, the first line without one sends it"
+            .truecolor(153, 140, 139)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continues_lines_ending_with_a_backslash() {
+        let mut draft = String::new();
+        assert_eq!(continue_draft(&mut draft, "fn main() {\\"), None);
+        assert_eq!(continue_draft(&mut draft, "    todo!()  \\  "), None);
+        assert_eq!(
+            continue_draft(&mut draft, "}"),
+            Some("fn main() {\n    todo!()  \n}".to_string())
+        );
+        assert!(draft.is_empty());
+        assert_eq!(continue_draft(&mut draft, "hi"), Some("hi".to_string()));
+    }
+
+    #[test]
+    fn merges_missed_messages_by_time() {
+        let message = |id: &str, timestamp| ChatMessage {
+            id: id.to_string(),
+            timestamp,
+            ..Default::default()
+        };
+        let mut scrollback = VecDeque::from([message("a", 1), message("d", 4)]);
+        let history = vec![
+            message("a", 1),
+            message("b", 2),
+            message("c", 3),
+            message("d", 4),
+        ];
+        assert_eq!(merge_missed(&mut scrollback, history), 2);
+        let ids: Vec<_> = scrollback.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn wraps_between_words() {
+        assert_eq!(wrap("the quick brown fox", 10), ["the quick", "brown fox"]);
+        assert_eq!(wrap("short", 10), ["short"]);
+        assert_eq!(wrap("", 10), [""]);
+    }
+
+    #[test]
+    fn splits_words_longer_than_a_line() {
+        assert_eq!(wrap("see abcdefghij", 6), ["see", "abcdef", "ghij"]);
+        assert_eq!(wrap("äöüäöüäö", 3), ["äöü", "äöü", "äö"]);
+    }
+
+    #[test]
+    fn pages_through_the_scrollback() {
+        assert_eq!(page_window(100, 0, 20), 80..100);
+        assert_eq!(page_window(100, 20, 20), 60..80);
+        assert_eq!(page_window(100, 90, 20), 0..10);
+        assert_eq!(page_window(5, 0, 20), 0..5);
+    }
+
+    #[test]
+    fn reads_the_config_file() {
+        let config = ClientConfig::parse(DEFAULT_CONFIG).unwrap();
+        assert_eq!(config.server, "http://[::1]:50051");
+        assert!(config.color);
+        assert_eq!(config.room, "public");
+        assert_eq!(config.reconnect_attempts, DEFAULT_RECONNECT_ATTEMPTS);
+
+        let config = ClientConfig::parse("color = false\ntime_format = \"12h\"\n").unwrap();
+        assert!(!config.color);
+        assert!(matches!(config.time_format, TimeFormat::Clock12));
+        assert_eq!(config.room, "public");
+
+        assert!(ClientConfig::parse("time_format = \"iso\"").is_err());
+        assert!(ClientConfig::parse("colour = false").is_err());
+        assert!(ClientConfig::parse("reconnect_attempts = -1").is_err());
+    }
+
+    #[test]
+    fn keeps_the_ignore_list_in_the_config_file() {
+        assert!(ClientConfig::parse(DEFAULT_CONFIG)
+            .unwrap()
+            .ignored
+            .is_empty());
+        let ignored: BTreeSet<String> = ["mallory", "trudy"].map(String::from).into();
+        let text = with_ignored(DEFAULT_CONFIG, &ignored);
+        assert_eq!(text.matches("ignored = ").count(), 1);
+        let config = ClientConfig::parse(&text).unwrap();
+        assert_eq!(config.ignored, ignored);
+        assert_eq!(config.room, "public");
+
+        // files from before the setting get it added
+        let text = with_ignored("color = false", &ignored);
+        assert_eq!(ClientConfig::parse(&text).unwrap().ignored, ignored);
+        assert!(with_ignored(&text, &BTreeSet::new()).ends_with("ignored = \"\"\n"));
+    }
+
+    #[test]
+    fn humanizes_ages() {
+        assert_eq!(humanize(-5_000), "just now");
+        assert_eq!(humanize(59_999), "just now");
+        assert_eq!(humanize(2 * 60_000 + 30_000), "2m ago");
+        assert_eq!(humanize(90 * 60_000), "1h ago");
+        assert_eq!(humanize(3 * 24 * 3_600_000), "3d ago");
+    }
+
+    #[test]
+    fn keeps_existing_line_breaks() {
+        assert_eq!(wrap("one two\nthree", 7), ["one two", "three"]);
+        assert_eq!(wrap("a  b", 10), ["a  b"]);
+    }
 }
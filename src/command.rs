@@ -0,0 +1,631 @@
+/// A message's content parsed into the command it invokes.
+///
+/// Only the first word names the command, case-insensitively, the rest are its arguments and
+/// keep their case. Commands missing a
+/// required argument parse into [`Command::Usage`], anything that isn't a known command is
+/// [`Command::Unknown`] and passed on as chat.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Join {
+        room: String,
+        password: Option<String>,
+    },
+    Dm {
+        target: String,
+        body: String,
+    },
+    /// A direct message that reads as an aside, to someone in any room or none.
+    Whisper {
+        target: String,
+        body: String,
+    },
+    Reply {
+        id: String,
+        text: String,
+    },
+    Edit {
+        id: String,
+        text: String,
+    },
+    Delete(String),
+    React {
+        id: String,
+        emoji: String,
+    },
+    Value {
+        currency: String,
+        amount: Option<f64>,
+    },
+    News {
+        feed: Option<String>,
+        count: Option<usize>,
+    },
+    User(Option<usize>),
+    Help,
+    Typing {
+        stop: bool,
+    },
+    Topic(Option<String>),
+    History(Option<usize>),
+    Search(String),
+    Kick(String),
+    Me(String),
+    Status(Option<String>),
+    Roll(Dice),
+    EightBall(String),
+    Weather(String),
+    Calc(String),
+    Uptime,
+    Stats,
+    /// `!ping`, with the time the client sent it at in milliseconds since the epoch if it
+    /// passes one along.
+    Ping(Option<i64>),
+    Joke,
+    Wiki(String),
+    Crates(String),
+    Announce(String),
+    Ban(String),
+    Unban(String),
+    Block(String),
+    Unblock(String),
+    /// A known command used without its required arguments, with the usage to show instead.
+    Usage(&'static str),
+    Unknown,
+}
+
+impl Command {
+    /// Name of the invoked command as counted in the metrics, `None` for chat.
+    pub fn name(&self) -> Option<&'static str> {
+        let name = match self {
+            Command::Join { .. } => "!join",
+            Command::Dm { .. } => "!dm",
+            Command::Whisper { .. } => "!whisper",
+            Command::Reply { .. } => "!reply",
+            Command::Edit { .. } => "!edit",
+            Command::Delete(_) => "!delete",
+            Command::React { .. } => "!react",
+            Command::Value { .. } => "!value",
+            Command::News { .. } => "!news",
+            Command::User(_) => "!user",
+            Command::Help => "!help",
+            Command::Typing { .. } => "!typing",
+            Command::Topic(_) => "!topic",
+            Command::History(_) => "!history",
+            Command::Search(_) => "!search",
+            Command::Kick(_) => "!kick",
+            Command::Me(_) => "!me",
+            Command::Status(_) => "!status",
+            Command::Roll(_) => "!roll",
+            Command::EightBall(_) => "!8ball",
+            Command::Weather(_) => "!weather",
+            Command::Calc(_) => "!calc",
+            Command::Uptime => "!uptime",
+            Command::Stats => "!stats",
+            Command::Ping(_) => "!ping",
+            Command::Joke => "!joke",
+            Command::Wiki(_) => "!wiki",
+            Command::Crates(_) => "!crates",
+            Command::Announce(_) => "!announce",
+            Command::Ban(_) => "!ban",
+            Command::Unban(_) => "!unban",
+            Command::Block(_) => "!block",
+            Command::Unblock(_) => "!unblock",
+            Command::Usage(usage) => usage.split(' ').next().unwrap(),
+            Command::Unknown => return None,
+        };
+        Some(name)
+    }
+
+    /// Whether only admins may use the command, see `ServerState::is_admin`.
+    pub fn is_privileged(&self) -> bool {
+        matches!(
+            self,
+            Command::Announce(_) | Command::Ban(_) | Command::Unban(_)
+        )
+    }
+}
+
+/// The largest modifier `!roll` adds or subtracts, which keeps the total far from overflowing.
+const MAX_DICE_MODIFIER: i64 = 1000;
+
+/// Dice to roll in the usual `NdS+M` notation, e.g. `2d6+3`. The count defaults to one and the
+/// modifier to zero, `!roll` alone rolls a single six-sided die.
+#[derive(Debug, PartialEq)]
+pub struct Dice {
+    pub count: u32,
+    pub sides: u32,
+    pub modifier: i64,
+}
+
+impl Default for Dice {
+    fn default() -> Dice {
+        Dice {
+            count: 1,
+            sides: 6,
+            modifier: 0,
+        }
+    }
+}
+
+impl Dice {
+    fn parse(notation: &str) -> Option<Dice> {
+        let notation = notation.trim().to_lowercase();
+        let (count, rest) = notation.split_once('d')?;
+        let (sides, modifier) = match rest.find(['+', '-']) {
+            Some(at) => rest.split_at(at),
+            None => (rest, ""),
+        };
+        let count = match count {
+            "" => 1,
+            count => count.parse().ok()?,
+        };
+        let modifier = match modifier.strip_prefix('+') {
+            _ if modifier.is_empty() => 0,
+            // parse would accept a second sign, as in `1d6+-2`
+            Some(positive) if positive.starts_with(|c: char| c.is_ascii_digit()) => {
+                positive.parse().ok()?
+            }
+            Some(_) => return None,
+            None => modifier.parse().ok()?,
+        };
+        if !(-MAX_DICE_MODIFIER..=MAX_DICE_MODIFIER).contains(&modifier) {
+            return None;
+        }
+        Some(Dice {
+            count,
+            sides: sides.parse().ok()?,
+            modifier,
+        })
+    }
+}
+
+/// Usage of every command, as listed by `!help`.
+pub const USAGES: &[&str] = &[
+    "!join <room> [password]",
+    "!user [page]",
+    "!topic [topic]",
+    "!history [count]",
+    "!search <text>",
+    "!kick <user>",
+    "!me <action>",
+    "!status [status]",
+    "!roll [dice]",
+    "!8ball <question>",
+    "!weather <city>",
+    "!calc <expression>",
+    "!uptime",
+    "!stats",
+    "!ping",
+    "!joke",
+    "!wiki <topic>",
+    "!crates <crate>",
+    "!announce <message>",
+    "!ban <user>",
+    "!unban <user>",
+    "!dm <user> <message>",
+    "!whisper <user> <message>",
+    "!block <user>",
+    "!unblock <user>",
+    "!reply <id> <message>",
+    "!edit <id> <message>",
+    "!delete <id>",
+    "!react <id> <emoji>",
+    "!value <currency> [amount]",
+    "!news [feed] [count]",
+    "!help",
+];
+
+/// Names of all commands as [`Command::name`] gives them, the ones `!help` lists and `!typing`.
+pub fn names() -> impl Iterator<Item = &'static str> {
+    USAGES
+        .iter()
+        .map(|usage| usage.split(' ').next().unwrap())
+        .chain(["!typing"])
+}
+
+/// Parses `content` with commands starting with `prefix` rather than `!`. Commands keep their
+/// `!` names, so a message starting with `!` is chat then.
+pub fn parse_with_prefix(content: &str, prefix: char) -> Command {
+    match content.trim_start().strip_prefix(prefix) {
+        Some(rest) if prefix != '!' => parse(&format!("!{}", rest)),
+        Some(_) => parse(content),
+        None => Command::Unknown,
+    }
+}
+
+/// `text` with the `!` of every command name in it replaced by `prefix`, for telling users about
+/// commands the way they have to type them. A `!` following a letter or digit isn't touched.
+pub fn with_prefix(text: &str, prefix: char) -> String {
+    if prefix == '!' {
+        return text.to_string();
+    }
+    let mut replaced = String::with_capacity(text.len());
+    let mut previous = None;
+    for (at, c) in text.char_indices() {
+        let starts_command = c == '!'
+            && !previous.is_some_and(char::is_alphanumeric)
+            && names().any(|name| {
+                let rest = &text[at..];
+                rest.starts_with(name)
+                    && !rest[name.len()..].starts_with(|c: char| c.is_alphanumeric())
+            });
+        replaced.push(if starts_command { prefix } else { c });
+        previous = Some(c);
+    }
+    replaced
+}
+
+pub fn parse(content: &str) -> Command {
+    let content = content.trim();
+    let (command, args) = content
+        .split_once(char::is_whitespace)
+        .map_or((content, ""), |(command, args)| {
+            (command, args.trim_start())
+        });
+    // the first argument, and whatever follows it with the whitespace in between trimmed
+    let (first, rest) = args
+        .split_once(char::is_whitespace)
+        .map_or((args, ""), |(first, rest)| (first, rest.trim_start()));
+    match command.to_lowercase().as_str() {
+        "!join" if first.is_empty() => Command::Usage("!join <room> [password]"),
+        "!join" => Command::Join {
+            room: first.to_string(),
+            password: Some(rest.to_string()).filter(|p| !p.is_empty()),
+        },
+        "!dm" if first.is_empty() || rest.is_empty() => Command::Usage("!dm <user> <message>"),
+        "!dm" => Command::Dm {
+            target: first.to_string(),
+            body: rest.to_string(),
+        },
+        "!whisper" if first.is_empty() || rest.is_empty() => {
+            Command::Usage("!whisper <user> <message>")
+        }
+        "!whisper" => Command::Whisper {
+            target: first.to_string(),
+            body: rest.to_string(),
+        },
+        "!reply" if first.is_empty() || rest.is_empty() => Command::Usage("!reply <id> <message>"),
+        "!reply" => Command::Reply {
+            id: first.to_string(),
+            text: rest.to_string(),
+        },
+        "!edit" if first.is_empty() || rest.is_empty() => Command::Usage("!edit <id> <message>"),
+        "!edit" => Command::Edit {
+            id: first.to_string(),
+            text: rest.to_string(),
+        },
+        "!delete" if first.is_empty() => Command::Usage("!delete <id>"),
+        "!delete" => Command::Delete(first.to_string()),
+        "!react" if first.is_empty() || rest.is_empty() => Command::Usage("!react <id> <emoji>"),
+        "!react" => Command::React {
+            id: first.to_string(),
+            emoji: rest.to_string(),
+        },
+        "!value" if first.is_empty() => Command::Usage("!value <currency> [amount]"),
+        "!value" => Command::Value {
+            currency: first.to_string(),
+            // anything that isn't a sensible amount falls back to the price of a single unit
+            amount: rest
+                .split_whitespace()
+                .next()
+                .and_then(|amount| amount.parse::<f64>().ok())
+                .filter(|amount| amount.is_finite() && *amount >= 0.0),
+        },
+        "!news" => {
+            let mut feed = None;
+            let mut count = None;
+            for arg in args.split_whitespace() {
+                match arg.parse() {
+                    Ok(n) => count = Some(n),
+                    // too many to count is still a count, not a feed
+                    Err(_) if arg.bytes().all(|b| b.is_ascii_digit()) => count = Some(usize::MAX),
+                    Err(_) => feed = Some(arg.to_string()),
+                }
+            }
+            Command::News { feed, count }
+        }
+        "!user" => Command::User(first.parse().ok().filter(|page| *page > 0)),
+        "!help" => Command::Help,
+        "!typing" => Command::Typing {
+            stop: first == "stop",
+        },
+        "!topic" => Command::Topic(Some(args.to_string()).filter(|t| !t.is_empty())),
+        "!history" => Command::History(first.parse().ok()),
+        "!search" if args.is_empty() => Command::Usage("!search <text>"),
+        "!search" => Command::Search(args.to_string()),
+        "!kick" if first.is_empty() => Command::Usage("!kick <user>"),
+        "!kick" => Command::Kick(first.to_string()),
+        "!me" if args.is_empty() => Command::Usage("!me <action>"),
+        "!me" => Command::Me(args.to_string()),
+        "!status" => Command::Status(Some(args.to_string()).filter(|s| !s.is_empty())),
+        "!roll" if args.is_empty() => Command::Roll(Dice::default()),
+        "!roll" => Dice::parse(args).map_or(Command::Usage("!roll [dice]"), Command::Roll),
+        "!8ball" if args.is_empty() => Command::Usage("!8ball <question>"),
+        "!8ball" => Command::EightBall(args.to_string()),
+        "!weather" if args.is_empty() => Command::Usage("!weather <city>"),
+        "!weather" => Command::Weather(args.to_string()),
+        "!calc" if args.is_empty() => Command::Usage("!calc <expression>"),
+        "!calc" => Command::Calc(args.to_string()),
+        "!uptime" => Command::Uptime,
+        "!stats" => Command::Stats,
+        "!ping" => Command::Ping(first.parse().ok()),
+        "!joke" => Command::Joke,
+        "!wiki" if args.is_empty() => Command::Usage("!wiki <topic>"),
+        "!wiki" => Command::Wiki(args.to_string()),
+        "!crates" if first.is_empty() => Command::Usage("!crates <crate>"),
+        "!crates" => Command::Crates(first.to_string()),
+        "!announce" if args.is_empty() => Command::Usage("!announce <message>"),
+        "!announce" => Command::Announce(args.to_string()),
+        "!ban" if first.is_empty() => Command::Usage("!ban <user>"),
+        "!ban" => Command::Ban(first.to_string()),
+        "!unban" if first.is_empty() => Command::Usage("!unban <user>"),
+        "!unban" => Command::Unban(first.to_string()),
+        "!block" if first.is_empty() => Command::Usage("!block <user>"),
+        "!block" => Command::Block(first.to_string()),
+        "!unblock" if first.is_empty() => Command::Usage("!unblock <user>"),
+        "!unblock" => Command::Unblock(first.to_string()),
+        _ => Command::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_every_command() {
+        for content in USAGES.iter().chain(&["!typing stop"]) {
+            let name = parse(content).name().unwrap();
+            assert!(names().any(|known| known == name), "{} is missing", name);
+        }
+    }
+
+    #[test]
+    fn parses_commands_with_another_prefix() {
+        assert_eq!(
+            parse_with_prefix("/dm bob hi", '/'),
+            Command::Dm {
+                target: "bob".to_string(),
+                body: "hi".to_string()
+            }
+        );
+        assert_eq!(parse_with_prefix("!dm bob hi", '/'), Command::Unknown);
+        assert_eq!(
+            parse_with_prefix("/join", '/'),
+            Command::Usage("!join <room> [password]")
+        );
+        assert_eq!(parse_with_prefix("!help", '!'), Command::Help);
+    }
+
+    #[test]
+    fn writes_commands_with_another_prefix() {
+        assert_eq!(
+            with_prefix("Wow! Try !help, !8ball or (!user 2), not !nope.", '/'),
+            "Wow! Try /help, /8ball or (/user 2), not !nope."
+        );
+        assert_eq!(with_prefix("!me waves", '!'), "!me waves");
+    }
+
+    #[test]
+    fn parses_commands_without_arguments() {
+        assert_eq!(parse("!user"), Command::User(None));
+        assert_eq!(parse("!user 2"), Command::User(Some(2)));
+        assert_eq!(parse("!user 0"), Command::User(None));
+        assert_eq!(parse("!help"), Command::Help);
+        assert_eq!(parse("!typing"), Command::Typing { stop: false });
+        assert_eq!(parse("!typing stop"), Command::Typing { stop: true });
+        assert_eq!(parse("!topic"), Command::Topic(None));
+        assert_eq!(parse("!history"), Command::History(None));
+        assert_eq!(parse("!status"), Command::Status(None));
+        assert_eq!(parse("!uptime"), Command::Uptime);
+        assert_eq!(parse("!stats"), Command::Stats);
+        assert_eq!(parse("!ping"), Command::Ping(None));
+        assert_eq!(parse("!joke"), Command::Joke);
+        assert_eq!(
+            parse("!ping 1700000000000"),
+            Command::Ping(Some(1700000000000))
+        );
+    }
+
+    #[test]
+    fn ignores_extra_whitespace() {
+        assert_eq!(parse("  !user  "), Command::User(None));
+        assert_eq!(
+            parse("!join   rust  "),
+            Command::Join {
+                room: "rust".to_string(),
+                password: None
+            }
+        );
+        assert_eq!(
+            parse("!dm  bob   hi  there "),
+            Command::Dm {
+                target: "bob".to_string(),
+                body: "hi  there".to_string()
+            }
+        );
+        assert_eq!(
+            parse("!whisper carol see you in #go"),
+            Command::Whisper {
+                target: "carol".to_string(),
+                body: "see you in #go".to_string()
+            }
+        );
+        assert_eq!(parse("!history  5"), Command::History(Some(5)));
+        assert_eq!(
+            parse("!reply x1y2z3 me too"),
+            Command::Reply {
+                id: "x1y2z3".to_string(),
+                text: "me too".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn keeps_the_rest_of_the_line_as_one_argument() {
+        assert_eq!(
+            parse("!join rust my secret"),
+            Command::Join {
+                room: "rust".to_string(),
+                password: Some("my secret".to_string())
+            }
+        );
+        assert_eq!(
+            parse("!me waves at bob"),
+            Command::Me("waves at bob".to_string())
+        );
+        assert_eq!(
+            parse("!topic Rust 2024 edition"),
+            Command::Topic(Some("Rust 2024 edition".to_string()))
+        );
+        assert_eq!(
+            parse("!status out for lunch"),
+            Command::Status(Some("out for lunch".to_string()))
+        );
+    }
+
+    #[test]
+    fn reports_missing_arguments() {
+        assert_eq!(parse("!join"), Command::Usage("!join <room> [password]"));
+        assert_eq!(parse("!join   "), Command::Usage("!join <room> [password]"));
+        assert_eq!(parse("!dm"), Command::Usage("!dm <user> <message>"));
+        assert_eq!(parse("!dm bob"), Command::Usage("!dm <user> <message>"));
+        assert_eq!(
+            parse("!whisper bob"),
+            Command::Usage("!whisper <user> <message>")
+        );
+        assert_eq!(parse("!reply x1"), Command::Usage("!reply <id> <message>"));
+        assert_eq!(parse("!edit"), Command::Usage("!edit <id> <message>"));
+        assert_eq!(parse("!delete"), Command::Usage("!delete <id>"));
+        assert_eq!(
+            parse("!react x1y2z3"),
+            Command::Usage("!react <id> <emoji>")
+        );
+        assert_eq!(
+            parse("!value"),
+            Command::Usage("!value <currency> [amount]")
+        );
+        assert_eq!(parse("!kick "), Command::Usage("!kick <user>"));
+        assert_eq!(parse("!search"), Command::Usage("!search <text>"));
+        assert_eq!(parse("!me"), Command::Usage("!me <action>"));
+        assert_eq!(parse("!8ball  "), Command::Usage("!8ball <question>"));
+        assert_eq!(parse("!wiki"), Command::Usage("!wiki <topic>"));
+        assert_eq!(parse("!crates"), Command::Usage("!crates <crate>"));
+        assert_eq!(parse("!announce"), Command::Usage("!announce <message>"));
+        assert_eq!(parse("!ban"), Command::Usage("!ban <user>"));
+        assert_eq!(
+            parse("!unban mallory"),
+            Command::Unban("mallory".to_string())
+        );
+        assert_eq!(parse("!block"), Command::Usage("!block <user>"));
+        assert_eq!(
+            parse("!unblock mallory"),
+            Command::Unblock("mallory".to_string())
+        );
+    }
+
+    #[test]
+    fn matches_commands_regardless_of_case() {
+        assert_eq!(parse("!User"), Command::User(None));
+        assert_eq!(
+            parse("!NEWS ASK"),
+            Command::News {
+                feed: Some("ASK".to_string()),
+                count: None
+            }
+        );
+        assert_eq!(
+            parse("!JOIN MyRoom"),
+            Command::Join {
+                room: "MyRoom".to_string(),
+                password: None
+            }
+        );
+        assert_eq!(
+            parse("!Dm Bob Hello There"),
+            Command::Dm {
+                target: "Bob".to_string(),
+                body: "Hello There".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn parses_value_amounts() {
+        let value = |amount| Command::Value {
+            currency: "btc".to_string(),
+            amount,
+        };
+        assert_eq!(parse("!value btc"), value(None));
+        assert_eq!(parse("!value btc 0.5"), value(Some(0.5)));
+        assert_eq!(parse("!value btc lots"), value(None));
+        assert_eq!(parse("!value btc -1"), value(None));
+        assert_eq!(parse("!value btc inf"), value(None));
+    }
+
+    #[test]
+    fn parses_news_arguments_in_any_order() {
+        let news = |feed: Option<&str>, count| Command::News {
+            feed: feed.map(str::to_string),
+            count,
+        };
+        assert_eq!(parse("!news"), news(None, None));
+        assert_eq!(parse("!news 20"), news(None, Some(20)));
+        assert_eq!(parse("!news ask"), news(Some("ask"), None));
+        assert_eq!(parse("!news 5 show"), news(Some("show"), Some(5)));
+        assert_eq!(
+            parse("!news 99999999999999999999"),
+            news(None, Some(usize::MAX))
+        );
+    }
+
+    #[test]
+    fn parses_dice_notation() {
+        let dice = |count, sides, modifier| {
+            Command::Roll(Dice {
+                count,
+                sides,
+                modifier,
+            })
+        };
+        assert_eq!(parse("!roll"), dice(1, 6, 0));
+        assert_eq!(parse("!roll 2d6+3"), dice(2, 6, 3));
+        assert_eq!(parse("!roll d20"), dice(1, 20, 0));
+        assert_eq!(parse("!roll 3D8-1"), dice(3, 8, -1));
+        assert_eq!(parse("!roll 1d6-1000"), dice(1, 6, -1000));
+        for invalid in [
+            "!roll 1d6+1001",
+            "!roll 1d6+9223372036854775807",
+            "!roll 1d6-9223372036854775808",
+            "!roll 2d",
+            "!roll d",
+            "!roll 6",
+            "!roll 2d6+",
+            "!roll 1d6+-2",
+            "!roll -1d6",
+        ] {
+            assert_eq!(
+                parse(invalid),
+                Command::Usage("!roll [dice]"),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn treats_everything_else_as_chat() {
+        assert_eq!(parse("hello"), Command::Unknown);
+        assert_eq!(parse(""), Command::Unknown);
+        assert_eq!(parse("!users"), Command::Unknown);
+        assert_eq!(parse("!"), Command::Unknown);
+        assert_eq!(parse("join rust"), Command::Unknown);
+    }
+
+    #[test]
+    fn names_commands_for_the_metrics() {
+        assert_eq!(parse("!dm bob").name(), Some("!dm"));
+        assert_eq!(parse("!news").name(), Some("!news"));
+        assert_eq!(parse("hello").name(), None);
+    }
+}
@@ -0,0 +1,319 @@
+//! gzip compression of gRPC messages, negotiated through the `grpc-encoding` and
+//! `grpc-accept-encoding` headers like tonic does with its `gzip` feature, which isn't available
+//! to this build. Each end says it accepts gzip, and messages are only sent compressed to a peer
+//! that said so, leaving other gRPC clients and servers unaffected.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tonic::body::BoxBody;
+use tonic::codegen::http::{HeaderMap, HeaderValue, Request, Response};
+use tonic::codegen::{Body, BoxFuture, Bytes, Service, StdError};
+use tonic::server::NamedService;
+use tonic::Status;
+
+use crate::gzip;
+
+const ENCODING: &str = "grpc-encoding";
+const ACCEPT_ENCODING: &str = "grpc-accept-encoding";
+
+/// Compresses the messages of a generated server or of a client's channel. Which one it wraps
+/// is told by the request body, a server is handed hyper's and a client sends tonic's.
+#[derive(Clone)]
+pub struct Gzip<S> {
+    inner: S,
+    /// Whether to compress what's sent, compressed messages are taken either way.
+    enabled: bool,
+    /// Upper bound on the size of a decompressed message, in bytes.
+    limit: usize,
+    /// Whether the server has said it accepts gzip, a client has to hear it before compressing.
+    peer_accepts: Arc<AtomicBool>,
+}
+
+impl<S> Gzip<S> {
+    pub fn new(inner: S, enabled: bool, limit: usize) -> Gzip<S> {
+        Gzip {
+            inner,
+            enabled,
+            limit,
+            peer_accepts: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl<S: NamedService> NamedService for Gzip<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<Request<hyper::Body>> for Gzip<S>
+where
+    S: Service<Request<Frames<hyper::Body>>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<Response<BoxBody>, S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<hyper::Body>) -> Self::Future {
+        let (mut parts, body) = request.into_parts();
+        let compress = self.enabled && accepts_gzip(&parts.headers);
+        let action = if take_gzip_encoding(&mut parts.headers) {
+            Action::Decompress(self.limit)
+        } else {
+            Action::Pass
+        };
+        let response = self
+            .inner
+            .call(Request::from_parts(parts, Frames::new(body, action)));
+        let enabled = self.enabled;
+        Box::pin(async move {
+            let mut response = response.await?;
+            if enabled {
+                let headers = response.headers_mut();
+                headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+            }
+            if !compress {
+                return Ok(response);
+            }
+            let headers = response.headers_mut();
+            headers.insert(ENCODING, HeaderValue::from_static("gzip"));
+            Ok(response.map(|body| Frames::new(body, Action::Compress).boxed_unsync()))
+        })
+    }
+}
+
+impl<S> Service<Request<BoxBody>> for Gzip<S>
+where
+    S: Service<Request<BoxBody>, Response = Response<hyper::Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Frames<hyper::Body>>;
+    type Error = S::Error;
+    type Future = BoxFuture<Response<Frames<hyper::Body>>, S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<BoxBody>) -> Self::Future {
+        if self.enabled {
+            let headers = request.headers_mut();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        }
+        if self.enabled && self.peer_accepts.load(Ordering::Relaxed) {
+            let headers = request.headers_mut();
+            headers.insert(ENCODING, HeaderValue::from_static("gzip"));
+            request = request.map(|body| Frames::new(body, Action::Compress).boxed_unsync());
+        }
+        let response = self.inner.call(request);
+        let (peer_accepts, limit) = (self.peer_accepts.clone(), self.limit);
+        Box::pin(async move {
+            let (mut parts, body) = response.await?.into_parts();
+            if accepts_gzip(&parts.headers) {
+                peer_accepts.store(true, Ordering::Relaxed);
+            }
+            let action = if take_gzip_encoding(&mut parts.headers) {
+                Action::Decompress(limit)
+            } else {
+                Action::Pass
+            };
+            Ok(Response::from_parts(parts, Frames::new(body, action)))
+        })
+    }
+}
+
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| encoding.trim() == "gzip")
+}
+
+/// Removes a gzip `grpc-encoding`, telling whether there was one. tonic would refuse the
+/// messages otherwise, not knowing they're decompressed before it sees them.
+fn take_gzip_encoding(headers: &mut HeaderMap) -> bool {
+    let gzip = headers
+        .get(ENCODING)
+        .is_some_and(|encoding| encoding == "gzip");
+    if gzip {
+        headers.remove(ENCODING);
+    }
+    gzip
+}
+
+/// What `Frames` does with the messages passing through.
+#[derive(Clone, Copy)]
+enum Action {
+    Pass,
+    Compress,
+    /// Decompresses messages, refusing any that would be longer than the given number of bytes.
+    Decompress(usize),
+}
+
+/// A body of length-prefixed gRPC messages, compressing or decompressing each on the way
+/// through.
+pub struct Frames<B> {
+    inner: B,
+    action: Action,
+    /// Data of messages that haven't been received in full yet.
+    buffer: Vec<u8>,
+}
+
+impl<B> Frames<B> {
+    fn new(inner: B, action: Action) -> Frames<B> {
+        Frames {
+            inner,
+            action,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The next message from the buffer once it holds one in full, with its 5 byte prefix of a
+    /// compressed flag and the message length.
+    fn next_message(&mut self) -> Option<Result<Bytes, Status>> {
+        let len = u32::from_be_bytes(self.buffer.get(1..5)?.try_into().unwrap()) as usize;
+        if self.buffer.len() < 5 + len {
+            return None;
+        }
+        let frame: Vec<u8> = self.buffer.drain(..5 + len).collect();
+        let (compressed, message) = (frame[0] == 1, &frame[5..]);
+        Some(match self.action {
+            Action::Compress if !compressed => {
+                let gzipped = gzip::compress(message);
+                // tiny messages can come out longer, those go as they are
+                Ok(if gzipped.len() < message.len() {
+                    frame_message(true, &gzipped)
+                } else {
+                    Bytes::from(frame)
+                })
+            }
+            Action::Decompress(limit) if compressed => gzip::decompress(message, limit)
+                .map(|message| frame_message(false, &message))
+                .map_err(|e| Status::internal(format!("Failed to decompress a message: {}", e))),
+            _ => Ok(Bytes::from(frame)),
+        })
+    }
+}
+
+fn frame_message(compressed: bool, message: &[u8]) -> Bytes {
+    let mut frame = Vec::with_capacity(5 + message.len());
+    frame.push(u8::from(compressed));
+    frame.extend((message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    Bytes::from(frame)
+}
+
+impl<B> Body for Frames<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: Into<StdError>,
+{
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Status>>> {
+        let frames = &mut *self;
+        loop {
+            if let Action::Pass = frames.action {
+                return Pin::new(&mut frames.inner)
+                    .poll_data(cx)
+                    .map_err(|e| Status::from_error(e.into()));
+            }
+            if let Some(message) = frames.next_message() {
+                return Poll::Ready(Some(message));
+            }
+            match ready!(Pin::new(&mut frames.inner).poll_data(cx)) {
+                Some(Ok(data)) => frames.buffer.extend_from_slice(&data),
+                Some(Err(e)) => return Poll::Ready(Some(Err(Status::from_error(e.into())))),
+                None if frames.buffer.is_empty() => return Poll::Ready(None),
+                None => return Poll::Ready(Some(Err(Status::internal("Message cut off")))),
+            }
+        }
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Status>> {
+        Pin::new(&mut self.inner)
+            .poll_trailers(cx)
+            .map_err(|e| Status::from_error(e.into()))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.buffer.is_empty() && self.inner.is_end_stream()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(messages: &[&[u8]]) -> Vec<u8> {
+        let frames = messages.iter().map(|message| frame_message(false, message));
+        frames.flatten().collect()
+    }
+
+    /// `data` sent through `Frames` in two chunks split mid-message, which have to be put back
+    /// together.
+    async fn pass_through(data: Vec<u8>, action: Action) -> Result<Bytes, Status> {
+        let (mut sender, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            let mut first = Bytes::from(data);
+            let second = first.split_off(first.len() / 2 + 3);
+            sender.send_data(first).await.unwrap();
+            sender.send_data(second).await.unwrap();
+        });
+        hyper::body::to_bytes(Frames::new(body, action)).await
+    }
+
+    #[tokio::test]
+    async fn compresses_and_restores_messages() {
+        let long = "-- alice has joined the room.\n".repeat(40);
+        let messages: [&[u8]; 3] = [long.as_bytes(), b"hi", long.as_bytes()];
+        let compressed = pass_through(frames(&messages), Action::Compress)
+            .await
+            .unwrap();
+        assert!(compressed.len() < long.len(), "{} bytes", compressed.len());
+        let mut flags = Vec::new();
+        let mut at = 0;
+        while at < compressed.len() {
+            flags.push(compressed[at]);
+            at += 5 + u32::from_be_bytes(compressed[at + 1..at + 5].try_into().unwrap()) as usize;
+        }
+        // the short one isn't worth compressing
+        assert_eq!(flags, [1, 0, 1]);
+
+        let restored = pass_through(compressed.to_vec(), Action::Decompress(1 << 20)).await;
+        assert_eq!(restored.unwrap(), frames(&messages));
+    }
+
+    #[tokio::test]
+    async fn refuses_messages_decompressing_past_the_limit() {
+        let frame = frame_message(true, &gzip::compress(&[0; 5000]));
+        let error = pass_through(frame.to_vec(), Action::Decompress(4096))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), tonic::Code::Internal);
+    }
+
+    #[test]
+    fn reads_the_accepted_encodings() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity, gzip"));
+        assert!(accepts_gzip(&headers));
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("deflate"));
+        assert!(!accepts_gzip(&headers));
+    }
+}
@@ -0,0 +1,220 @@
+//! Reader for the config files of the client and the server. They take the subset of TOML
+//! that flat settings need: `key = value` lines, optionally grouped under `[table]` headers,
+//! where a value is a string, an integer or a boolean, and `#` starts a comment.
+
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+/// The settings of a config file, keyed by their dotted name, e.g. `limits.max_room_users` for
+/// `max_room_users` under `[limits]`.
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    values: HashMap<String, Value>,
+}
+
+impl ConfigFile {
+    pub fn parse(text: &str) -> Result<ConfigFile, String> {
+        let mut config = ConfigFile::default();
+        let mut table = String::new();
+        for (number, line) in text.lines().enumerate() {
+            config
+                .parse_line(line.trim(), &mut table)
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+        }
+        Ok(config)
+    }
+
+    fn parse_line(&mut self, line: &str, table: &mut String) -> Result<(), String> {
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let (name, rest) = header
+                .split_once(']')
+                .ok_or_else(|| String::from("missing ] after the table name"))?;
+            expect_end(rest)?;
+            *table = key(name)?;
+            return Ok(());
+        }
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| String::from("expected key = value"))?;
+        let name = match key(name)? {
+            name if table.is_empty() => name,
+            name => format!("{}.{}", table, name),
+        };
+        let value = parse_value(value.trim())?;
+        if self.values.insert(name.clone(), value).is_some() {
+            return Err(format!("{} is set twice", name));
+        }
+        Ok(())
+    }
+
+    /// The settings not among `known`, which are likely misspelled.
+    pub fn unknown_keys(&self, known: &[&str]) -> Vec<&str> {
+        let mut unknown: Vec<&str> = self
+            .values
+            .keys()
+            .map(String::as_str)
+            .filter(|key| !known.contains(key))
+            .collect();
+        unknown.sort();
+        unknown
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<String>, String> {
+        match self.values.get(key) {
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(format!("{} must be a string", key)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn integer(&self, key: &str) -> Result<Option<i64>, String> {
+        match self.values.get(key) {
+            Some(Value::Integer(value)) => Ok(Some(*value)),
+            Some(_) => Err(format!("{} must be an integer", key)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn boolean(&self, key: &str) -> Result<Option<bool>, String> {
+        match self.values.get(key) {
+            Some(Value::Boolean(value)) => Ok(Some(*value)),
+            Some(_) => Err(format!("{} must be true or false", key)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A bare key, quoted keys and dotted ones aren't supported.
+fn key(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!("{:?} isn't a valid key", name));
+    }
+    Ok(name.to_string())
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        return basic_string(quoted).map(Value::String);
+    }
+    if let Some(quoted) = value.strip_prefix('\'') {
+        let (string, rest) = quoted
+            .split_once('\'')
+            .ok_or_else(|| String::from("missing closing '"))?;
+        expect_end(rest)?;
+        return Ok(Value::String(string.to_string()));
+    }
+    let value = value.split('#').next().unwrap_or_default().trim();
+    match value {
+        "true" => Ok(Value::Boolean(true)),
+        "false" => Ok(Value::Boolean(false)),
+        "" => Err(String::from("missing value")),
+        _ => value
+            .replace('_', "")
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("{} isn't a string, integer or boolean", value)),
+    }
+}
+
+/// A double-quoted string following its opening quote, with the common escapes.
+fn basic_string(quoted: &str) -> Result<String, String> {
+    let mut string = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((at, c)) = chars.next() {
+        match c {
+            '"' => {
+                expect_end(&quoted[at + 1..])?;
+                return Ok(string);
+            }
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => string.push('"'),
+                Some('\\') => string.push('\\'),
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                other => return Err(format!("unsupported escape \\{}", other.unwrap_or(' '))),
+            },
+            c => string.push(c),
+        }
+    }
+    Err(String::from("missing closing \""))
+}
+
+/// Checks that nothing but a comment follows a value or header.
+fn expect_end(rest: &str) -> Result<(), String> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected {}", rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values_under_tables() {
+        let config = ConfigFile::parse(
+            "# defaults\nserver = \"http://[::1]:50051\" # local\n\n[limits]\nmax_users = 1_000\nstrict = false\npath = 'C:\\chat'\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.string("server"),
+            Ok(Some("http://[::1]:50051".to_string()))
+        );
+        assert_eq!(config.integer("limits.max_users"), Ok(Some(1000)));
+        assert_eq!(config.boolean("limits.strict"), Ok(Some(false)));
+        assert_eq!(
+            config.string("limits.path"),
+            Ok(Some("C:\\chat".to_string()))
+        );
+        assert_eq!(config.string("room"), Ok(None));
+        assert_eq!(
+            config.integer("server"),
+            Err(String::from("server must be an integer"))
+        );
+        assert_eq!(
+            config.unknown_keys(&["server", "limits.max_users"]),
+            ["limits.path", "limits.strict"]
+        );
+    }
+
+    #[test]
+    fn handles_escapes_and_hashes_in_strings() {
+        let config = ConfigFile::parse(r#"motd = "say \"hi\" # not a comment""#).unwrap();
+        assert_eq!(
+            config.string("motd"),
+            Ok(Some("say \"hi\" # not a comment".to_string()))
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_an_error() {
+        let error = |text| ConfigFile::parse(text).unwrap_err();
+        assert_eq!(error("a = 1\nb"), "line 2: expected key = value");
+        assert_eq!(error("a = \"open"), "line 1: missing closing \"");
+        assert_eq!(
+            error("a = yes"),
+            "line 1: yes isn't a string, integer or boolean"
+        );
+        assert_eq!(error("a = 1\na = 2"), "line 2: a is set twice");
+        assert_eq!(error("[limits"), "line 1: missing ] after the table name");
+        assert_eq!(error("a b = 1"), "line 1: \"a b\" isn't a valid key");
+        assert_eq!(error("a = \"x\" y"), "line 1: unexpected y");
+    }
+}
@@ -0,0 +1,106 @@
+//! Emoji shortcodes like `:smile:`, which the client expands in what users send.
+
+/// The shortcodes known, a common set rather than every emoji there is. Sorted by shortcode for
+/// the binary search.
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("beer", "🍺"),
+    ("blush", "😊"),
+    ("bug", "🐛"),
+    ("clap", "👏"),
+    ("coffee", "☕"),
+    ("confused", "😕"),
+    ("crab", "🦀"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("ok_hand", "👌"),
+    ("party", "🥳"),
+    ("pray", "🙏"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("sad", "😞"),
+    ("scream", "😱"),
+    ("shrug", "🤷"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("sunglasses", "😎"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("warning", "⚠️"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+    ("x", "❌"),
+];
+
+/// `text` with every known shortcode replaced by its emoji. Unknown ones, and colons that don't
+/// start one, are left as they are.
+pub fn expand_shortcodes(text: &str) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after
+            .find(':')
+            .and_then(|end| Some((emoji(&after[..end])?, end)));
+        match emoji {
+            Some((emoji, end)) => {
+                expanded.push_str(emoji);
+                rest = &after[end + 1..];
+            }
+            None => {
+                // the closing colon may open the next shortcode, as in `10:30:smile:`
+                expanded.push(':');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn emoji(shortcode: &str) -> Option<&'static str> {
+    SHORTCODES
+        .binary_search_by_key(&shortcode, |(shortcode, _)| shortcode)
+        .ok()
+        .map(|at| SHORTCODES[at].1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortcodes_are_sorted() {
+        assert!(SHORTCODES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn expands_known_shortcodes() {
+        assert_eq!(expand_shortcodes("hi :wave: :tada::tada:"), "hi 👋 🎉🎉");
+        assert_eq!(expand_shortcodes(":+1:"), "👍");
+        assert_eq!(expand_shortcodes("at 10:30:smile:"), "at 10:30😄");
+    }
+
+    #[test]
+    fn leaves_everything_else_alone() {
+        assert_eq!(expand_shortcodes("see :nope: here"), "see :nope: here");
+        assert_eq!(expand_shortcodes("std::mem::swap"), "std::mem::swap");
+        assert_eq!(expand_shortcodes("a: b : c:"), "a: b : c:");
+        assert_eq!(expand_shortcodes("::"), "::");
+        assert_eq!(expand_shortcodes(""), "");
+    }
+}
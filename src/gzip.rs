@@ -0,0 +1,541 @@
+//! Gzip (RFC 1952) around deflate (RFC 1951), for compressing gRPC messages. tonic only
+//! compresses through a crate this build doesn't have, so the codec is written out here.
+//!
+//! Compression finds repeats through hash chains and codes them with the fixed Huffman codes in
+//! a single block, which is most of the gain on chat text for little code. Decompression takes
+//! any conforming stream, including the dynamic Huffman blocks other gRPC implementations send.
+
+/// How far back repeats are looked for, the most deflate allows.
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Candidates tried per position, more compresses slightly better and takes longer.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are sent in by dynamic blocks.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+/// `data` as a gzip member.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend(deflate(data));
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+/// The data of the gzip member `data`, an error if it's malformed or would be longer than
+/// `limit` bytes.
+pub fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    if data.len() < 18 || data[..3] != [0x1f, 0x8b, 8] {
+        return Err(String::from("not gzip data"));
+    }
+    let flags = data[3];
+    let mut at = 10;
+    let skip = |at: usize, len: usize| {
+        at.checked_add(len)
+            .filter(|end| *end <= data.len())
+            .ok_or_else(|| String::from("gzip header cut off"))
+    };
+    if flags & 0x04 != 0 {
+        // FEXTRA
+        let len = u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+        at = skip(at, 2 + len)?;
+    }
+    for flag in [0x08, 0x10] {
+        // FNAME and FCOMMENT, both zero-terminated
+        if flags & flag != 0 {
+            let len = data[at..]
+                .iter()
+                .position(|byte| *byte == 0)
+                .ok_or_else(|| String::from("gzip header cut off"))?;
+            at = skip(at, len + 1)?;
+        }
+    }
+    if flags & 0x02 != 0 {
+        // FHCRC
+        at = skip(at, 2)?;
+    }
+    let (inflated, end) = inflate(&data[at..], limit)?;
+    let trailer = data
+        .get(at + end..at + end + 8)
+        .ok_or_else(|| String::from("gzip trailer cut off"))?;
+    let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    if crc != crc32(&inflated) || len != inflated.len() as u32 {
+        return Err(String::from("gzip checksum mismatch"));
+    }
+    Ok(inflated)
+}
+
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    // a single final block with the fixed codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+    let mut chains = Chains::new(data);
+    let mut at = 0;
+    while at < data.len() {
+        let (len, distance) = chains.longest_match(at);
+        if len >= MIN_MATCH {
+            write_length(&mut writer, len);
+            write_distance(&mut writer, distance);
+            for position in at..at + len {
+                chains.insert(position);
+            }
+            at += len;
+        } else {
+            write_literal(&mut writer, u16::from(data[at]));
+            chains.insert(at);
+            at += 1;
+        }
+    }
+    write_literal(&mut writer, 256);
+    writer.finish()
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let key = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+    (key.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Earlier positions of the data by the hash of the three bytes starting there, each linked to
+/// the previous position with the same hash.
+struct Chains<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    previous: Vec<usize>,
+}
+
+impl<'a> Chains<'a> {
+    fn new(data: &'a [u8]) -> Chains<'a> {
+        Chains {
+            data,
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            previous: vec![usize::MAX; data.len()],
+        }
+    }
+
+    fn insert(&mut self, at: usize) {
+        if at + MIN_MATCH <= self.data.len() {
+            let hash = hash(&self.data[at..]);
+            self.previous[at] = self.head[hash];
+            self.head[hash] = at;
+        }
+    }
+
+    /// Length and distance of the longest earlier repeat of what starts at `at`, a length of
+    /// zero if there's none.
+    fn longest_match(&self, at: usize) -> (usize, usize) {
+        let data = self.data;
+        if at + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+        let max_len = MAX_MATCH.min(data.len() - at);
+        let mut best = (0, 0);
+        let mut candidate = self.head[hash(&data[at..])];
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || at - candidate > WINDOW {
+                break;
+            }
+            let len = data[candidate..]
+                .iter()
+                .zip(&data[at..at + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.0 {
+                best = (len, at - candidate);
+                if len == max_len {
+                    break;
+                }
+            }
+            candidate = self.previous[candidate];
+        }
+        best
+    }
+}
+
+/// Writes `symbol` of the literal/length alphabet with its fixed code.
+fn write_literal(writer: &mut BitWriter, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8),
+    };
+    writer.write_code(code, len);
+}
+
+fn write_length(writer: &mut BitWriter, len: usize) {
+    let index = LENGTH_BASE
+        .iter()
+        .rposition(|base| *base as usize <= len)
+        .unwrap();
+    write_literal(writer, 257 + index as u16);
+    let extra = len as u32 - u32::from(LENGTH_BASE[index]);
+    writer.write(extra, u32::from(LENGTH_EXTRA[index]));
+}
+
+fn write_distance(writer: &mut BitWriter, distance: usize) {
+    let index = DISTANCE_BASE
+        .iter()
+        .rposition(|base| *base as usize <= distance)
+        .unwrap();
+    writer.write_code(index as u16, 5);
+    let extra = distance as u32 - u32::from(DISTANCE_BASE[index]);
+    writer.write(extra, u32::from(DISTANCE_EXTRA[index]));
+}
+
+/// Packs bits into bytes starting from the least significant one, as deflate does.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, len: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += len;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which goes most significant bit first unlike everything else.
+    fn write_code(&mut self, code: u16, len: u32) {
+        let reversed = (code.reverse_bits() >> (16 - len)) as u32;
+        self.write(reversed, len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// The data of the deflate stream at the start of `data` and how many bytes of it the stream
+/// took.
+fn inflate(data: &[u8], limit: usize) -> Result<(Vec<u8>, usize), String> {
+    let mut reader = BitReader {
+        data,
+        at: 0,
+        bit: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let len = reader.bits(16)?;
+                if reader.bits(16)? != !len & 0xffff {
+                    return Err(String::from("stored block length mismatch"));
+                }
+                let block = data
+                    .get(reader.at..reader.at + len as usize)
+                    .ok_or_else(|| String::from("deflate data cut off"))?;
+                if out.len() + block.len() > limit {
+                    return Err(String::from("decompressed data too long"));
+                }
+                out.extend_from_slice(block);
+                reader.at += block.len();
+            }
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                let literals = Huffman::new(&lengths)?;
+                let distances = Huffman::new(&[5; 30])?;
+                inflate_block(&mut reader, &mut out, &literals, &distances, limit)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut out, &literals, &distances, limit)?;
+            }
+            _ => return Err(String::from("invalid deflate block type")),
+        }
+        if last {
+            reader.align();
+            return Ok((out, reader.at));
+        }
+    }
+}
+
+fn read_dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    let mut code_lengths = [0; 19];
+    for index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[*index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| String::from("repeat without a previous length"))?;
+                (previous, 3 + reader.bits(2)?)
+            }
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err(String::from("code lengths overrun"));
+    }
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..])?;
+    Ok((literals, distances))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+    limit: usize,
+) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let index = symbol as usize - 257;
+                if index >= LENGTH_BASE.len() {
+                    return Err(String::from("invalid length code"));
+                }
+                let len = LENGTH_BASE[index] as usize
+                    + reader.bits(u32::from(LENGTH_EXTRA[index]))? as usize;
+                let index = distances.decode(reader)? as usize;
+                if index >= DISTANCE_BASE.len() {
+                    return Err(String::from("invalid distance code"));
+                }
+                let distance = DISTANCE_BASE[index] as usize
+                    + reader.bits(u32::from(DISTANCE_EXTRA[index]))? as usize;
+                if distance > out.len() {
+                    return Err(String::from("distance before the start of the data"));
+                }
+                // copied a byte at a time, a repeat may overlap what it produces
+                let start = out.len() - distance;
+                for at in start..start + len {
+                    out.push(out[at]);
+                }
+            }
+        }
+        if out.len() > limit {
+            return Err(String::from("decompressed data too long"));
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    at: usize,
+    bit: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> Result<u32, String> {
+        let mut value = 0;
+        for shift in 0..count {
+            let byte = self
+                .data
+                .get(self.at)
+                .ok_or_else(|| String::from("deflate data cut off"))?;
+            value |= u32::from(byte >> self.bit & 1) << shift;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.at += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary.
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.bit = 0;
+            self.at += 1;
+        }
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and the symbols ordered by
+/// code.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// The code giving symbol `i` a code of `lengths[i]` bits, zero for symbols not used.
+    fn new(lengths: &[u8]) -> Result<Huffman, String> {
+        let mut counts = [0; 16];
+        for len in lengths {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0; 16];
+        for len in 1..16 {
+            offsets[len] = offsets[len - 1] + counts[len - 1];
+        }
+        let mut symbols = vec![0; lengths.iter().filter(|len| **len > 0).count()];
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len > 0 {
+                symbols[offsets[*len as usize] as usize] = symbol as u16;
+                offsets[*len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0_i32, 0_i32, 0_i32);
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = i32::from(self.counts[len]);
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(String::from("invalid Huffman code"))
+    }
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        CRC_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: usize = 1 << 20;
+
+    #[test]
+    fn computes_the_standard_crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn round_trips_chat_text() {
+        let text = "-- alice has joined the room.\n-- bob has joined the room.\n".repeat(20);
+        let compressed = compress(text.as_bytes());
+        assert!(
+            compressed.len() < text.len() / 4,
+            "{} bytes",
+            compressed.len()
+        );
+        assert_eq!(decompress(&compressed, LIMIT).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn round_trips_edge_cases() {
+        let long_run = vec![b'a'; 100_000];
+        let far_repeat: Vec<u8> = (0..40_000_u32).map(|n| (n * 7919 % 251) as u8).collect();
+        let cases: [&[u8]; 5] = [b"", b"x", b"\xff\xfe\x00\x90", &long_run, &far_repeat];
+        for data in cases {
+            assert_eq!(decompress(&compress(data), LIMIT).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn decompresses_other_encoders_output() {
+        // from Python's gzip module: a fixed Huffman block, a stored one and a dynamic one
+        let fixed = [
+            31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 203, 72, 205, 201, 201, 87, 200, 64, 144, 0, 128, 136,
+            249, 229, 17, 0, 0, 0,
+        ];
+        assert_eq!(decompress(&fixed, LIMIT).unwrap(), b"hello hello hello");
+        let stored = [
+            31, 139, 8, 0, 0, 0, 0, 0, 4, 3, 1, 6, 0, 249, 255, 115, 116, 111, 114, 101, 100, 11,
+            249, 67, 86, 6, 0, 0, 0,
+        ];
+        assert_eq!(decompress(&stored, LIMIT).unwrap(), b"stored");
+        let dynamic = [
+            31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 181, 202, 75, 1, 128, 32, 16, 5, 192, 42, 47, 129, 89,
+            60, 80, 0, 20, 100, 21, 92, 69, 62, 66, 122, 183, 132, 231, 25, 229, 45, 238, 66, 203,
+            1, 147, 184, 157, 112, 252, 98, 47, 241, 122, 192, 213, 38, 100, 225, 160, 71, 199,
+            202, 219, 4, 245, 91, 158, 181, 188, 216, 97, 36, 53, 202, 30, 142, 170, 21, 26, 31,
+            85, 23, 67, 103, 160, 0, 0, 0,
+        ];
+        let text =
+            "The quick brown fox jumps over the lazy dog. ".repeat(3) + "Pack my box with five doz";
+        assert_eq!(decompress(&dynamic, LIMIT).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn refuses_data_past_the_limit() {
+        let compressed = compress(&[0; 10_000]);
+        assert!(decompress(&compressed, 9_999).is_err());
+        assert_eq!(decompress(&compressed, 10_000).unwrap().len(), 10_000);
+    }
+
+    #[test]
+    fn refuses_corrupt_data() {
+        let mut compressed = compress(b"some text that gets mangled");
+        let middle = compressed.len() / 2;
+        compressed[middle] ^= 0x55;
+        assert!(decompress(&compressed, LIMIT).is_err());
+        assert!(decompress(&compressed[..12], LIMIT).is_err());
+        assert!(decompress(b"plain text, not gzip", LIMIT).is_err());
+    }
+}
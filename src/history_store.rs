@@ -0,0 +1,359 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use super::chat::{Kind, Style};
+use super::ChatMessage;
+
+/// Persistent log of room messages, one JSON object per line.
+///
+/// Appending is cheap and survives crashes mid-write (a torn last line is skipped on read).
+/// Where each room's lines start is indexed when opening, so reads only touch the lines of
+/// their room. Edits and deletions are appended as well and applied to their messages when
+/// reading. Reads and writes block, callers on the runtime go through `spawn_blocking`.
+pub struct HistoryStore {
+    path: PathBuf,
+    log: Mutex<Log>,
+}
+
+/// The file being appended to, along with the index, kept under one lock so every offset in
+/// the index points at a line that was written completely.
+struct Log {
+    file: File,
+    len: u64,
+    /// Offsets of each room's lines since it was last closed, in the order they were written.
+    rooms: HashMap<String, Vec<u64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredMessage {
+    room: String,
+    sender: String,
+    content: String,
+    timestamp: i64,
+    /// Missing on lines written before kinds existed, which were all chat messages.
+    #[serde(default)]
+    kind: i32,
+    /// Missing on lines written before messages had ids. What a reply quoted isn't kept.
+    #[serde(default)]
+    id: String,
+    /// Set when reading a message that was edited later on.
+    #[serde(skip)]
+    edited: bool,
+    /// Marks where a room was closed, nothing of it before this line is read anymore.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    closed: bool,
+}
+
+impl HistoryStore {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<HistoryStore> {
+        let path = path.into();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut rooms: HashMap<String, Vec<u64>> = HashMap::new();
+        let mut reader = BufReader::new(&file);
+        let mut line = Vec::new();
+        let mut len = 0;
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)? as u64;
+            if read == 0 {
+                break;
+            }
+            if let Ok(stored) = serde_json::from_slice::<StoredMessage>(&line) {
+                let offsets = rooms.entry(stored.room).or_default();
+                if stored.closed {
+                    offsets.clear();
+                } else {
+                    offsets.push(len);
+                }
+            }
+            len += read;
+        }
+        // a torn last line would swallow the next one written
+        if line.last().is_some_and(|last| *last != b'\n') {
+            file.write_all(b"\n")?;
+            len += 1;
+        }
+        Ok(HistoryStore {
+            path,
+            log: Mutex::new(Log { file, len, rooms }),
+        })
+    }
+
+    pub fn record(&self, message: &ChatMessage) -> io::Result<()> {
+        let stored = StoredMessage {
+            room: message.chatroom.clone(),
+            sender: message.sender.clone(),
+            content: message.content.clone(),
+            timestamp: message.timestamp,
+            kind: message.kind,
+            id: message.id.clone(),
+            edited: false,
+            closed: false,
+        };
+        self.write(&stored)
+    }
+
+    /// Closes a room, hiding its messages so far from later reads. Protected rooms are closed
+    /// when they empty, so whoever creates one of the same name again can't read them.
+    pub fn close_room(&self, room: &str) -> io::Result<()> {
+        self.write(&StoredMessage {
+            room: room.to_string(),
+            sender: String::new(),
+            content: String::new(),
+            timestamp: 0,
+            kind: 0,
+            id: String::new(),
+            edited: false,
+            closed: true,
+        })
+    }
+
+    fn write(&self, stored: &StoredMessage) -> io::Result<()> {
+        let mut line = serde_json::to_string(stored)?;
+        line.push('\n');
+        let mut log = self.log.lock().unwrap();
+        if let Err(e) = log.file.write_all(line.as_bytes()) {
+            // part of the line may have made it, later ones start after it
+            log.len = log.file.metadata()?.len();
+            return Err(e);
+        }
+        let at = log.len;
+        log.len += line.len() as u64;
+        let offsets = log.rooms.entry(stored.room.clone()).or_default();
+        if stored.closed {
+            offsets.clear();
+        } else {
+            offsets.push(at);
+        }
+        Ok(())
+    }
+
+    /// The last `limit` messages of a room, oldest first.
+    pub fn last(&self, room: &str, limit: usize) -> io::Result<Vec<ChatMessage>> {
+        self.page(room, None, limit).map(|(messages, _)| messages)
+    }
+
+    /// The last `limit` messages of a room sent before the timestamp `before`, oldest first,
+    /// along with whether there are earlier ones.
+    pub fn page(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> io::Result<(Vec<ChatMessage>, bool)> {
+        let mut found = VecDeque::with_capacity(limit);
+        let mut more = false;
+        for stored in self.room(room)? {
+            if before.is_some_and(|before| stored.timestamp >= before) {
+                continue;
+            }
+            if found.len() == limit {
+                found.pop_front();
+                more = true;
+            }
+            found.push_back(stored);
+        }
+        Ok((found.into_iter().map(ChatMessage::from).collect(), more))
+    }
+
+    /// The last `limit` messages of a room containing `query`, ignoring case, oldest first,
+    /// along with the total number of matches.
+    pub fn search(
+        &self,
+        room: &str,
+        query: &str,
+        limit: usize,
+    ) -> io::Result<(Vec<ChatMessage>, usize)> {
+        let query = query.to_lowercase();
+        let mut found = VecDeque::with_capacity(limit);
+        let mut total = 0;
+        for stored in self.room(room)? {
+            if stored.content.to_lowercase().contains(&query) {
+                total += 1;
+                if found.len() == limit {
+                    found.pop_front();
+                }
+                found.push_back(stored);
+            }
+        }
+        Ok((found.into_iter().map(ChatMessage::from).collect(), total))
+    }
+
+    /// The messages of a room as they read now, oldest first, with the latest edit of each
+    /// applied and the deleted ones left out.
+    fn room(&self, room: &str) -> io::Result<impl Iterator<Item = StoredMessage>> {
+        let lines = self.read(room)?;
+        let mut edits = HashMap::new();
+        let mut deleted = HashSet::new();
+        for stored in &lines {
+            if stored.kind == Kind::Edit as i32 {
+                edits.insert(stored.id.clone(), stored.content.clone());
+            } else if stored.kind == Kind::Delete as i32 {
+                deleted.insert(stored.id.clone());
+            }
+        }
+        Ok(lines
+            .into_iter()
+            .filter(move |stored| {
+                stored.kind != Kind::Edit as i32
+                    && stored.kind != Kind::Delete as i32
+                    && (stored.id.is_empty() || !deleted.contains(&stored.id))
+            })
+            .map(move |mut stored| {
+                if let Some(content) = edits.get(&stored.id).filter(|_| !stored.id.is_empty()) {
+                    stored.content = content.clone();
+                    stored.edited = true;
+                }
+                stored
+            }))
+    }
+
+    /// The lines of a room since it was last closed, read from their offsets in the index.
+    fn read(&self, room: &str) -> io::Result<Vec<StoredMessage>> {
+        let offsets = match self.log.lock().unwrap().rooms.get(room) {
+            Some(offsets) => offsets.clone(),
+            None => return Ok(Vec::new()),
+        };
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut position = 0;
+        let mut line = Vec::new();
+        let mut lines = Vec::with_capacity(offsets.len());
+        for offset in offsets {
+            // relative seeks keep what's buffered, lines of a busy room are often close
+            reader.seek_relative(offset as i64 - position as i64)?;
+            line.clear();
+            position = offset + reader.read_until(b'\n', &mut line)? as u64;
+            if let Ok(stored) = serde_json::from_slice(&line) {
+                lines.push(stored);
+            }
+        }
+        Ok(lines)
+    }
+}
+
+impl From<StoredMessage> for ChatMessage {
+    fn from(stored: StoredMessage) -> ChatMessage {
+        ChatMessage {
+            sender: stored.sender,
+            timestamp: stored.timestamp,
+            chatroom: stored.room,
+            content: stored.content,
+            target: String::new(),
+            style: Style::Plain as i32,
+            kind: stored.kind,
+            id: stored.id,
+            edited: stored.edited,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_edits_and_deletions_when_reading() {
+        let path = std::env::temp_dir().join(format!("nc_history_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = HistoryStore::open(&path).unwrap();
+        let message = |id: &str, kind: Kind, content: &str| ChatMessage {
+            sender: "alice".to_string(),
+            chatroom: "rust".to_string(),
+            content: content.to_string(),
+            kind: kind as i32,
+            id: id.to_string(),
+            ..Default::default()
+        };
+        store.record(&message("a1", Kind::Chat, "helo")).unwrap();
+        store.record(&message("a2", Kind::Chat, "world")).unwrap();
+        store.record(&message("a1", Kind::Edit, "hello")).unwrap();
+        store.record(&message("a3", Kind::Chat, "oops")).unwrap();
+        store.record(&message("a3", Kind::Delete, "")).unwrap();
+
+        let last = store.last("rust", 10).unwrap();
+        let contents: Vec<_> = last
+            .iter()
+            .map(|m| (m.content.as_str(), m.edited))
+            .collect();
+        assert_eq!(contents, [("hello", true), ("world", false)]);
+        let (page, more) = store.page("rust", Some(i64::MAX), 1).unwrap();
+        assert_eq!(page[0].content, "world");
+        assert!(more);
+        let (found, total) = store.search("rust", "helo", 10).unwrap();
+        assert!(found.is_empty());
+        assert_eq!(total, 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn hides_what_was_said_before_a_room_closed() {
+        let path = std::env::temp_dir().join(format!("nc_closed_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = HistoryStore::open(&path).unwrap();
+        let message = |room: &str, content: &str| ChatMessage {
+            sender: "alice".to_string(),
+            chatroom: room.to_string(),
+            content: content.to_string(),
+            kind: Kind::Chat as i32,
+            ..Default::default()
+        };
+        store.record(&message("vault", "the code is 1234")).unwrap();
+        store.record(&message("rust", "hi")).unwrap();
+        store.close_room("vault").unwrap();
+        assert!(store.last("vault", 10).unwrap().is_empty());
+        store.record(&message("vault", "new here")).unwrap();
+        let contents: Vec<_> = store
+            .last("vault", 10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(contents, ["new here"]);
+        assert_eq!(store.last("rust", 10).unwrap().len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn indexes_what_was_written_before_opening() {
+        let path = std::env::temp_dir().join(format!("nc_reopen_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let message = |room: &str, content: &str| ChatMessage {
+            sender: "alice".to_string(),
+            chatroom: room.to_string(),
+            content: content.to_string(),
+            kind: Kind::Chat as i32,
+            ..Default::default()
+        };
+        let store = HistoryStore::open(&path).unwrap();
+        store.record(&message("rust", "first")).unwrap();
+        store.record(&message("go", "elsewhere")).unwrap();
+        store.close_room("vault").unwrap();
+        drop(store);
+        // a crash mid-write leaves a torn line behind
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"room\":\"rust\",\"sen").unwrap();
+
+        let store = HistoryStore::open(&path).unwrap();
+        store.record(&message("rust", "second")).unwrap();
+        let contents: Vec<_> = store
+            .last("rust", 10)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(contents, ["first", "second"]);
+        assert_eq!(store.last("go", 10).unwrap()[0].content, "elsewhere");
+        assert!(store.last("vault", 10).unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,1081 @@
+//! Runs a server with fresh state on an ephemeral port and talks to it through the generated
+//! gRPC client, the same way `novachat` does.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{TcpListenerStream, UnboundedReceiverStream};
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, Server};
+use tonic::{Code, Request, Streaming};
+
+use super::chat::chat_client::ChatClient;
+use super::chat::chat_server::ChatServer;
+use super::chat::{
+    ChatMessage, GetHistoryRequest, Kind, ListRoomsRequest, NameCheckRequest, Style,
+};
+use super::compression::Gzip;
+use super::reflection::proto::server_reflection_client::ServerReflectionClient;
+use super::reflection::proto::server_reflection_request::MessageRequest;
+use super::reflection::proto::server_reflection_response::MessageResponse;
+use super::reflection::proto::ServerReflectionRequest;
+use super::reflection::{Reflection, ServerReflectionServer};
+use super::state::{Config, ServerState};
+use super::ChatService;
+
+/// How long a client waits for an expected message before the test fails.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest decompressed message on either end.
+const MAX_DECOMPRESSED: usize = 1 << 20;
+
+async fn start_server() -> SocketAddr {
+    start_server_with(Config::default()).await
+}
+
+async fn start_server_with(config: Config) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = ChatService::new(Arc::new(ServerState::new(config)));
+    tokio::spawn(
+        Server::builder()
+            .add_service(Gzip::new(ChatServer::new(service), true, MAX_DECOMPRESSED))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    addr
+}
+
+async fn connect(addr: SocketAddr) -> ChatClient<Gzip<Channel>> {
+    connect_with(addr, true).await
+}
+
+async fn connect_with(addr: SocketAddr, compression: bool) -> ChatClient<Gzip<Channel>> {
+    let channel = Channel::from_shared(format!("http://{}", addr))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    ChatClient::new(Gzip::new(channel, compression, MAX_DECOMPRESSED))
+}
+
+async fn name_available(client: &mut ChatClient<Gzip<Channel>>, name: &str) -> bool {
+    let request = NameCheckRequest {
+        name: name.to_string(),
+    };
+    client
+        .check_for_name(request)
+        .await
+        .unwrap()
+        .into_inner()
+        .available
+}
+
+/// A connected user, tracking its room like the real client does.
+struct TestUser {
+    name: String,
+    room: String,
+    outbound: mpsc::UnboundedSender<ChatMessage>,
+    inbound: Streaming<ChatMessage>,
+}
+
+impl TestUser {
+    async fn connect(addr: SocketAddr, name: &str) -> TestUser {
+        TestUser::with_client(connect(addr).await, name).await
+    }
+
+    async fn with_client(mut client: ChatClient<Gzip<Channel>>, name: &str) -> TestUser {
+        assert!(name_available(&mut client, name).await);
+        let (outbound, outbound_rx) = mpsc::unbounded_channel();
+        let inbound = client
+            .live_chat(UnboundedReceiverStream::new(outbound_rx))
+            .await
+            .unwrap()
+            .into_inner();
+        TestUser {
+            name: name.to_string(),
+            room: String::new(),
+            outbound,
+            inbound,
+        }
+    }
+
+    fn send(&self, content: &str) {
+        let message = ChatMessage {
+            sender: self.name.clone(),
+            chatroom: self.room.clone(),
+            content: content.to_string(),
+            ..Default::default()
+        };
+        self.outbound.send(message).unwrap();
+    }
+
+    async fn join(&mut self, room: &str) {
+        self.send(&format!("!join {}", room));
+        let announcement = format!("-- {} has joined {}", self.name, room);
+        self.expect(|m| m.kind() == Kind::Join && m.content.starts_with(&announcement))
+            .await;
+        self.room = room.to_string();
+    }
+
+    /// Skips messages until one matches `expected`, failing the test if none arrives in time.
+    async fn expect(&mut self, expected: impl Fn(&ChatMessage) -> bool) -> ChatMessage {
+        let name = self.name.clone();
+        tokio::time::timeout(RECEIVE_TIMEOUT, async {
+            loop {
+                match self.inbound.message().await.unwrap() {
+                    Some(message) if expected(&message) => return message,
+                    Some(_) => continue,
+                    None => panic!("connection of {} closed", name),
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{} didn't receive the expected message", name))
+    }
+
+    /// The next message that isn't a server notice or typing event.
+    async fn next_chat(&mut self) -> ChatMessage {
+        self.expect(|m| m.sender != "server" && m.kind() != Kind::Typing)
+            .await
+    }
+}
+
+#[tokio::test]
+async fn users_in_a_room_see_each_others_messages() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+    alice
+        .expect(|m| {
+            m.kind() == Kind::Join && m.content == "-- bob has joined rust, 1 user was already here"
+        })
+        .await;
+
+    alice.send("hi bob");
+    let message = bob.next_chat().await;
+    assert_eq!(message.sender, "alice");
+    assert_eq!(message.chatroom, "rust");
+    assert_eq!(message.content, "hi bob");
+    assert_eq!(message.kind(), Kind::Chat);
+
+    bob.send("hi alice");
+    let message = alice
+        .expect(|m| m.sender == "bob" && m.kind() == Kind::Chat)
+        .await;
+    assert_eq!(message.content, "hi alice");
+}
+
+#[tokio::test]
+async fn escape_sequences_are_stripped() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+
+    alice.send("\x1b[2Jgotcha\x1b[31m");
+    let message = bob.next_chat().await;
+    assert_eq!(message.content, "gotcha");
+    // nothing is left of a message that was only escape codes, so it isn't sent
+    alice.send("\x1b[2J");
+    alice.send("after");
+    assert_eq!(bob.next_chat().await.content, "after");
+}
+
+#[tokio::test]
+async fn blank_messages_arent_broadcast() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+
+    alice.send("  \t ");
+    alice.send("\n\n");
+    alice.send("after");
+    assert_eq!(bob.next_chat().await.content, "after");
+}
+
+#[tokio::test]
+async fn leaving_is_announced_to_the_room() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    let mut carol = TestUser::connect(addr, "carol").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+    carol.join("rust").await;
+
+    bob.join("python").await;
+    let notice = alice.expect(|m| m.kind() == Kind::Leave).await;
+    assert_eq!(notice.content, "-- bob has left rust");
+
+    drop(carol);
+    let notice = alice.expect(|m| m.kind() == Kind::Leave).await;
+    assert_eq!(notice.sender, "server");
+    assert_eq!(notice.content, "-- carol has left.");
+}
+
+#[tokio::test]
+async fn direct_messages_only_reach_their_target() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    let mut carol = TestUser::connect(addr, "carol").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+    carol.join("rust").await;
+
+    alice.send("!dm bob psst");
+    let message = bob.next_chat().await;
+    assert_eq!(message.kind(), Kind::DirectMessage);
+    assert_eq!(message.sender, "alice");
+    assert_eq!(message.target, "bob");
+    assert_eq!(message.content, "psst");
+
+    // the room message sent afterwards is the first one carol sees
+    alice.send("hello everyone");
+    let message = carol.next_chat().await;
+    assert_eq!(message.content, "hello everyone");
+}
+
+#[tokio::test]
+async fn whispers_reach_users_in_other_rooms() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    let mut carol = TestUser::connect(addr, "carol").await;
+    alice.join("rust").await;
+    bob.join("go").await;
+    carol.join("rust").await;
+
+    alice.send("!whisper bob lunch?");
+    let whisper = bob.next_chat().await;
+    assert_eq!(whisper.kind(), Kind::DirectMessage);
+    assert_eq!(whisper.style(), Style::Highlight);
+    assert_eq!(whisper.sender, "alice");
+    assert_eq!(whisper.content, "lunch?");
+    // alice gets her copy as the confirmation, and is still in her room
+    let copy = alice.next_chat().await;
+    assert_eq!((copy.target.as_str(), copy.id), ("bob", whisper.id));
+    alice.send("back in rust");
+    assert_eq!(carol.next_chat().await.content, "back in rust");
+
+    // bob whispers back from his room without leaving it
+    bob.send("!whisper alice sure");
+    let reply = alice
+        .expect(|m| m.kind() == Kind::DirectMessage && m.sender == "bob")
+        .await;
+    assert_eq!(reply.content, "sure");
+}
+
+#[tokio::test]
+async fn direct_messages_of_blocked_users_are_dropped() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut mallory = TestUser::connect(addr, "mallory").await;
+    alice.join("rust").await;
+    mallory.join("go").await;
+
+    alice.send("!block mallory");
+    let notice = alice
+        .expect(|m| m.sender == "server" && m.target == "alice")
+        .await;
+    assert!(notice.content.starts_with("-- Blocked mallory"));
+
+    // to mallory it looks like any delivered message
+    mallory.send("!dm alice hey");
+    let copy = mallory.next_chat().await;
+    assert_eq!(
+        (copy.kind(), copy.target.as_str()),
+        (Kind::DirectMessage, "alice")
+    );
+    let receipt = mallory.expect(|m| m.kind() == Kind::Delivered).await;
+    assert_eq!(receipt.id, copy.id);
+    assert_eq!(receipt.content, "Delivered to alice.");
+    mallory.send("!whisper alice hey again");
+    mallory.expect(|m| m.kind() == Kind::Delivered).await;
+
+    alice.send("!unblock mallory");
+    mallory.send("!dm alice sorry");
+    // the first direct message alice sees is the one after the block was lifted
+    let message = alice.next_chat().await;
+    assert_eq!(message.content, "sorry");
+}
+
+#[tokio::test]
+async fn direct_messages_are_confirmed_once_delivered() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+
+    alice.send("!dm bob psst");
+    let sent = alice.expect(|m| m.kind() == Kind::DirectMessage).await;
+    let receipt = alice.expect(|m| m.kind() == Kind::Delivered).await;
+    assert_eq!(receipt.id, sent.id);
+    assert_eq!(receipt.content, "Delivered to bob.");
+
+    alice.send("!dm carol later");
+    let notice = alice.expect(|m| m.sender == "server").await;
+    assert!(notice
+        .content
+        .starts_with("No user named carol is currently connected"));
+    let mut carol = TestUser::connect(addr, "carol").await;
+    carol.join("go").await;
+    let receipt = alice.expect(|m| m.kind() == Kind::Delivered).await;
+    assert_eq!(receipt.content, "Delivered to carol.");
+}
+
+#[tokio::test]
+async fn history_is_read_a_page_at_a_time() {
+    let path = std::env::temp_dir().join(format!("nc_get_history_{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let addr = start_server_with(Config {
+        history_path: Some(path.to_string_lossy().into_owned()),
+        ..Config::default()
+    })
+    .await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    alice.join("rust").await;
+    for content in ["one", "two", "three"] {
+        alice.send(content);
+        alice.expect(|m| m.content == content).await;
+        // the cursor is a timestamp, which messages sent within a millisecond share
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    let mut client = connect(addr).await;
+    let page = |before| {
+        let request = GetHistoryRequest {
+            room: "Rust".to_string(),
+            limit: 2,
+            before,
+            ..Default::default()
+        };
+        let mut client = client.clone();
+        async move { client.get_history(request).await.unwrap().into_inner() }
+    };
+    let latest = page(0).await;
+    let contents: Vec<_> = latest.messages.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, ["two", "three"]);
+    assert!(latest.more);
+    let earlier = page(latest.messages[0].timestamp).await;
+    let contents: Vec<_> = earlier
+        .messages
+        .iter()
+        .map(|m| m.content.as_str())
+        .collect();
+    assert_eq!(contents, ["one"]);
+    assert!(!earlier.more);
+
+    alice.send("!join vault secret");
+    alice
+        .expect(|m| m.content == "-- alice has joined vault, a new room")
+        .await;
+    alice.room = String::from("vault");
+    alice.send("hidden");
+    alice.expect(|m| m.content == "hidden").await;
+    let request = |password: &str| GetHistoryRequest {
+        room: "vault".to_string(),
+        password: password.to_string(),
+        ..Default::default()
+    };
+    for password in ["", "guess"] {
+        let status = client.get_history(request(password)).await.unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+    let vault = client.get_history(request("secret")).await.unwrap();
+    assert_eq!(vault.into_inner().messages[0].content, "hidden");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn history_reads_are_rate_limited() {
+    let path = std::env::temp_dir().join(format!("nc_history_limit_{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let addr = start_server_with(Config {
+        history_path: Some(path.to_string_lossy().into_owned()),
+        flood_messages: 2,
+        ..Config::default()
+    })
+    .await;
+    let mut client = connect(addr).await;
+    let request = || GetHistoryRequest {
+        room: "rust".to_string(),
+        ..Default::default()
+    };
+    for _ in 0..2 {
+        client.get_history(request()).await.unwrap();
+    }
+    let status = client.get_history(request()).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn taken_names_are_rejected() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    alice.join("rust").await;
+
+    let mut client = connect(addr).await;
+    assert!(!name_available(&mut client, "alice").await);
+    assert!(name_available(&mut client, "bob").await);
+}
+
+#[tokio::test]
+async fn servers_dont_share_state() {
+    let first = start_server().await;
+    let second = start_server().await;
+    let _alice = TestUser::connect(first, "alice").await;
+
+    let mut client = connect(second).await;
+    assert!(name_available(&mut client, "alice").await);
+}
+
+#[tokio::test]
+async fn messages_go_to_the_room_the_server_has_the_sender_in() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    alice.send("!join go secret");
+    alice
+        .expect(|m| m.content == "-- alice has joined go, a new room")
+        .await;
+    alice.room = String::from("go");
+    bob.join("rust").await;
+
+    // bob claims to be in the protected room without having given its password
+    bob.room = String::from("go");
+    bob.send("sneaking in");
+    assert_eq!(bob.next_chat().await.chatroom, "rust");
+    bob.send("!topic hijacked");
+    bob.expect(|m| m.content.contains("hijacked") && m.chatroom == "rust")
+        .await;
+
+    alice.send("still private");
+    assert_eq!(alice.next_chat().await.content, "still private");
+    let mut client = connect(addr).await;
+    let rooms = client
+        .list_rooms(ListRoomsRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .rooms;
+    let go = rooms.iter().find(|room| room.name == "go").unwrap();
+    assert_eq!(go.topic, "");
+}
+
+#[tokio::test]
+async fn kicked_users_no_longer_reach_the_room() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    let mut carol = TestUser::connect(addr, "carol").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+    carol.join("rust").await;
+
+    alice.send("!kick bob");
+    bob.expect(|m| m.content.starts_with("You were kicked from rust"))
+        .await;
+    // bob's client ignores the kick and keeps naming the room
+    bob.send("still here");
+    alice.send("bye bob");
+    assert_eq!(carol.next_chat().await.content, "bye bob");
+}
+
+#[tokio::test]
+async fn rooms_are_listed_with_their_user_counts() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    let _carol = TestUser::connect(addr, "carol").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+    bob.send("!topic borrowck");
+    bob.expect(|m| m.content.contains("borrowck")).await;
+    alice.send("!join go secret");
+    alice
+        .expect(|m| m.content == "-- alice has joined go, a new room")
+        .await;
+
+    let mut client = connect(addr).await;
+    let response = client
+        .list_rooms(ListRoomsRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(response.max_users, 50);
+    let rooms: Vec<_> = response
+        .rooms
+        .iter()
+        .map(|r| {
+            (
+                r.name.as_str(),
+                r.users,
+                r.topic.as_str(),
+                r.password_protected,
+            )
+        })
+        .collect();
+    assert_eq!(rooms, [("go", 1, "", true), ("rust", 1, "borrowck", false)]);
+}
+
+#[tokio::test]
+async fn emptied_rooms_lose_their_password() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    let mut carol = TestUser::connect(addr, "carol").await;
+    alice.send("!join go secret");
+    alice
+        .expect(|m| m.content == "-- alice has joined go, a new room")
+        .await;
+    carol.send("!join vault secret");
+    carol
+        .expect(|m| m.content == "-- carol has joined vault, a new room")
+        .await;
+    alice.join("rust").await;
+    drop(carol);
+    let mut client = connect(addr).await;
+    while !name_available(&mut client, "carol").await {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    for room in ["go", "vault"] {
+        bob.send(&format!("!join {}", room));
+        let joined = format!("-- bob has joined {}, a new room", room);
+        bob.expect(|m| m.content == joined).await;
+    }
+    let rooms = client
+        .list_rooms(ListRoomsRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .rooms;
+    assert!(rooms.iter().all(|room| !room.password_protected));
+}
+
+#[tokio::test]
+async fn emptied_protected_rooms_dont_replay_what_was_said() {
+    let path = std::env::temp_dir().join(format!("nc_closed_room_{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let addr = start_server_with(Config {
+        history_path: Some(path.to_string_lossy().into_owned()),
+        ..Config::default()
+    })
+    .await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    alice.send("!join vault secret");
+    alice
+        .expect(|m| m.content == "-- alice has joined vault, a new room")
+        .await;
+    alice.room = String::from("vault");
+    alice.send("the code is 1234");
+    alice.expect(|m| m.content == "the code is 1234").await;
+    alice.send("!topic plans");
+    alice.expect(|m| m.content.contains("plans")).await;
+    alice.join("rust").await;
+
+    bob.send("!join vault");
+    let joined = bob
+        .expect(|m| m.content.starts_with("-- bob has joined vault"))
+        .await;
+    assert_eq!(joined.content, "-- bob has joined vault, a new room");
+    bob.room = String::from("vault");
+    bob.send("anyone?");
+    // the replay would come before bob's own message
+    assert_eq!(bob.next_chat().await.content, "anyone?");
+    bob.send("!history");
+    let end = bob
+        .expect(|m| m.content.starts_with("-- End of history"))
+        .await;
+    assert_eq!(end.content, "-- End of history, 1 messages.");
+
+    let mut client = connect(addr).await;
+    let request = GetHistoryRequest {
+        room: "vault".to_string(),
+        ..Default::default()
+    };
+    let history = client.get_history(request).await.unwrap().into_inner();
+    let contents: Vec<_> = history
+        .messages
+        .iter()
+        .map(|m| m.content.as_str())
+        .collect();
+    assert_eq!(contents, ["anyone?"]);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn bots_can_post_without_a_connection() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    alice.join("rust").await;
+
+    let mut bot = connect(addr).await;
+    let post = |sender: &str, content: &str| ChatMessage {
+        sender: sender.to_string(),
+        chatroom: "rust".to_string(),
+        content: content.to_string(),
+        ..Default::default()
+    };
+    let ack = bot
+        .send_message(post("ci", "build passed"))
+        .await
+        .unwrap()
+        .into_inner()
+        .response
+        .unwrap();
+    assert_eq!(ack.kind(), Kind::Chat);
+    let message = alice.next_chat().await;
+    assert_eq!(message.sender, "ci");
+    assert_eq!(message.content, "build passed");
+
+    let status = bot.send_message(post("alice", "not me")).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    let status = bot.send_message(post("ci", "!join go")).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let status = bot.send_message(post("ci", "again")).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+}
+
+#[tokio::test]
+async fn bots_cant_post_to_protected_rooms() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    alice.send("!join go secret");
+    alice
+        .expect(|m| m.content == "-- alice has joined go, a new room")
+        .await;
+
+    let mut bot = connect(addr).await;
+    for content in ["hello", "!topic hijacked"] {
+        let message = ChatMessage {
+            sender: "ci".to_string(),
+            chatroom: "Go".to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        };
+        let status = bot.send_message(message).await.unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+}
+
+#[tokio::test]
+async fn bots_get_command_results_as_their_ack() {
+    let addr = start_server().await;
+    let mut bot = connect(addr).await;
+    let message = ChatMessage {
+        sender: "ci".to_string(),
+        chatroom: "rust".to_string(),
+        content: "!help".to_string(),
+        ..Default::default()
+    };
+    let ack = bot
+        .send_message(message)
+        .await
+        .unwrap()
+        .into_inner()
+        .response
+        .unwrap();
+    assert_eq!(ack.target, "ci");
+    assert!(ack.content.starts_with("-- Commands:"));
+    assert!(!ack.id.is_empty());
+}
+
+#[tokio::test]
+async fn compressing_and_plain_clients_talk_to_each_other() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::with_client(connect_with(addr, false).await, "bob").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+
+    let long = "Compress me, I repeat myself. ".repeat(50);
+    alice.send(&long);
+    assert_eq!(bob.next_chat().await.content, long);
+    bob.send(&long.to_uppercase());
+    let reply = alice.expect(|m| m.sender == "bob").await;
+    assert_eq!(reply.content, long.to_uppercase());
+}
+
+#[tokio::test]
+async fn clients_accepting_gzip_get_compressed_responses() {
+    let addr = start_server().await;
+    // plain tonic can't read gzip, which shows the response was sent compressed
+    let mut client = ChatClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+    let mut request = Request::new(NameCheckRequest {
+        name: String::from("alice"),
+    });
+    let accept = MetadataValue::from_static("gzip");
+    request
+        .metadata_mut()
+        .insert("grpc-accept-encoding", accept);
+    let status = client.check_for_name(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unimplemented);
+
+    let request = NameCheckRequest {
+        name: String::from("bob"),
+    };
+    assert!(
+        client
+            .check_for_name(request)
+            .await
+            .unwrap()
+            .into_inner()
+            .available
+    );
+}
+
+#[tokio::test]
+async fn commands_start_with_the_configured_prefix() {
+    let addr = start_server_with(Config {
+        command_prefix: '/',
+        ..Config::default()
+    })
+    .await;
+    let mut client = connect(addr).await;
+    let request = NameCheckRequest {
+        name: "carol".to_string(),
+    };
+    let response = client.check_for_name(request).await.unwrap().into_inner();
+    assert_eq!(response.command_prefix, "/");
+
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    for user in [&mut alice, &mut bob] {
+        user.send("/join rust");
+        user.expect(|m| m.kind() == Kind::Join).await;
+        user.room = "rust".to_string();
+    }
+    alice.send("!help");
+    assert_eq!(bob.next_chat().await.content, "!help");
+    alice.send("/help");
+    let help = alice
+        .expect(|m| m.content.starts_with("-- Commands:"))
+        .await;
+    assert!(help.content.contains("/join <room>"));
+    assert!(!help.content.contains("!join"));
+}
+
+#[tokio::test]
+async fn reflection_lists_the_chat_service() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(ServerReflectionServer::new(Reflection::default()))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let mut client = ServerReflectionClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+    let request = ServerReflectionRequest {
+        host: String::new(),
+        message_request: Some(MessageRequest::ListServices(String::new())),
+    };
+    let mut responses = client
+        .server_reflection_info(tokio_stream::iter([request]))
+        .await
+        .unwrap()
+        .into_inner();
+    let response = responses.message().await.unwrap().unwrap();
+    match response.message_response {
+        Some(MessageResponse::ListServicesResponse(list)) => {
+            assert_eq!(list.service[0].name, "chat.Chat")
+        }
+        other => panic!("unexpected response {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn spoofed_senders_are_ignored() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut mallory = TestUser::connect(addr, "mallory").await;
+    alice.join("rust").await;
+    mallory.join("rust").await;
+
+    mallory.name = "alice".to_string();
+    mallory.send("I'm alice");
+    let message = alice.next_chat().await;
+    assert_eq!(message.sender, "mallory");
+    assert_eq!(message.content, "I'm alice");
+}
+
+#[tokio::test]
+async fn connections_cant_claim_a_name_in_use() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    alice.join("rust").await;
+
+    let mut client = connect(addr).await;
+    let (outbound, outbound_rx) = mpsc::unbounded_channel();
+    let mut inbound = client
+        .live_chat(UnboundedReceiverStream::new(outbound_rx))
+        .await
+        .unwrap()
+        .into_inner();
+    let message = ChatMessage {
+        sender: "alice".to_string(),
+        content: "!join rust".to_string(),
+        ..Default::default()
+    };
+    outbound.send(message).unwrap();
+    let closed = tokio::time::timeout(RECEIVE_TIMEOUT, inbound.message()).await;
+    assert!(matches!(closed, Ok(Ok(None))));
+
+    // alice keeps her name and her connection
+    assert!(!name_available(&mut client, "alice").await);
+    alice.send("still here");
+    assert_eq!(alice.next_chat().await.content, "still here");
+}
+
+#[tokio::test]
+async fn nobody_can_take_the_servers_name() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    alice.join("rust").await;
+    let mut client = connect(addr).await;
+    for name in ["server", "Server", " SERVER "] {
+        assert!(!name_available(&mut client, name).await);
+    }
+
+    let (outbound, outbound_rx) = mpsc::unbounded_channel();
+    let mut inbound = client
+        .live_chat(UnboundedReceiverStream::new(outbound_rx))
+        .await
+        .unwrap()
+        .into_inner();
+    let message = ChatMessage {
+        sender: "Server".to_string(),
+        content: "!join rust".to_string(),
+        ..Default::default()
+    };
+    outbound.send(message).unwrap();
+    let closed = tokio::time::timeout(RECEIVE_TIMEOUT, inbound.message()).await;
+    assert!(matches!(closed, Ok(Ok(None))));
+    alice.send("alone");
+    assert_eq!(alice.next_chat().await.content, "alone");
+}
+
+#[tokio::test]
+async fn typing_events_sent_too_fast_are_dropped() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+
+    for _ in 0..5 {
+        bob.send("!typing");
+    }
+    bob.send("typed");
+    let typing = std::cell::Cell::new(0);
+    alice
+        .expect(|m| {
+            if m.kind() == Kind::Typing {
+                typing.set(typing.get() + 1);
+            }
+            m.content == "typed"
+        })
+        .await;
+    assert_eq!(typing.get(), 1);
+}
+
+#[tokio::test]
+async fn flooding_users_are_muted() {
+    let addr = start_server_with(Config {
+        flood_messages: 3,
+        ..Config::default()
+    })
+    .await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+
+    for n in 1..=4 {
+        bob.send(&format!("spam {}", n));
+    }
+    let notice = bob
+        .expect(|m| m.sender == "server" && m.content.starts_with("You're sending"))
+        .await;
+    assert_eq!(notice.target, "bob");
+    bob.send("still here?");
+    bob.expect(|m| m.content.starts_with("You're sending"))
+        .await;
+
+    // the join counted as well, so only two of the chat messages got through
+    assert_eq!(alice.next_chat().await.content, "spam 1");
+    assert_eq!(alice.next_chat().await.content, "spam 2");
+    alice.send("done");
+    assert_eq!(alice.next_chat().await.content, "done");
+}
+
+#[tokio::test]
+async fn announcements_reach_every_room() {
+    let addr = start_server_with(Config {
+        admins: vec![String::from("alice")],
+        ..Config::default()
+    })
+    .await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    let mut carol = TestUser::connect(addr, "carol").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+    carol.join("go").await;
+
+    bob.send("!announce free pizza");
+    let error = bob.expect(|m| m.style() == Style::Error).await;
+    assert_eq!(
+        error.content,
+        "Permission denied, !announce is for admins only."
+    );
+
+    alice.send("!announce restarting at noon");
+    for user in [&mut alice, &mut bob, &mut carol] {
+        let announcement = user.expect(|m| m.kind() == Kind::Announcement).await;
+        assert_eq!(announcement.sender, "server");
+        assert_eq!(
+            announcement.content,
+            "-- Announcement from alice: restarting at noon"
+        );
+    }
+    // alice is still in her room afterwards
+    alice.send("back to chat");
+    assert_eq!(bob.next_chat().await.content, "back to chat");
+}
+
+#[tokio::test]
+async fn banned_users_are_disconnected_and_kept_out() {
+    let addr = start_server_with(Config {
+        admins: vec![String::from("root")],
+        ..Config::default()
+    })
+    .await;
+    let mut root = TestUser::connect(addr, "root").await;
+    let mut mallory = TestUser::connect(addr, "mallory").await;
+    root.join("rust").await;
+    mallory.join("rust").await;
+
+    root.send("!ban mallory");
+    let notice = mallory.expect(|m| m.style() == Style::Error).await;
+    assert_eq!(notice.content, "You were banned by root.");
+    let closed = tokio::time::timeout(RECEIVE_TIMEOUT, mallory.inbound.message()).await;
+    assert!(matches!(closed, Ok(Ok(None))));
+    root.expect(|m| m.content == "-- mallory is banned and was disconnected")
+        .await;
+    let mut client = connect(addr).await;
+    assert!(!name_available(&mut client, "mallory").await);
+
+    root.send("!unban mallory");
+    root.expect(|m| m.content == "-- mallory is no longer banned")
+        .await;
+    assert!(name_available(&mut client, "mallory").await);
+}
+
+#[tokio::test]
+async fn addresses_connecting_too_often_are_refused() {
+    let addr = start_server_with(Config {
+        connection_limit: 3,
+        ..Config::default()
+    })
+    .await;
+    let mut client = connect(addr).await;
+    assert!(name_available(&mut client, "alice").await);
+    // a name check and a chat connection
+    TestUser::connect(addr, "bob").await;
+    let request = NameCheckRequest {
+        name: String::from("carol"),
+    };
+    let status = client.check_for_name(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+}
+
+#[tokio::test]
+async fn banned_addresses_are_refused() {
+    let addr = start_server_with(Config {
+        banned_ips: vec!["127.0.0.1".parse().unwrap()],
+        ..Config::default()
+    })
+    .await;
+    let mut client = connect(addr).await;
+    let request = NameCheckRequest {
+        name: String::from("alice"),
+    };
+    let status = client.check_for_name(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+    let status = client
+        .live_chat(tokio_stream::empty::<ChatMessage>())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+}
+
+#[tokio::test]
+async fn replies_quote_earlier_messages() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+
+    // ids are the server's to give
+    let hello = ChatMessage {
+        sender: String::from("bob"),
+        chatroom: String::from("rust"),
+        content: String::from("hello"),
+        id: String::from("spoofed"),
+        ..Default::default()
+    };
+    bob.outbound.send(hello).unwrap();
+    let hello = alice.next_chat().await;
+    assert_ne!(hello.id, "spoofed");
+    assert!(!hello.id.is_empty());
+
+    alice.send(&format!("!reply {} hi bob", hello.id));
+    let reply = bob.expect(|m| m.reply_to.is_some()).await;
+    assert_eq!(reply.sender, "alice");
+    assert_eq!(reply.content, "hi bob");
+    let quote = reply.reply_to.unwrap();
+    assert_eq!(quote.id, hello.id);
+    assert_eq!(quote.sender, "bob");
+    assert_eq!(quote.content, "hello");
+}
+
+#[tokio::test]
+async fn every_message_has_an_id() {
+    let addr = start_server().await;
+    let mut alice = TestUser::connect(addr, "alice").await;
+    let mut bob = TestUser::connect(addr, "bob").await;
+    alice.join("rust").await;
+    bob.join("rust").await;
+    let joined = alice.expect(|m| m.kind() == Kind::Join).await;
+    assert!(!joined.id.is_empty());
+
+    // both ends of a DM see the same id
+    alice.send("!dm bob psst");
+    let sent = alice.expect(|m| m.kind() == Kind::DirectMessage).await;
+    let received = bob.next_chat().await;
+    assert_eq!(received.content, "psst");
+    assert_eq!(received.id, sent.id);
+
+    bob.send("!user");
+    let users = bob.expect(|m| m.sender == "server").await;
+    assert!(!users.id.is_empty());
+}
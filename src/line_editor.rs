@@ -0,0 +1,688 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+use tokio::sync::mpsc;
+
+const PROMPT: &str = "> ";
+/// Number of lines kept in the history file.
+const MAX_HISTORY: usize = 1000;
+/// How often typing is re-announced while the user keeps typing.
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+/// How often the terminal size is checked for a resize.
+const RESIZE_POLL: Duration = Duration::from_millis(200);
+
+/// What the user did at the prompt.
+pub enum Input {
+    Line(String),
+    /// The user started (`true`) or stopped (`false`) typing without sending anything.
+    Typing(bool),
+    /// Page Up or Page Down, to scroll through the messages shown so far.
+    PageUp,
+    PageDown,
+    /// The terminal window gained (`true`) or lost focus, reported by terminals that support
+    /// focus events.
+    Focus(bool),
+}
+
+/// Suggests completions for the word under the cursor.
+pub trait Completer: Send {
+    /// Where the word being completed starts in `line`, counted in chars, and the candidates
+    /// that could replace it. Only the part of `line` before the cursor is passed.
+    fn complete(&self, line: &str) -> (usize, Vec<String>);
+
+    /// Whether `line` is a command rather than chat.
+    fn is_command(&self, line: &str) -> bool {
+        line.starts_with('!')
+    }
+}
+
+/// Line editing on a terminal in raw mode: left/right and the usual emacs keys move the cursor,
+/// up/down walk the history, which is persisted to `~/.novachat_history`, and tab completes
+/// with the given [`Completer`].
+///
+/// The screen is split into a message pane and, below it, a status line and the input line. The
+/// pane is a scroll region over all but the last two rows, so messages printed into it scroll
+/// without touching what the user is typing, and since it starts at the top row the lines
+/// leaving it go to the terminal's own scrollback. Everything written to stdout has to go
+/// through [`LineEditor::output`], which puts the cursor back where the pane left off. Resizing
+/// the terminal lays the screen out again. When stdin isn't a terminal lines are read as they
+/// come and printed as they are.
+pub struct LineEditor {
+    state: Arc<Mutex<State>>,
+    original: Option<libc::termios>,
+}
+
+struct State {
+    raw: bool,
+    /// Terminal size the screen is laid out for, rows and columns.
+    size: (usize, usize),
+    /// Whether the cursor is in the message pane rather than on the input line.
+    in_pane: bool,
+    buffer: Vec<char>,
+    cursor: usize,
+    /// First char of `buffer` that's on screen, when it doesn't fit the terminal width.
+    offset: usize,
+    status: String,
+    status_shown: bool,
+    history: History,
+    typing_since: Option<Instant>,
+    completer: Box<dyn Completer>,
+    /// Whether the previous key was a tab that couldn't complete any further, a second one
+    /// lists the candidates.
+    completion_pending: bool,
+}
+
+struct History {
+    lines: Vec<String>,
+    /// Position while walking the history, `lines.len()` is the line being edited.
+    position: usize,
+    draft: String,
+    path: Option<PathBuf>,
+}
+
+impl LineEditor {
+    pub fn start(
+        completer: impl Completer + 'static,
+    ) -> (LineEditor, mpsc::UnboundedReceiver<Input>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let original = enable_raw_mode();
+        let state = Arc::new(Mutex::new(State {
+            raw: original.is_some(),
+            size: terminal_size(),
+            in_pane: false,
+            buffer: Vec::new(),
+            cursor: 0,
+            offset: 0,
+            status: String::new(),
+            status_shown: false,
+            history: History::load(),
+            typing_since: None,
+            completer: Box::new(completer),
+            completion_pending: false,
+        }));
+        let editor = LineEditor {
+            state: state.clone(),
+            original,
+        };
+        if editor.original.is_some() {
+            // ask the terminal to report focus changes
+            print!("\x1B[?1004h");
+            editor.state().layout();
+            let watched = state.clone();
+            thread::spawn(move || watch_size(watched));
+            thread::spawn(move || read_keys(state, tx));
+        } else {
+            thread::spawn(move || {
+                for line in io::stdin().lock().lines().map_while(Result::ok) {
+                    if tx.send(Input::Line(line)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        (editor, rx)
+    }
+
+    /// Takes the input line off the screen until the returned guard is dropped.
+    pub fn output(&self) -> Output<'_> {
+        let mut state = self.state();
+        state.hide();
+        Output { state }
+    }
+
+    pub fn println(&self, line: impl std::fmt::Display) {
+        let _output = self.output();
+        println!("{}", line);
+    }
+
+    /// Shows `status` in the status line, e.g. who is typing, nothing if it's empty.
+    pub fn set_status(&self, status: String) {
+        let mut state = self.state();
+        state.hide();
+        state.status = status;
+        state.redraw();
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for LineEditor {
+    fn drop(&mut self) {
+        if let Some(original) = self.original {
+            let mut state = self.state();
+            // give the whole screen back, with the status and input lines cleared
+            print!("\x1B[?1004l\x1B[r\x1B[{};1H\x1B[J", state.size.0 - 1);
+            let _ = io::stdout().flush();
+            state.raw = false;
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original) };
+        }
+    }
+}
+
+/// Holds the screen for printing, the input line comes back when this is dropped.
+pub struct Output<'a> {
+    state: MutexGuard<'a, State>,
+}
+
+impl Drop for Output<'_> {
+    fn drop(&mut self) {
+        self.state.redraw();
+    }
+}
+
+impl State {
+    /// Moves the cursor back into the message pane, where it was when the input line was drawn.
+    fn hide(&mut self) {
+        if self.raw && !self.in_pane {
+            print!("\x1B8");
+            self.in_pane = true;
+        } else if !self.raw && self.status_shown {
+            print!("\r\x1B[2K");
+            self.status_shown = false;
+        }
+        let _ = io::stdout().flush();
+    }
+
+    fn redraw(&mut self) {
+        if !self.raw {
+            if !self.status.is_empty() {
+                let status = self.status.truecolor(153, 140, 139);
+                print!("\r\x1B[2K{}", status);
+                self.status_shown = true;
+                let _ = io::stdout().flush();
+            }
+            return;
+        }
+        let (rows, columns) = self.size;
+        if self.in_pane {
+            print!("\x1B7");
+            self.in_pane = false;
+        }
+        let rule = if self.status.is_empty() {
+            "─".repeat(columns)
+        } else {
+            let fill = columns.saturating_sub(self.status.chars().count() + 4);
+            format!("── {} {}", self.status, "─".repeat(fill))
+        };
+        let rule: String = rule.chars().take(columns).collect();
+        let mut line = format!(
+            "\x1B[{};1H\x1B[2K{}\x1B[{};1H\x1B[2K{}",
+            rows - 1,
+            rule.truecolor(153, 140, 139),
+            rows,
+            PROMPT
+        );
+        // scroll sideways so the cursor stays visible on a line longer than the terminal
+        let visible = columns.saturating_sub(PROMPT.len() + 1).max(1);
+        if self.cursor < self.offset {
+            self.offset = self.cursor;
+        } else if self.cursor > self.offset + visible {
+            self.offset = self.cursor - visible;
+        }
+        line.extend(self.buffer.iter().skip(self.offset).take(visible));
+        line.push_str(&format!(
+            "\r\x1B[{}C",
+            PROMPT.len() + self.cursor - self.offset
+        ));
+        print!("{}", line);
+        let _ = io::stdout().flush();
+    }
+
+    /// Makes the message pane a scroll region over all but the last two rows, which setting
+    /// it moves the cursor to the top of.
+    fn layout(&mut self) {
+        print!("\x1B[1;{}r", self.size.0 - 2);
+        self.in_pane = true;
+        self.redraw();
+    }
+
+    /// Lays the screen out again for a terminal of `size`, going on with the messages at the
+    /// bottom of the pane.
+    fn resize(&mut self, size: (usize, usize)) {
+        self.hide();
+        let old_rows = self.size.0;
+        // the old status and input lines would be left in the middle of a taller pane
+        print!("\x1B[r");
+        for row in [old_rows - 1, old_rows]
+            .into_iter()
+            .filter(|row| *row <= size.0)
+        {
+            print!("\x1B[{};1H\x1B[2K", row);
+        }
+        self.size = size;
+        print!("\x1B[1;{}r\x1B[{};1H", size.0 - 2, size.0 - 2);
+        self.redraw();
+    }
+
+    fn insert(&mut self, c: char) {
+        self.buffer.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    fn take_line(&mut self) -> String {
+        self.cursor = 0;
+        self.offset = 0;
+        self.buffer.drain(..).collect()
+    }
+
+    fn replace_line(&mut self, line: &str) {
+        self.buffer = line.chars().collect();
+        self.cursor = self.buffer.len();
+    }
+
+    fn delete_word_before_cursor(&mut self) {
+        let mut start = self.cursor;
+        while start > 0 && self.buffer[start - 1] == ' ' {
+            start -= 1;
+        }
+        while start > 0 && self.buffer[start - 1] != ' ' {
+            start -= 1;
+        }
+        self.buffer.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    /// Completes the word before the cursor as far as all candidates agree, adding a space
+    /// when there's only one. Ambiguous completions are listed on the second tab in a row.
+    fn complete(&mut self) {
+        let before_cursor: String = self.buffer[..self.cursor].iter().collect();
+        let (start, candidates) = self.completer.complete(&before_cursor);
+        let word: String = self.buffer[start..self.cursor].iter().collect();
+        let mut candidates: Vec<String> = candidates
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(&word))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        let completion = match candidates.as_slice() {
+            [] => return,
+            [only] => format!("{} ", only),
+            [first, rest @ ..] => rest.iter().fold(first.clone(), |prefix, candidate| {
+                prefix
+                    .chars()
+                    .zip(candidate.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a)
+                    .collect()
+            }),
+        };
+        let added: Vec<char> = completion.chars().skip(word.chars().count()).collect();
+        if !added.is_empty() {
+            for c in added {
+                self.insert(c);
+            }
+            self.completion_pending = false;
+        } else if self.completion_pending {
+            self.hide();
+            println!("{}", candidates.join("  "));
+            self.completion_pending = false;
+        } else {
+            self.completion_pending = true;
+        }
+    }
+
+    /// The typing event caused by the last edit, if any.
+    fn typing_change(&mut self) -> Option<bool> {
+        if self.buffer.is_empty() {
+            return self.typing_since.take().map(|_| false);
+        }
+        match self.typing_since {
+            Some(since) if since.elapsed() < TYPING_REFRESH => None,
+            _ => {
+                self.typing_since = Some(Instant::now());
+                Some(true)
+            }
+        }
+    }
+}
+
+impl History {
+    fn load() -> History {
+        let path = env::var_os("HOME").map(|home| PathBuf::from(home).join(".novachat_history"));
+        let mut lines: Vec<String> = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|history| history.lines().map(String::from).collect())
+            .unwrap_or_default();
+        if lines.len() > MAX_HISTORY {
+            lines.drain(..lines.len() - MAX_HISTORY);
+            if let Some(path) = &path {
+                let _ = fs::write(path, lines.join("\n") + "\n");
+            }
+        }
+        History {
+            position: lines.len(),
+            lines,
+            draft: String::new(),
+            path,
+        }
+    }
+
+    fn add(&mut self, line: &str) {
+        if !line.trim().is_empty() && self.lines.last().map(String::as_str) != Some(line) {
+            self.lines.push(line.to_string());
+            if let Some(path) = &self.path {
+                if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+                    let _ = writeln!(file, "{}", line);
+                }
+            }
+        }
+        self.position = self.lines.len();
+    }
+
+    fn previous(&mut self, current: String) -> Option<&str> {
+        if self.position == 0 {
+            return None;
+        }
+        if self.position == self.lines.len() {
+            self.draft = current;
+        }
+        self.position -= 1;
+        Some(&self.lines[self.position])
+    }
+
+    fn next(&mut self) -> Option<&str> {
+        if self.position >= self.lines.len() {
+            return None;
+        }
+        self.position += 1;
+        Some(self.lines.get(self.position).unwrap_or(&self.draft))
+    }
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    KillToStart,
+    KillToEnd,
+    KillWord,
+    Tab,
+    PageUp,
+    PageDown,
+    Focus(bool),
+    /// Ctrl-D, ends the input on an empty line like a shell does.
+    EndOfInput,
+    /// Ctrl-C or stdin going away.
+    Interrupt,
+    Ignored,
+}
+
+/// Lays the screen out again whenever the terminal size changes, until the editor is dropped.
+fn watch_size(state: Arc<Mutex<State>>) {
+    loop {
+        thread::sleep(RESIZE_POLL);
+        let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+        if !state.raw {
+            return;
+        }
+        let size = terminal_size();
+        if size != state.size {
+            state.resize(size);
+        }
+    }
+}
+
+fn read_keys(state: Arc<Mutex<State>>, tx: mpsc::UnboundedSender<Input>) {
+    let mut stdin = io::stdin().lock();
+    loop {
+        let key = read_key(&mut stdin);
+        let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut inputs = Vec::new();
+        if !matches!(key, Key::Tab) {
+            state.completion_pending = false;
+        }
+        let edited = match key {
+            // dropping the sender tells the client there's no more input
+            Key::Interrupt => return,
+            Key::EndOfInput if state.buffer.is_empty() => return,
+            Key::Enter => {
+                let line = state.take_line();
+                state.history.add(&line);
+                if state.typing_since.take().is_some() && state.completer.is_command(&line) {
+                    // chat messages supersede the typing indicator, commands don't
+                    inputs.push(Input::Typing(false));
+                }
+                inputs.push(Input::Line(line));
+                false
+            }
+            Key::Char(c) => {
+                state.insert(c);
+                true
+            }
+            Key::Backspace if state.cursor > 0 => {
+                state.cursor -= 1;
+                let cursor = state.cursor;
+                state.buffer.remove(cursor);
+                true
+            }
+            Key::Delete if state.cursor < state.buffer.len() => {
+                let cursor = state.cursor;
+                state.buffer.remove(cursor);
+                true
+            }
+            Key::Left => {
+                state.cursor = state.cursor.saturating_sub(1);
+                false
+            }
+            Key::Right => {
+                state.cursor = (state.cursor + 1).min(state.buffer.len());
+                false
+            }
+            Key::Home => {
+                state.cursor = 0;
+                false
+            }
+            Key::End => {
+                state.cursor = state.buffer.len();
+                false
+            }
+            Key::Up => {
+                let current = state.buffer.iter().collect();
+                if let Some(line) = state.history.previous(current).map(String::from) {
+                    state.replace_line(&line);
+                }
+                true
+            }
+            Key::Down => {
+                if let Some(line) = state.history.next().map(String::from) {
+                    state.replace_line(&line);
+                }
+                true
+            }
+            Key::KillToStart => {
+                let cursor = state.cursor;
+                state.buffer.drain(..cursor);
+                state.cursor = 0;
+                true
+            }
+            Key::KillToEnd => {
+                let cursor = state.cursor;
+                state.buffer.truncate(cursor);
+                true
+            }
+            Key::KillWord => {
+                state.delete_word_before_cursor();
+                true
+            }
+            Key::Tab => {
+                state.complete();
+                true
+            }
+            Key::PageUp => {
+                inputs.push(Input::PageUp);
+                false
+            }
+            Key::PageDown => {
+                inputs.push(Input::PageDown);
+                false
+            }
+            Key::Focus(focused) => {
+                inputs.push(Input::Focus(focused));
+                false
+            }
+            Key::Backspace | Key::Delete | Key::EndOfInput | Key::Ignored => false,
+        };
+        if edited {
+            if let Some(typing) = state.typing_change() {
+                inputs.push(Input::Typing(typing));
+            }
+        }
+        state.redraw();
+        drop(state);
+        for input in inputs {
+            if tx.send(input).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn read_key(stdin: &mut impl Read) -> Key {
+    let byte = match read_byte(stdin) {
+        Some(byte) => byte,
+        None => return Key::Interrupt,
+    };
+    match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        // signals are off in raw mode, ctrl-c ends the input instead of killing the client so
+        // the terminal gets restored
+        0x03 => Key::Interrupt,
+        0x04 => Key::EndOfInput,
+        0x01 => Key::Home,
+        0x05 => Key::End,
+        0x02 => Key::Left,
+        0x06 => Key::Right,
+        0x10 => Key::Up,
+        0x0e => Key::Down,
+        0x15 => Key::KillToStart,
+        0x0b => Key::KillToEnd,
+        0x17 => Key::KillWord,
+        b'\t' => Key::Tab,
+        0x1b => read_escape_sequence(stdin),
+        byte if byte < 0x20 => Key::Ignored,
+        byte => read_char(stdin, byte)
+            .map(Key::Char)
+            .unwrap_or(Key::Ignored),
+    }
+}
+
+fn read_escape_sequence(stdin: &mut impl Read) -> Key {
+    match read_byte(stdin) {
+        Some(b'[') => {
+            let mut parameters = String::new();
+            loop {
+                match read_byte(stdin) {
+                    Some(byte @ 0x40..=0x7e) => {
+                        return match (parameters.as_str(), byte) {
+                            ("", b'A') => Key::Up,
+                            ("", b'B') => Key::Down,
+                            ("", b'C') => Key::Right,
+                            ("", b'D') => Key::Left,
+                            ("", b'H') | ("1" | "7", b'~') => Key::Home,
+                            ("", b'F') | ("4" | "8", b'~') => Key::End,
+                            ("3", b'~') => Key::Delete,
+                            ("5", b'~') => Key::PageUp,
+                            ("6", b'~') => Key::PageDown,
+                            ("", b'I') => Key::Focus(true),
+                            ("", b'O') => Key::Focus(false),
+                            _ => Key::Ignored,
+                        };
+                    }
+                    Some(byte) => parameters.push(byte as char),
+                    None => return Key::Interrupt,
+                }
+            }
+        }
+        Some(b'O') => match read_byte(stdin) {
+            Some(b'H') => Key::Home,
+            Some(b'F') => Key::End,
+            _ => Key::Ignored,
+        },
+        _ => Key::Ignored,
+    }
+}
+
+fn read_char(stdin: &mut impl Read, first: u8) -> Option<char> {
+    let len = match first {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    };
+    let mut bytes = vec![first];
+    for _ in 1..len {
+        bytes.push(read_byte(stdin)?);
+    }
+    std::str::from_utf8(&bytes).ok()?.chars().next()
+}
+
+fn read_byte(stdin: &mut impl Read) -> Option<u8> {
+    let mut byte = [0];
+    match stdin.read(&mut byte) {
+        Ok(1) => Some(byte[0]),
+        _ => None,
+    }
+}
+
+/// Switches the terminal to raw mode, returning the settings to restore, `None` if stdin or
+/// stdout isn't a terminal.
+fn enable_raw_mode() -> Option<libc::termios> {
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) != 1 || libc::isatty(libc::STDOUT_FILENO) != 1 {
+            return None;
+        }
+        let mut original = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+            return None;
+        }
+        let mut raw = original;
+        // output processing stays on, so printed newlines still return the carriage
+        raw.c_iflag &= !(libc::ICRNL | libc::IXON);
+        raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::ISIG | libc::IEXTEN);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+            return None;
+        }
+        Some(original)
+    }
+}
+
+pub fn terminal_width() -> usize {
+    terminal_size().1
+}
+
+/// Rows and columns of the terminal, 24 by 80 if stdout isn't one. At least 4 rows, the fewest
+/// the message pane, status line and input line fit in.
+fn terminal_size() -> (usize, usize) {
+    unsafe {
+        let mut size = std::mem::zeroed::<libc::winsize>();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 {
+            ((size.ws_row as usize).max(4), size.ws_col as usize)
+        } else {
+            (24, 80)
+        }
+    }
+}
+
+/// Rows of the message pane, all of the terminal's but the status and input lines.
+pub fn pane_height() -> usize {
+    terminal_size().0 - 2
+}
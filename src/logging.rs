@@ -0,0 +1,213 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+/// Installs a subscriber printing events to stderr, filtered by `RUST_LOG`.
+///
+/// `RUST_LOG` takes comma separated directives, either a bare level (`debug`) that applies to
+/// everything or `target=level` for a module prefix (`nc_server::router=trace`). Defaults to `info`.
+pub fn init() {
+    let logger = StderrLogger::new(&env::var("RUST_LOG").unwrap_or_default());
+    let _ = tracing::subscriber::set_global_default(logger);
+}
+
+struct StderrLogger {
+    default_level: LevelFilter,
+    target_levels: Vec<(String, LevelFilter)>,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+}
+
+struct SpanData {
+    name: &'static str,
+    fields: String,
+    refs: usize,
+}
+
+thread_local! {
+    /// Spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+impl StderrLogger {
+    fn new(directives: &str) -> StderrLogger {
+        let mut default_level = LevelFilter::INFO;
+        let mut target_levels = Vec::new();
+        for directive in directives
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+        {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    if let Ok(level) = level.parse() {
+                        target_levels.push((target.to_string(), level));
+                    }
+                }
+                None => {
+                    if let Ok(level) = directive.parse() {
+                        default_level = level;
+                    }
+                }
+            }
+        }
+        // the longest matching target wins, so check the most specific ones first
+        target_levels.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        StderrLogger {
+            default_level,
+            target_levels,
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.target_levels
+            .iter()
+            .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default_level)
+    }
+}
+
+impl Subscriber for StderrLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.target_levels
+            .iter()
+            .map(|(_, level)| *level)
+            .chain(Some(self.default_level))
+            .max()
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut fields = FieldWriter::default();
+        span.record(&mut fields);
+        let data = SpanData {
+            name: span.metadata().name(),
+            fields: fields.fields,
+            refs: 1,
+        };
+        self.spans.lock().unwrap().insert(id, data);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            let mut fields = FieldWriter {
+                fields: std::mem::take(&mut data.fields),
+                ..Default::default()
+            };
+            values.record(&mut fields);
+            data.fields = fields.fields;
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = FieldWriter::default();
+        event.record(&mut fields);
+
+        let mut context = String::new();
+        {
+            let spans = self.spans.lock().unwrap();
+            ENTERED.with(|entered| {
+                for id in entered.borrow().iter() {
+                    if let Some(span) = spans.get(id) {
+                        let _ = write!(context, "{}{{{}}}:", span.name, span.fields);
+                    }
+                }
+            });
+        }
+
+        eprintln!(
+            "{} {:>5} {}{}: {}{}",
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
+            event.metadata().level(),
+            context,
+            event.metadata().target(),
+            fields.message,
+            if fields.fields.is_empty() {
+                String::new()
+            } else {
+                format!(" {}", fields.fields)
+            }
+        );
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let closed = match spans.get_mut(&span.into_u64()) {
+            Some(data) => {
+                data.refs -= 1;
+                data.refs == 0
+            }
+            None => false,
+        };
+        if closed {
+            spans.remove(&span.into_u64());
+        }
+        closed
+    }
+}
+
+/// Collects the `message` of an event and renders every other field as `name=value`.
+#[derive(Default)]
+struct FieldWriter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldWriter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            if !self.fields.is_empty() {
+                self.fields.push(' ');
+            }
+            let _ = write!(self.fields, "{}={:?}", field.name(), value);
+        }
+    }
+}
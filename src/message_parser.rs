@@ -1,16 +1,119 @@
 #![allow(dead_code)]
 
-use colored::*;
+use chrono::{TimeZone, Utc};
 use futures_util::{stream, StreamExt};
-use reqwest::{Error, Response};
+use openssl::{hash::MessageDigest, memcmp, pkcs5::pbkdf2_hmac, rand::rand_bytes};
+use rand::Rng;
+use reqwest::{header::HeaderMap, Error, Response, StatusCode};
 use serde::Deserialize;
 use std::{
-    collections::HashMap,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{HashMap, HashSet},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::broadcast;
+use tracing::{debug, error, info, instrument, warn};
 
-use super::ChatMessage;
+use super::calc;
+use super::chat::{Kind, Quote, Reaction, Style};
+use super::command::{self, Command, Dice, USAGES};
+use super::state::ServerState;
+use super::{ChatMessage, MAX_QUEUED_DMS, MAX_ROOM_NAME_CHARS, MAX_STATUS_CHARS, MAX_TOPIC_CHARS};
+
+/// Leading marker on `content` flagging an action message (`!me waves`), modelled after IRC's CTCP ACTION.
+/// The `sender` stays intact, so clients strip the marker and render `* sender content`.
+pub const ACTION_MARKER: &str = "\u{1}ACTION ";
+
+/// Reserved `content` of typing events. `!typing` is relayed to the room as [`TYPING_MARKER`]
+/// and `!typing stop` as [`TYPING_STOP_MARKER`], both keeping the original `sender`.
+/// Clients show "alice is typing…" until it's stopped, superseded by a real message from
+/// the same sender, or goes stale after a few seconds without a refresh.
+pub const TYPING_MARKER: &str = "\u{1}TYPING";
+pub const TYPING_STOP_MARKER: &str = "\u{1}TYPING_STOP";
+
+/// Presence of users that didn't set a status, and of those who went idle without one.
+pub const ONLINE: &str = "online";
+pub const AWAY: &str = "away";
+
+/// Most dice `!roll` rolls at once, and the most sides they can have.
+const MAX_DICE: u32 = 100;
+const MAX_DICE_SIDES: u32 = 1000;
+
+/// The classic answers of a magic 8-ball, `!8ball` picks one at random.
+const EIGHT_BALL_ANSWERS: &[&str] = &[
+    "It is certain.",
+    "It is decidedly so.",
+    "Without a doubt.",
+    "Yes definitely.",
+    "You may rely on it.",
+    "As I see it, yes.",
+    "Most likely.",
+    "Outlook good.",
+    "Yes.",
+    "Signs point to yes.",
+    "Reply hazy, try again.",
+    "Ask again later.",
+    "Better not tell you now.",
+    "Cannot predict now.",
+    "Concentrate and ask again.",
+    "Don't count on it.",
+    "My reply is no.",
+    "My sources say no.",
+    "Outlook not so good.",
+    "Very doubtful.",
+];
+
+/// Number of messages `!history` shows without an explicit count, and the most it shows at all.
+const DEFAULT_HISTORY_COUNT: usize = 20;
+const MAX_HISTORY_COUNT: usize = 100;
+
+/// Number of stories `!news` shows without an explicit count, and the most it fetches at all
+/// so a single command can't hammer the Hacker News API.
+const DEFAULT_NEWS_COUNT: usize = 10;
+const MAX_NEWS_COUNT: usize = 30;
+
+/// Attempts made for a request to an external API, the wait before retrying doubles after
+/// each failed one. Together with the timeout per attempt this keeps a command from taking
+/// more than about ten seconds.
+const API_ATTEMPTS: u32 = 3;
+const API_RETRY_DELAY: Duration = Duration::from_millis(250);
+const API_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long Binance is left alone after rate limiting us without saying for how long.
+const DEFAULT_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Feeds `!news` can show, by name and Firebase endpoint. Top stories are the default.
+const HN_FEEDS: &[(&str, &str)] = &[
+    ("top", "topstories"),
+    ("new", "newstories"),
+    ("ask", "askstories"),
+    ("show", "showstories"),
+    ("jobs", "jobstories"),
+];
+
+/// Number of matches `!search` shows, the most recent ones win.
+const MAX_SEARCH_RESULTS: usize = 10;
+
+/// Characters of the message a reply quotes, longer ones are cut off.
+const MAX_QUOTE_CHARS: usize = 80;
+
+/// Characters message ids are made of.
+const MESSAGE_ID_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+const MESSAGE_ID_LENGTH: usize = 6;
+
+/// Different emojis a message can be reacted to with, so reactions don't drown out the message.
+const MAX_REACTIONS: usize = 10;
+/// Characters an emoji passed to `!react` may have, enough for flags, skin tones and the emojis
+/// joined from several.
+const MAX_EMOJI_CHARS: usize = 8;
+
+/// Commands `!stats` lists the use of, the most used ones.
+const MOST_USED_COMMANDS: usize = 5;
+
+/// Names on a page of `!user`, so a large room doesn't list everyone in one enormous line.
+const USERS_PER_PAGE: usize = 30;
+
+// A `server` message targeted at a user with an empty `chatroom` means that user is in the lobby,
+// both the connection's output stream and the client reset their room when they see one.
 
 #[derive(Deserialize)]
 struct Currency {
@@ -18,239 +121,3401 @@ struct Currency {
     price: String,
 }
 
+/// Error body Binance sends instead of a price, e.g. `{"code":-1121,"msg":"Invalid symbol."}`.
+#[derive(Deserialize)]
+struct BinanceError {
+    code: i64,
+    msg: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BinanceReply {
+    Price(Currency),
+    Error(BinanceError),
+}
+
+/// Binance's error code for a symbol it doesn't trade.
+const INVALID_SYMBOL: i64 = -1121;
+
+#[derive(Debug, PartialEq)]
+enum Conversion {
+    Price(f64),
+    UnknownSymbol,
+    RateLimited(Duration),
+    Invalid,
+}
+
+#[derive(Deserialize)]
+struct PriceChange {
+    #[serde(rename = "priceChangePercent")]
+    price_change_percent: String,
+}
+
+/// An item of the Hacker News API. Deleted and dead items keep their id but lose most fields.
 #[derive(Deserialize, Clone)]
 struct HNStory {
     by: Option<String>,
-    score: i16,
+    #[serde(default)]
+    score: u32,
     time: i64,
-    title: String,
+    title: Option<String>,
     url: Option<String>,
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default)]
+    dead: bool,
+}
+
+/// Search results of Open-Meteo's geocoding API, `results` is missing when nothing matched.
+#[derive(Deserialize)]
+struct GeocodingResults {
+    results: Option<Vec<Place>>,
+}
+
+#[derive(Deserialize)]
+struct Place {
+    name: String,
+    country: Option<String>,
+    latitude: f64,
+    longitude: f64,
+}
+
+#[derive(Deserialize)]
+struct Forecast {
+    current_weather: CurrentWeather,
+}
+
+#[derive(Deserialize)]
+struct CurrentWeather {
+    temperature: f64,
+    windspeed: f64,
+    weathercode: u8,
+}
+
+/// A random joke of the Official Joke API.
+#[derive(Deserialize)]
+struct Joke {
+    setup: String,
+    punchline: String,
+}
+
+/// Summary of a Wikipedia article from the REST API. `kind` is `disambiguation` for pages that
+/// only list articles of the same name.
+#[derive(Deserialize)]
+struct Summary {
+    #[serde(rename = "type")]
+    kind: String,
+    title: String,
+    extract: String,
+    content_urls: Option<ContentUrls>,
+}
+
+#[derive(Deserialize)]
+struct ContentUrls {
+    desktop: PageUrl,
+}
+
+#[derive(Deserialize)]
+struct PageUrl {
+    page: String,
+}
+
+/// A crate as described by the crates.io API, which wraps it in a `crate` field.
+#[derive(Deserialize)]
+struct CrateResponse {
+    #[serde(rename = "crate")]
+    krate: Crate,
+}
+
+#[derive(Deserialize)]
+struct Crate {
+    name: String,
+    max_version: String,
+    description: Option<String>,
+    downloads: u64,
+}
+
+/// Salted PBKDF2 hash of a room password, the plaintext is never stored. Hashing takes long
+/// enough to stall the runtime, it's done with `spawn_blocking` and without holding locks.
+#[derive(Clone, PartialEq)]
+pub struct RoomPassword {
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl RoomPassword {
+    const ITERATIONS: usize = 100_000;
+
+    fn new(password: &str) -> Option<RoomPassword> {
+        let mut salt = [0; 16];
+        rand_bytes(&mut salt).ok()?;
+        let hash = RoomPassword::derive(password, &salt)?;
+        Some(RoomPassword { salt, hash })
+    }
+
+    pub fn matches(&self, password: &str) -> bool {
+        RoomPassword::derive(password, &self.salt)
+            .map(|hash| memcmp::eq(&hash, &self.hash))
+            .unwrap_or(false)
+    }
+
+    fn derive(password: &str, salt: &[u8]) -> Option<[u8; 32]> {
+        let mut hash = [0; 32];
+        pbkdf2_hmac(
+            password.as_bytes(),
+            salt,
+            RoomPassword::ITERATIONS,
+            MessageDigest::sha256(),
+            &mut hash,
+        )
+        .ok()?;
+        Some(hash)
+    }
 }
 
 impl ChatMessage {
-    pub async fn into_response(
-        inbound: ChatMessage,
-        users: &mut tokio::sync::MutexGuard<'_, HashMap<String, String>>,
-        tx: &broadcast::Sender<ChatMessage>,
-    ) -> ChatMessage {
-        if inbound.chatroom.is_empty() && !inbound.content.starts_with("!join ") {
-            return build_need_to_join_response(inbound);
+    #[instrument(name = "message", skip_all, fields(sender = %inbound.sender, room = %inbound.chatroom))]
+    pub async fn into_response(inbound: ChatMessage, state: &Arc<ServerState>) -> ChatMessage {
+        respond(inbound, state).await
+    }
+}
+
+/// Carries out the command `inbound` invokes, or passes it on as chat.
+async fn respond(inbound: ChatMessage, state: &Arc<ServerState>) -> ChatMessage {
+    let max_chars = state.config.max_message_chars;
+    if exceeds_length_limit(&inbound.content, max_chars) {
+        return build_too_long_response(inbound, max_chars);
+    }
+    // what the server says mentions commands the way its users have to type them, but only in
+    // its own words, what users wrote is passed on as it is
+    let prefix = state.config.command_prefix;
+    let command = command::parse_with_prefix(&inbound.content, prefix);
+    if inbound.chatroom.is_empty() && !matches!(command, Command::Join { .. }) {
+        return build_need_to_join_response(inbound, prefix);
+    }
+    // only known commands are counted, so clients can't blow up the number of exported series
+    if let Some(name) = command.name() {
+        debug!(command = name, "dispatching command");
+        state.metrics.command_invoked(name);
+    }
+    if command.is_privileged() && !state.is_admin(&inbound.sender) {
+        return build_permission_denied_response(&command, inbound, prefix);
+    }
+    match command {
+        Command::User(page) => {
+            let users = state.users.read().await;
+            let statuses = state.statuses.lock().await;
+            build_user_command_response(page.unwrap_or(1), inbound, &users, &statuses, prefix)
+        }
+        Command::Help => build_help_response(inbound, prefix),
+        Command::News { feed, count } => {
+            build_hn_command_response(feed, count, inbound, state.clone())
+        }
+        Command::Typing { stop: false } => build_typing_response(inbound, TYPING_MARKER),
+        Command::Typing { stop: true } => build_typing_response(inbound, TYPING_STOP_MARKER),
+        Command::Topic(topic) => build_topic_response(topic, inbound, state).await,
+        Command::History(count) => build_history_response(count, inbound, state).await,
+        Command::Search(query) => build_search_response(query, inbound, state).await,
+        Command::Join { room, password } => {
+            // an invalid room name is refused before the password is looked at
+            let password = match (password, normalize_room_name(&room)) {
+                (Some(password), Ok(room)) => {
+                    Some(hash_join_password(&room, password, state).await)
+                }
+                _ => None,
+            };
+            let mut users = state.users.write().await;
+            build_user_connection_response(&room, password, &mut users, inbound, state).await
         }
-        let content_copy = inbound.content.clone();
-        match content_copy.as_str() {
-            "!user" => build_user_command_response(inbound, users),
-            "!news" => build_hn_command_response(inbound, tx).await,
-            s if s.starts_with("!join ") => build_user_connection_response(s, users, inbound),
-            s if s.starts_with("!dm ") => build_direct_message_response(s, users, inbound),
-            s if s.starts_with("!value ") => build_binance_command_response(s, inbound).await,
-            _ => inbound,
+        Command::Kick(kicked) => {
+            let mut users = state.users.write().await;
+            build_kick_response(&kicked, &mut users, inbound, state).await
         }
+        Command::Me(action) => match filter_words(&action, state) {
+            Some(action) => build_action_response(&action, inbound),
+            None => build_filtered_response(inbound),
+        },
+        Command::Status(status) => build_status_response(status, inbound, state).await,
+        Command::Roll(dice) => build_roll_response(dice, inbound),
+        Command::EightBall(question) => build_eight_ball_response(&question, inbound),
+        Command::Weather(place) => build_weather_response(&place, inbound, state).await,
+        Command::Calc(expression) => build_calc_response(&expression, inbound),
+        Command::Uptime => build_uptime_response(inbound, state).await,
+        Command::Stats => build_stats_response(inbound, state).await,
+        Command::Ping(sent_at) => build_ping_response(sent_at, inbound),
+        Command::Joke => build_joke_response(inbound, state).await,
+        Command::Wiki(topic) => build_wiki_response(&topic, inbound, state).await,
+        Command::Crates(name) => build_crates_response(&name, inbound, state).await,
+        Command::Announce(announcement) => build_announce_response(&announcement, inbound),
+        Command::Ban(banned) => build_ban_response(&banned, inbound, state).await,
+        Command::Unban(banned) => build_unban_response(&banned, inbound, state).await,
+        Command::Block(blocked) => build_block_response(&blocked, inbound, state).await,
+        Command::Unblock(blocked) => build_unblock_response(&blocked, inbound, state).await,
+        Command::Reply { id, text } => match filter_words(&text, state) {
+            Some(text) => build_reply_response(&id, text, inbound, state).await,
+            None => build_filtered_response(inbound),
+        },
+        Command::Edit { id, text } => match filter_words(&text, state) {
+            Some(text) => build_edit_response(&id, text, inbound, state).await,
+            None => build_filtered_response(inbound),
+        },
+        Command::Delete(id) => build_delete_response(&id, inbound, state).await,
+        Command::React { id, emoji } => build_react_response(&id, &emoji, inbound, state).await,
+        Command::Dm { target, body } => {
+            let users = state.users.read().await;
+            build_direct_message_response(&target, &body, Style::Plain, &users, inbound, state)
+                .await
+        }
+        // the same as a DM, only set apart for clients to show it as one
+        Command::Whisper { target, body } => {
+            let users = state.users.read().await;
+            build_direct_message_response(&target, &body, Style::Highlight, &users, inbound, state)
+                .await
+        }
+        Command::Value { currency, amount } => {
+            build_binance_command_response(&currency, amount, inbound, state).await
+        }
+        Command::Usage(usage) => build_usage_response(usage, inbound, prefix),
+        Command::Unknown => match filter_words(&inbound.content, state) {
+            Some(content) => {
+                let inbound = ChatMessage { content, ..inbound };
+                build_chat_response(inbound, &*state.users.read().await, state)
+            }
+            None => build_filtered_response(inbound),
+        },
     }
 }
 
-fn build_need_to_join_response(mut inbound: ChatMessage) -> ChatMessage {
+fn build_usage_response(usage: &str, mut inbound: ChatMessage, prefix: char) -> ChatMessage {
+    inbound.target = inbound.sender;
+    inbound.sender = "server".to_string();
+    inbound.kind = Kind::System as i32;
+    inbound.content = format!("Usage: {}", command::with_prefix(usage, prefix));
+    inbound.style = Style::Notice as i32;
+    inbound
+}
+
+fn build_help_response(mut inbound: ChatMessage, prefix: char) -> ChatMessage {
     inbound.target = inbound.sender;
     inbound.sender = "server".to_string();
+    inbound.kind = Kind::System as i32;
     inbound.content = format!(
-        "Type {} to enter a room.",
-        String::from("!join <roomname>").bright_yellow()
+        "-- Commands: {}",
+        command::with_prefix(&USAGES.join(", "), prefix)
     );
+    inbound.style = Style::Highlight as i32;
     inbound
 }
 
-async fn build_binance_command_response(s: &str, mut inbound: ChatMessage) -> ChatMessage {
-    let currency = s.strip_prefix("!value ").unwrap().to_uppercase();
-    let url = format!(
-        "https://api4.binance.com/api/v3/ticker/price?symbol={}EUR",
-        currency
+/// A fresh id for a message. Six random letters and digits tell apart the recent messages that
+/// can be referred to well enough and are quick to type in `!reply <id>`.
+pub fn message_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..MESSAGE_ID_LENGTH)
+        .map(|_| MESSAGE_ID_CHARS[rng.gen_range(0..MESSAGE_ID_CHARS.len())] as char)
+        .collect()
+}
+
+/// Clients can't be trusted with the clock, to pass as the server or to make up ids, so every
+/// message they send is stamped, marked as plain chat and given an id on arrival.
+pub fn stamp_inbound(message: &mut ChatMessage) {
+    message.timestamp = timestamp();
+    message.style = Style::Plain as i32;
+    message.kind = Kind::Chat as i32;
+    message.id = message_id();
+    message.reply_to = None;
+}
+
+/// `content` without escape sequences and control characters, which would let users clear or
+/// restyle the terminals of everyone reading. Line breaks and tabs stay.
+pub fn strip_control(content: &str) -> String {
+    let mut stripped = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1B' => match chars.next() {
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7E').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1B' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                // the rest are two characters long, like `ESC c` resetting the terminal
+                _ => {}
+            },
+            '\n' | '\t' => stripped.push(c),
+            c if c.is_control() => {}
+            c => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+/// Posts `text` quoting the message with the given id, which has to be one of the room's recent
+/// messages.
+async fn build_reply_response(
+    id: &str,
+    text: String,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let quote = state
+        .room_history
+        .lock()
+        .await
+        .get(&inbound.chatroom)
+        .and_then(|history| history.iter().find(|message| message.id == id))
+        .map(|quoted| Quote {
+            id: quoted.id.clone(),
+            sender: quoted.sender.clone(),
+            content: truncate(
+                quoted
+                    .content
+                    .strip_prefix(ACTION_MARKER)
+                    .unwrap_or(&quoted.content),
+                MAX_QUOTE_CHARS,
+            ),
+        });
+    let Some(quote) = quote else {
+        inbound.target = std::mem::replace(&mut inbound.sender, String::from("server"));
+        inbound.kind = Kind::System as i32;
+        inbound.content = format!("There's no recent message {} in this room.", id);
+        inbound.style = Style::Error as i32;
+        return inbound;
+    };
+    inbound.content = text;
+    inbound.reply_to = Some(quote);
+    build_chat_response(inbound, &*state.users.read().await, state)
+}
+
+/// Changes the text of one of the sender's recent messages in the room, telling the room about
+/// the edit.
+async fn build_edit_response(
+    id: &str,
+    text: String,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let mut history = state.room_history.lock().await;
+    let edited = history
+        .get_mut(&inbound.chatroom)
+        .and_then(|history| history.iter_mut().find(|message| message.id == id));
+    let error = match edited {
+        None => format!("There's no recent message {} in this room.", id),
+        Some(edited) if edited.sender != inbound.sender => {
+            String::from("You can only edit your own messages.")
+        }
+        Some(edited) => {
+            // an action stays one
+            edited.content = match edited.kind() {
+                Kind::Action => format!("{}{}", ACTION_MARKER, text),
+                _ => text,
+            };
+            edited.edited = true;
+            return ChatMessage {
+                kind: Kind::Edit as i32,
+                ..edited.clone()
+            };
+        }
+    };
+    inbound.target = std::mem::replace(&mut inbound.sender, String::from("server"));
+    inbound.kind = Kind::System as i32;
+    inbound.content = error;
+    inbound.style = Style::Error as i32;
+    inbound
+}
+
+/// Removes one of the sender's recent messages from the room, or anyone's if the sender is an
+/// admin, telling the room about it so clients drop it too.
+async fn build_delete_response(
+    id: &str,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let mut history = state.room_history.lock().await;
+    let room_history = history.get_mut(&inbound.chatroom);
+    let at = room_history
+        .as_ref()
+        .and_then(|history| history.iter().position(|message| message.id == id));
+    let error = match (room_history, at) {
+        (Some(history), Some(at))
+            if history[at].sender == inbound.sender || state.is_admin(&inbound.sender) =>
+        {
+            history.remove(at);
+            state.reactions.lock().await.remove(id);
+            inbound.id = id.to_string();
+            inbound.kind = Kind::Delete as i32;
+            inbound.content.clear();
+            return inbound;
+        }
+        (Some(_), Some(_)) => String::from("You can only delete your own messages."),
+        _ => format!("There's no recent message {} in this room.", id),
+    };
+    inbound.target = std::mem::replace(&mut inbound.sender, String::from("server"));
+    inbound.kind = Kind::System as i32;
+    inbound.content = error;
+    inbound.style = Style::Error as i32;
+    inbound
+}
+
+/// Adds the sender's reaction with `emoji` to a recent message of the room, or takes it back if
+/// they had already reacted with it, telling the room about the message's reactions now.
+async fn build_react_response(
+    id: &str,
+    emoji: &str,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let history = state.room_history.lock().await;
+    let exists = history
+        .get(&inbound.chatroom)
+        .is_some_and(|history| history.iter().any(|message| message.id == id));
+    let error = if !is_emoji(emoji) {
+        format!("{} isn't an emoji, react with one like 👍.", emoji)
+    } else if !exists {
+        format!("There's no recent message {} in this room.", id)
+    } else {
+        let mut reactions = state.reactions.lock().await;
+        let reactions = reactions.entry(id.to_string()).or_default();
+        if toggle_reaction(reactions, emoji, &inbound.sender) {
+            inbound.id = id.to_string();
+            inbound.kind = Kind::Reaction as i32;
+            inbound.content = emoji.to_string();
+            inbound.reactions = reactions.clone();
+            return inbound;
+        }
+        format!(
+            "That message already has {} different reactions.",
+            MAX_REACTIONS
+        )
+    };
+    inbound.target = std::mem::replace(&mut inbound.sender, String::from("server"));
+    inbound.kind = Kind::System as i32;
+    inbound.content = error;
+    inbound.style = Style::Error as i32;
+    inbound
+}
+
+/// Adds `user` to the users who reacted with `emoji`, or removes them if they're among them
+/// already. False if that would be one emoji more than `MAX_REACTIONS`.
+fn toggle_reaction(reactions: &mut Vec<Reaction>, emoji: &str, user: &str) -> bool {
+    let Some(at) = reactions
+        .iter()
+        .position(|reaction| reaction.emoji == emoji)
+    else {
+        if reactions.len() >= MAX_REACTIONS {
+            return false;
+        }
+        reactions.push(Reaction {
+            emoji: emoji.to_string(),
+            users: vec![user.to_string()],
+        });
+        return true;
+    };
+    let users = &mut reactions[at].users;
+    match users.iter().position(|reacted| reacted == user) {
+        Some(reacted) => {
+            users.remove(reacted);
+            if users.is_empty() {
+                reactions.remove(at);
+            }
+        }
+        None => users.push(user.to_string()),
+    }
+    true
+}
+
+/// Whether `text` could be a single emoji. There's no telling without the Unicode tables, so
+/// anything short without letters, digits, spaces or ASCII punctuation passes.
+fn is_emoji(text: &str) -> bool {
+    text.chars().count() <= MAX_EMOJI_CHARS
+        && !text
+            .chars()
+            .any(|c| c.is_ascii() || c.is_alphanumeric() || c.is_whitespace())
+}
+
+/// Passes a chat message on as is, letting mentioned users in other rooms know about it.
+fn build_chat_response(
+    inbound: ChatMessage,
+    users: &HashMap<String, String>,
+    state: &ServerState,
+) -> ChatMessage {
+    let mut notified = Vec::new();
+    for mentioned in mentions(&inbound.content) {
+        let elsewhere = users
+            .get(mentioned)
+            .is_some_and(|room| *room != inbound.chatroom);
+        if elsewhere && mentioned != inbound.sender && !notified.contains(&mentioned) {
+            notified.push(mentioned);
+            state.router.send(ChatMessage {
+                sender: "server".to_string(),
+                timestamp: inbound.timestamp,
+                chatroom: inbound.chatroom.clone(),
+                content: format!(
+                    "{} mentioned you in {}: {}",
+                    inbound.sender, inbound.chatroom, inbound.content
+                ),
+                target: mentioned.to_string(),
+                style: Style::Highlight as i32,
+                kind: Kind::System as i32,
+                ..Default::default()
+            });
+        }
+    }
+    inbound
+}
+
+/// The names mentioned as `@name` in `content`. A mention starts a word, so mail addresses
+/// don't count, and trailing punctuation isn't part of the name.
+fn mentions(content: &str) -> Vec<&str> {
+    content
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// `text` with the banned words masked, `None` if the word filter rejects it. Without a filter
+/// everything passes as is.
+fn filter_words(text: &str, state: &ServerState) -> Option<String> {
+    let Some(filter) = state.config.word_filter.as_ref() else {
+        return Some(text.to_string());
+    };
+    match filter.mask(text) {
+        Some(_) if filter.reject => None,
+        Some(masked) => Some(masked),
+        None => Some(text.to_string()),
+    }
+}
+
+fn build_filtered_response(mut inbound: ChatMessage) -> ChatMessage {
+    inbound.target = inbound.sender;
+    inbound.sender = "server".to_string();
+    inbound.kind = Kind::System as i32;
+    inbound.content = String::from("Your message wasn't sent, it contains a banned word.");
+    inbound.style = Style::Error as i32;
+    inbound
+}
+
+fn build_need_to_join_response(mut inbound: ChatMessage, prefix: char) -> ChatMessage {
+    inbound.target = inbound.sender;
+    inbound.sender = "server".to_string();
+    inbound.kind = Kind::System as i32;
+    inbound.content = format!("Type {}join <roomname> to enter a room.", prefix);
+    inbound.style = Style::Notice as i32;
+    inbound
+}
+
+fn exceeds_length_limit(content: &str, max_chars: usize) -> bool {
+    // check the byte length first so huge payloads are rejected without walking every char
+    content.len() > max_chars * 4 || content.chars().count() > max_chars
+}
+
+fn build_too_long_response(mut inbound: ChatMessage, max_chars: usize) -> ChatMessage {
+    inbound.target = inbound.sender;
+    inbound.sender = "server".to_string();
+    inbound.kind = Kind::System as i32;
+    inbound.content = format!(
+        "Message not sent, it exceeds the limit of {} characters.",
+        max_chars
     );
-    if let Ok(response) = get_currency_conversion(url).await {
-        if response.status().is_server_error() || response.status().is_client_error() {
-            inbound.content = format!(
-                "{} {}",
-                String::from("Error requesting conversion rate for").red(),
-                currency.bright_yellow()
-            );
-        } else {
-            if let Ok(value) = response.text().await {
-                let parsed_response: Currency = serde_json::from_str(&value).unwrap();
-                let price = parsed_response.price.parse::<f32>().unwrap();
+    inbound.style = Style::Error as i32;
+    inbound
+}
+
+async fn build_binance_command_response(
+    currency: &str,
+    amount: Option<f64>,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let currency = currency.to_uppercase();
+    if let Some(wait) = value_throttle(state, &inbound.sender).await {
+        inbound.target = inbound.sender.clone();
+        inbound.sender = String::from("server");
+        inbound.kind = Kind::System as i32;
+        inbound.content = format!(
+            "Rate limited, try again in {} seconds.",
+            wait.as_secs_f64().ceil()
+        );
+        inbound.style = Style::Error as i32;
+        return inbound;
+    }
+    let api = &state.config.binance_api;
+    // the symbol is what the user typed, it mustn't add parameters of its own
+    let ticker = |endpoint: &str| {
+        reqwest::Url::parse_with_params(
+            &format!("{}/api/v3/ticker/{}", api, endpoint),
+            &[("symbol", format!("{}EUR", currency))],
+        )
+        .expect("the Binance API base is a valid URL")
+        .to_string()
+    };
+    let (url, change_url) = (ticker("price"), ticker("24hr"));
+    let (conversion, change) = tokio::join!(
+        get_currency_conversion(&state.http_client, url),
+        get_price_change(&state.http_client, change_url)
+    );
+    if let Err(e) = &conversion {
+        warn!(error = %e, %currency, "binance request failed");
+        state.metrics.api_error("binance");
+    }
+    if let Ok(response) = conversion {
+        let status = response.status();
+        // Binance answers unknown symbols with an error body, so only a failing server is
+        // treated as an error without looking at what it sent. Clients ignoring its 429s are
+        // banned for a while and get 418 instead.
+        let conversion =
+            if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::IM_A_TEAPOT {
+                Conversion::RateLimited(retry_after(response.headers()))
+            } else if status.is_server_error() {
+                Conversion::Invalid
+            } else {
+                parse_conversion(&response.text().await.unwrap_or_default())
+            };
+        match conversion {
+            Conversion::Price(price) => {
+                inbound.content = match amount {
+                    Some(amount) => format!(
+                        "{} {} is currently worth {:.2}€",
+                        amount,
+                        currency,
+                        amount * price
+                    ),
+                    None => format!("${} is currently worth {price:.2}€", currency),
+                };
+                // the price is still worth showing when only the 24h statistics are unavailable
+                if let Some(change) = change {
+                    inbound.content += &format!(", {:+.2}% in 24h", change);
+                }
+                inbound.style = Style::Highlight as i32;
+            }
+            Conversion::RateLimited(wait) => {
+                warn!(%status, ?wait, "binance rate limited us");
+                state.metrics.api_error("binance");
+                *state.binance_backoff.lock().await = Some(Instant::now() + wait);
+                inbound.content = format!(
+                    "Rate limited by Binance, try again in {} seconds.",
+                    wait.as_secs_f64().ceil()
+                );
+                inbound.style = Style::Error as i32;
+            }
+            Conversion::UnknownSymbol => {
                 inbound.content = format!(
-                    "{}{} is currently worth {}{}",
-                    String::from("$").bright_yellow(),
-                    currency.bright_yellow(),
-                    format!("{price:.2}").bright_yellow(),
-                    String::from("€").bright_yellow()
+                    "Unknown symbol {}, try e.g. {}value BTC",
+                    currency, state.config.command_prefix
                 );
+                inbound.style = Style::Error as i32;
+            }
+            Conversion::Invalid => {
+                warn!(%status, %currency, "binance returned an error");
+                state.metrics.api_error("binance");
+                inbound.content = format!("Error requesting conversion rate for {}", currency);
+                inbound.style = Style::Error as i32;
             }
         }
     } else {
-        inbound.content = format!(
-            "{}",
-            String::from("Something went wrong when trying to retrieve currency information").red()
-        );
+        inbound.content =
+            String::from("Something went wrong when trying to retrieve currency information");
+        inbound.style = Style::Error as i32;
     }
     inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
     inbound
 }
 
-async fn build_hn_command_response(
+fn build_hn_command_response(
+    feed: Option<String>,
+    count: Option<usize>,
     mut inbound: ChatMessage,
-    tx: &broadcast::Sender<ChatMessage>,
+    state: Arc<ServerState>,
 ) -> ChatMessage {
     inbound.target = inbound.sender.clone();
     inbound.sender = String::from("server");
-    let tx = tx.clone();
+    inbound.kind = Kind::System as i32;
+    let count = match count.unwrap_or(DEFAULT_NEWS_COUNT) {
+        count if count > MAX_NEWS_COUNT => {
+            inbound.content = format!("At most {} stories can be shown at once.", MAX_NEWS_COUNT);
+            inbound.style = Style::Error as i32;
+            return inbound;
+        }
+        count => count.max(1),
+    };
+    let feed = match feed {
+        None => "topstories",
+        Some(feed) => match HN_FEEDS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&feed))
+        {
+            Some((_, endpoint)) => endpoint,
+            None => {
+                inbound.content = format!(
+                    "Unknown feed {}, try one of: {}",
+                    feed,
+                    HN_FEEDS
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                inbound.style = Style::Error as i32;
+                return inbound;
+            }
+        },
+    };
     let mut delayed = inbound.clone();
     tokio::spawn(async move {
-        let url = "https://hacker-news.firebaseio.com/v0/topstories.json?print=pretty".to_string();
-        if let Ok(top) = get_top_hn(url).await {
+        let api = &state.config.hacker_news_api;
+        let top = get_top_hn(&state.http_client, api, feed, count).await;
+        if let Err(e) = &top {
+            warn!(error = %e, "hacker news request failed");
+            state.metrics.api_error("hacker_news");
+        }
+        if let Ok(top) = top {
             delayed.content = top
                 .iter()
                 .map(|story| {
                     format!(
-                        "{} {} \t\"{}\" {} {}\n\t{}",
-                        String::from("▲").truecolor(100, 248, 140),
-                        story.score.to_string().truecolor(100, 248, 140),
-                        story.title.white(),
-                        String::from("by").truecolor(153, 140, 139),
-                        story
-                            .by
-                            .clone()
-                            .unwrap_or(String::new())
-                            .truecolor(100, 248, 140),
-                        story
-                            .url
-                            .clone()
-                            .unwrap_or(String::new())
-                            .truecolor(99, 215, 253)
+                        "▲ {} \t\"{}\" by {}\n\t{}",
+                        story.score,
+                        story.title.clone().unwrap_or(String::new()),
+                        story.by.clone().unwrap_or(String::new()),
+                        story.url.clone().unwrap_or(String::new())
                     )
                 })
                 .collect::<Vec<String>>()
                 .join("\n");
+            delayed.style = Style::Plain as i32;
         } else {
-            delayed.content = format!(
-                "{}",
-                String::from("Couldn't retrieve Hacker News frontpage").red()
-            );
+            delayed.content = String::from("Couldn't retrieve Hacker News frontpage");
+            delayed.style = Style::Error as i32;
         }
-        let _ = tx.send(delayed);
+        state.router.send(delayed);
     });
-    inbound.content = format!(
-        "{}",
-        String::from("Retrieving News ...").truecolor(153, 140, 139)
-    );
+    inbound.content = String::from("Retrieving News ...");
+    inbound.style = Style::Notice as i32;
     inbound
 }
 
-fn build_direct_message_response(
-    s: &str,
-    users: &mut tokio::sync::MutexGuard<HashMap<String, String>>,
-    inbound: ChatMessage,
+/// Looks up the current weather of a city, or of coordinates given as `lat,lon`, and shows it
+/// to the room. Coordinates skip the geocoding request.
+async fn build_weather_response(
+    query: &str,
+    mut inbound: ChatMessage,
+    state: &ServerState,
 ) -> ChatMessage {
-    let cmd_split = s.split(" ").collect::<Vec<&str>>();
-    let target_user = cmd_split.get(1).unwrap();
-    let msg = &cmd_split[2..];
+    let asker = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    let client = &state.http_client;
+    let place = match parse_coordinates(query) {
+        Some((latitude, longitude)) => Ok(Some(Place {
+            name: format!("{}, {}", latitude, longitude),
+            country: None,
+            latitude,
+            longitude,
+        })),
+        None => find_place(client, &state.config.geocoding_api, query).await,
+    };
+    let weather = match place {
+        Ok(Some(place)) => get_current_weather(client, &state.config.weather_api, &place)
+            .await
+            .map(|weather| Some((place, weather))),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+    match weather {
+        Ok(Some((place, weather))) => {
+            let place = match place.country {
+                Some(country) => format!("{}, {}", place.name, country),
+                None => place.name,
+            };
+            inbound.content = format!(
+                "-- Weather in {}: {:.1}°C, {}, wind {:.0} km/h",
+                place,
+                weather.temperature,
+                weather_description(weather.weathercode),
+                weather.windspeed
+            );
+            inbound.style = Style::Highlight as i32;
+        }
+        Ok(None) => {
+            inbound.target = asker;
+            inbound.content = format!("Couldn't find a place called {}.", query);
+            inbound.style = Style::Error as i32;
+        }
+        Err(e) => {
+            warn!(error = %e, %query, "open-meteo request failed");
+            state.metrics.api_error("open_meteo");
+            inbound.target = asker;
+            inbound.content = format!("Couldn't retrieve the weather for {}.", query);
+            inbound.style = Style::Error as i32;
+        }
+    }
+    inbound
+}
 
-    if !users.contains_key(target_user.to_owned()) {
-        ChatMessage {
-            sender: "server".to_string(),
-            timestamp: timestamp(),
-            chatroom: inbound.chatroom,
-            content: format!(
-                "No user named {} is currently connected.",
-                target_user.bright_yellow()
-            ),
-            target: inbound.sender,
+/// Tells the room a joke.
+async fn build_joke_response(mut inbound: ChatMessage, state: &ServerState) -> ChatMessage {
+    let asker = std::mem::replace(&mut inbound.sender, String::from("server"));
+    inbound.kind = Kind::System as i32;
+    match get_joke(&state.http_client, &state.config.jokes_api).await {
+        Ok(joke) => {
+            inbound.content = format!("-- {}\n-- {}", joke.setup, joke.punchline);
+            inbound.style = Style::Highlight as i32;
         }
-    } else {
-        ChatMessage {
-            sender: inbound.sender,
-            timestamp: timestamp(),
-            chatroom: inbound.chatroom,
-            content: msg.join(" "),
-            target: target_user.to_string(),
+        Err(e) => {
+            warn!(error = %e, "joke request failed");
+            state.metrics.api_error("jokes");
+            inbound.target = asker;
+            inbound.content = String::from("Couldn't retrieve a joke, try again later.");
+            inbound.style = Style::Error as i32;
         }
     }
+    inbound
 }
 
-fn build_user_connection_response(
-    s: &str,
-    users: &mut tokio::sync::MutexGuard<HashMap<String, String>>,
-    inbound: ChatMessage,
+/// Shows the room the start of the Wikipedia article on `topic`, only telling the sender if
+/// there's none or it's ambiguous.
+async fn build_wiki_response(
+    topic: &str,
+    mut inbound: ChatMessage,
+    state: &ServerState,
 ) -> ChatMessage {
-    let new_room = s.strip_prefix("!join ").unwrap();
-    users.insert(inbound.sender.to_string(), new_room.to_string());
-    ChatMessage {
-        sender: "server".to_string(),
-        timestamp: timestamp(),
-        chatroom: new_room.to_string(),
-        content: format!("-- {} has joined {}", inbound.sender, new_room)
-            .truecolor(153, 140, 139)
-            .to_string(),
-        target: String::new(),
+    let asker = std::mem::replace(&mut inbound.sender, String::from("server"));
+    inbound.kind = Kind::System as i32;
+    inbound.style = Style::Error as i32;
+    match get_summary(&state.http_client, &state.config.wikipedia_api, topic).await {
+        Ok(Some(summary)) if summary.kind == "disambiguation" => {
+            inbound.target = asker;
+            inbound.content = format!(
+                "{} could mean several articles, try being more specific.",
+                summary.title
+            );
+        }
+        Ok(Some(summary)) => {
+            let extract = truncate(summary.extract.trim(), state.config.wiki_extract_chars);
+            inbound.content = match summary.content_urls {
+                Some(urls) => format!("-- {}: {} {}", summary.title, extract, urls.desktop.page),
+                None => format!("-- {}: {}", summary.title, extract),
+            };
+            inbound.style = Style::Highlight as i32;
+        }
+        Ok(None) => {
+            inbound.target = asker;
+            inbound.content = format!("Wikipedia has no article on {}.", topic);
+        }
+        Err(e) => {
+            warn!(error = %e, %topic, "wikipedia request failed");
+            state.metrics.api_error("wikipedia");
+            inbound.target = asker;
+            inbound.content = format!("Couldn't retrieve the article on {}.", topic);
+        }
     }
+    inbound
 }
 
-fn build_user_command_response(
+/// Shows the room the latest version of a crate, what it is and how popular.
+async fn build_crates_response(
+    name: &str,
     mut inbound: ChatMessage,
-    users: &mut tokio::sync::MutexGuard<HashMap<String, String>>,
+    state: &ServerState,
 ) -> ChatMessage {
-    inbound.target = inbound.sender.clone();
+    let asker = std::mem::replace(&mut inbound.sender, String::from("server"));
+    inbound.kind = Kind::System as i32;
+    inbound.style = Style::Error as i32;
+    match get_crate(&state.http_client, &state.config.crates_api, name).await {
+        Ok(Some(krate)) => {
+            let description = krate
+                .description
+                .map(|description| format!(": {}", description.trim()))
+                .unwrap_or_default();
+            inbound.content = format!(
+                "-- {} {}{}, {} downloads https://crates.io/crates/{}",
+                krate.name,
+                krate.max_version,
+                description,
+                thousands(krate.downloads),
+                krate.name
+            );
+            inbound.style = Style::Highlight as i32;
+        }
+        Ok(None) => {
+            inbound.target = asker;
+            inbound.content = format!("There's no crate called {} on crates.io.", name);
+        }
+        Err(e) => {
+            warn!(error = %e, %name, "crates.io request failed");
+            state.metrics.api_error("crates_io");
+            inbound.target = asker;
+            inbound.content = format!("Couldn't look up the crate {}.", name);
+        }
+    }
+    inbound
+}
+
+fn build_permission_denied_response(
+    command: &Command,
+    mut inbound: ChatMessage,
+    prefix: char,
+) -> ChatMessage {
+    warn!(command = command.name(), "permission denied");
+    inbound.target = inbound.sender;
     inbound.sender = String::from("server");
-    let users_in_room = users
-        .iter()
-        .filter(|e| e.1 == inbound.chatroom.as_str())
-        .map(|(user, _)| user.clone())
-        .collect::<Vec<String>>();
+    inbound.kind = Kind::System as i32;
     inbound.content = format!(
-        "-- Users in {}: {}",
-        inbound.chatroom.bright_cyan(),
-        users_in_room.join(", ").bright_yellow()
+        "Permission denied, {} is for admins only.",
+        command::with_prefix(command.name().unwrap_or_default(), prefix)
     );
+    inbound.style = Style::Error as i32;
     inbound
 }
 
-fn timestamp() -> i64 {
-    let time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time broke..");
-    time.as_millis() as i64
-}
-
-async fn get_currency_conversion(url: String) -> Result<Response, Error> {
-    let response = reqwest::get(url).await?;
-    Ok(response)
+/// Sends an announcement to everyone connected, in any room.
+fn build_announce_response(announcement: &str, mut inbound: ChatMessage) -> ChatMessage {
+    let sender = std::mem::replace(&mut inbound.sender, String::from("server"));
+    info!(admin = %sender, "announcing to everyone");
+    inbound.kind = Kind::Announcement as i32;
+    inbound.content = format!("-- Announcement from {}: {}", sender, announcement);
+    inbound.style = Style::Highlight as i32;
+    inbound
 }
 
-async fn get_top_hn(url: String) -> Result<Vec<HNStory>, Error> {
-    let response = reqwest::get(url).await?;
-    let response = response.text().await?;
-
-    let ids = response
-        .trim_start_matches("[ ")
+/// Bans a name for good, disconnecting its user right away if they are connected.
+async fn build_ban_response(
+    banned: &str,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let admin = std::mem::replace(&mut inbound.sender, String::from("server"));
+    inbound.target = admin.clone();
+    inbound.kind = Kind::System as i32;
+    inbound.style = Style::Error as i32;
+    if banned == admin {
+        inbound.content = String::from("You can't ban yourself.");
+        return inbound;
+    }
+    if state.is_admin(banned) {
+        inbound.content = String::from("Admins can't be banned.");
+        return inbound;
+    }
+    let saved = match state.bans.lock().await.ban(banned) {
+        Ok(false) => {
+            inbound.content = format!("{} is already banned.", banned);
+            return inbound;
+        }
+        Ok(true) => true,
+        Err(e) => {
+            error!(error = %e, "couldn't save the ban list");
+            false
+        }
+    };
+    info!(%admin, user = %banned, "banned user");
+    inbound.content = format!("-- {} is banned", banned);
+    let disconnect = state.disconnects.lock().await.get(banned).cloned();
+    if let Some(disconnect) = disconnect {
+        let room = state.users.read().await.get(banned).cloned();
+        state.router.send(ChatMessage {
+            sender: "server".to_string(),
+            timestamp: timestamp(),
+            chatroom: room.unwrap_or_default(),
+            content: format!("You were banned by {}.", admin),
+            target: banned.to_string(),
+            style: Style::Error as i32,
+            kind: Kind::System as i32,
+            ..Default::default()
+        });
+        disconnect.notify_one();
+        inbound.content += " and was disconnected";
+    }
+    if !saved {
+        inbound.content += ", but the ban list couldn't be saved so the ban ends on restart";
+    }
+    inbound.style = Style::Notice as i32;
+    inbound
+}
+
+async fn build_unban_response(
+    banned: &str,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    inbound.target = std::mem::replace(&mut inbound.sender, String::from("server"));
+    inbound.kind = Kind::System as i32;
+    let result = state.bans.lock().await.unban(banned);
+    (inbound.content, inbound.style) = match result {
+        Ok(true) => (
+            format!("-- {} is no longer banned", banned),
+            Style::Notice as i32,
+        ),
+        Ok(false) => (format!("{} isn't banned.", banned), Style::Error as i32),
+        Err(e) => {
+            error!(error = %e, "couldn't save the ban list");
+            (
+                format!(
+                    "-- {} is no longer banned, but the ban list couldn't be saved so the ban returns on restart",
+                    banned
+                ),
+                Style::Notice as i32,
+            )
+        }
+    };
+    inbound
+}
+
+/// `n` with commas between groups of thousands, like `1,234,567`.
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// Cuts `text` down to `max_chars`, at the last word boundary and with an ellipsis if it's
+/// longer.
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        None => text.to_string(),
+        Some((end, _)) => {
+            let cut = &text[..end];
+            let cut = cut.rfind(char::is_whitespace).map_or(cut, |at| &cut[..at]);
+            format!("{}…", cut.trim_end())
+        }
+    }
+}
+
+/// Coordinates like `52.52,13.41` or `52.52 13.41`, latitude first.
+fn parse_coordinates(query: &str) -> Option<(f64, f64)> {
+    let (latitude, longitude) = query.split_once([',', ' '])?;
+    let latitude: f64 = latitude.trim().parse().ok()?;
+    let longitude: f64 = longitude.trim().parse().ok()?;
+    let valid = (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude);
+    valid.then_some((latitude, longitude))
+}
+
+/// Describes a WMO weather interpretation code as used by Open-Meteo.
+fn weather_description(code: u8) -> &'static str {
+    match code {
+        0 => "clear sky",
+        1 => "mainly clear",
+        2 => "partly cloudy",
+        3 => "overcast",
+        45 | 48 => "fog",
+        51 | 53 | 55 | 56 | 57 => "drizzle",
+        61 | 63 | 65 | 66 | 67 | 80 | 81 | 82 => "rain",
+        71 | 73 | 75 | 77 | 85 | 86 => "snow",
+        95 | 96 | 99 => "thunderstorm",
+        _ => "unknown conditions",
+    }
+}
+
+async fn build_history_response(
+    count: Option<usize>,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let count = count
+        .unwrap_or(DEFAULT_HISTORY_COUNT)
+        .clamp(1, MAX_HISTORY_COUNT);
+    inbound.target = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    let history = match state.history_store.clone() {
+        Some(store) => {
+            let room = inbound.chatroom.clone();
+            read_history(move || store.last(&room, count)).await
+        }
+        None => Err(std::io::ErrorKind::NotFound.into()),
+    };
+    match history {
+        Ok(messages) => {
+            state.router.send_to_user(
+                &inbound.target,
+                ChatMessage {
+                    sender: "server".to_string(),
+                    timestamp: timestamp(),
+                    chatroom: inbound.chatroom.clone(),
+                    content: format!("-- History of {}:", inbound.chatroom),
+                    target: inbound.target.clone(),
+                    style: Style::Notice as i32,
+                    kind: Kind::System as i32,
+                    ..Default::default()
+                },
+            );
+            let shown = messages.len();
+            for message in messages {
+                state.router.send_to_user(&inbound.target, message);
+            }
+            inbound.content = format!("-- End of history, {} messages.", shown);
+            inbound.style = Style::Notice as i32;
+        }
+        Err(_) => {
+            inbound.content = String::from("Couldn't read the chat history.");
+            inbound.style = Style::Error as i32;
+        }
+    }
+    inbound
+}
+
+async fn build_search_response(
+    query: String,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    inbound.target = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    let results = match state.history_store.clone() {
+        Some(store) => {
+            let (room, query) = (inbound.chatroom.clone(), query.clone());
+            read_history(move || store.search(&room, &query, MAX_SEARCH_RESULTS)).await
+        }
+        None => Err(std::io::ErrorKind::NotFound.into()),
+    };
+    match results {
+        Ok((_, 0)) => {
+            inbound.content = format!("-- No matches for \"{}\".", query);
+            inbound.style = Style::Notice as i32;
+        }
+        Ok((matches, total)) => {
+            state.router.send_to_user(
+                &inbound.target,
+                ChatMessage {
+                    sender: "server".to_string(),
+                    timestamp: timestamp(),
+                    chatroom: inbound.chatroom.clone(),
+                    content: format!(
+                        "-- Messages in {} matching \"{}\":",
+                        inbound.chatroom, query
+                    ),
+                    target: inbound.target.clone(),
+                    style: Style::Notice as i32,
+                    kind: Kind::System as i32,
+                    ..Default::default()
+                },
+            );
+            let shown = matches.len();
+            for message in matches {
+                state.router.send_to_user(&inbound.target, message);
+            }
+            inbound.content = format!("-- Showing the latest {} of {} matches.", shown, total);
+            inbound.style = Style::Notice as i32;
+        }
+        Err(_) => {
+            inbound.content = String::from("Couldn't search the chat history.");
+            inbound.style = Style::Error as i32;
+        }
+    }
+    inbound
+}
+
+/// Runs a read of the history store off the runtime, it goes to the file.
+async fn read_history<T: Send + 'static>(
+    read: impl FnOnce() -> std::io::Result<T> + Send + 'static,
+) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(read).await?
+}
+
+fn build_typing_response(mut inbound: ChatMessage, marker: &str) -> ChatMessage {
+    inbound.content = marker.to_string();
+    inbound.kind = Kind::Typing as i32;
+    inbound
+}
+
+fn build_action_response(action: &str, mut inbound: ChatMessage) -> ChatMessage {
+    inbound.content = format!("{}{}", ACTION_MARKER, action);
+    inbound.kind = Kind::Action as i32;
+    inbound
+}
+
+/// Rolls the dice and shows every roll and the total to the whole room.
+fn build_roll_response(dice: Dice, mut inbound: ChatMessage) -> ChatMessage {
+    let roller = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    if dice.count == 0 || dice.count > MAX_DICE || dice.sides == 0 || dice.sides > MAX_DICE_SIDES {
+        inbound.target = roller;
+        inbound.content = format!(
+            "You can roll 1 to {} dice with 1 to {} sides.",
+            MAX_DICE, MAX_DICE_SIDES
+        );
+        inbound.style = Style::Error as i32;
+        return inbound;
+    }
+    let mut rng = rand::thread_rng();
+    let rolls: Vec<u32> = (0..dice.count)
+        .map(|_| rng.gen_range(1..=dice.sides))
+        .collect();
+    let total = rolls.iter().map(|&roll| i64::from(roll)).sum::<i64>() + dice.modifier;
+    let rolls = rolls
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let notation = match dice.modifier {
+        0 => format!("{}d{}", dice.count, dice.sides),
+        modifier => format!("{}d{}{:+}", dice.count, dice.sides, modifier),
+    };
+    inbound.content = match dice.modifier {
+        0 => format!("-- {} rolled {}: [{}] = {}", roller, notation, rolls, total),
+        modifier => format!(
+            "-- {} rolled {}: [{}] {:+} = {}",
+            roller, notation, rolls, modifier, total
+        ),
+    };
+    inbound.style = Style::Highlight as i32;
+    inbound
+}
+
+fn build_calc_response(expression: &str, mut inbound: ChatMessage) -> ChatMessage {
+    inbound.target = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    (inbound.content, inbound.style) = match calc::evaluate(expression) {
+        Ok(result) => (
+            format!("-- {} = {}", expression, result),
+            Style::Highlight as i32,
+        ),
+        Err(e) => (
+            format!("Couldn't calculate that: {}.", e),
+            Style::Error as i32,
+        ),
+    };
+    inbound
+}
+
+/// Tells the sender how long the server has been running and how busy it is.
+async fn build_uptime_response(mut inbound: ChatMessage, state: &ServerState) -> ChatMessage {
+    let users = state.users.read().await;
+    let rooms: HashSet<&String> = users.values().filter(|room| !room.is_empty()).collect();
+    // names reserved by a name check are kept in `users` before anyone connects with them
+    let reservations = state.reservations.lock().await;
+    let online = users
+        .keys()
+        .filter(|user| !reservations.contains_key(*user))
+        .count();
+    inbound.target = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    inbound.style = Style::Highlight as i32;
+    inbound.content = format!(
+        "-- Up for {}, {} online in {}",
+        format_uptime(state.started.elapsed()),
+        count(online, "user"),
+        count(rooms.len(), "room")
+    );
+    inbound
+}
+
+/// Tells the sender how many messages were sent in their room and overall since startup.
+async fn build_stats_response(mut inbound: ChatMessage, state: &ServerState) -> ChatMessage {
+    let in_room = state
+        .room_messages
+        .lock()
+        .await
+        .get(&inbound.chatroom)
+        .copied()
+        .unwrap_or(0);
+    inbound.content = format!(
+        "-- {} in {}, {} in all rooms and {} since the server started",
+        count(in_room as usize, "message"),
+        inbound.chatroom,
+        state.total_messages.load(Ordering::Relaxed),
+        count(
+            state.direct_messages.load(Ordering::Relaxed) as usize,
+            "direct message"
+        )
+    );
+    let commands: Vec<String> = state
+        .metrics
+        .command_counts()
+        .into_iter()
+        .take(MOST_USED_COMMANDS)
+        .map(|(command, count)| format!("{} {}", command, count))
+        .collect();
+    inbound.content = format!(
+        "{}\nMost used commands: {}",
+        inbound.content,
+        commands.join(", ")
+    );
+    inbound.target = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    inbound.style = Style::Highlight as i32;
+    inbound
+}
+
+/// Answers a ping right away, with how long it took to arrive if the client said when it sent
+/// it. That compares the client's clock with the server's, so a client clock running ahead
+/// is reported instead of a negative delay.
+fn build_ping_response(sent_at: Option<i64>, mut inbound: ChatMessage) -> ChatMessage {
+    // the server stamps every message on arrival
+    let received_at = inbound.timestamp;
+    let time = Utc
+        .timestamp_millis_opt(received_at)
+        .single()
+        .map(|time| time.format("%H:%M:%S%.3f UTC").to_string())
+        .unwrap_or_default();
+    inbound.content = match sent_at.map(|sent_at| received_at - sent_at) {
+        None => format!("-- pong, your ping arrived at {}", time),
+        Some(delay) if delay >= 0 => {
+            format!("-- pong, your ping took {} ms to arrive at {}", delay, time)
+        }
+        Some(delay) => format!(
+            "-- pong, your ping arrived at {}. Your clock is {} ms ahead of the server's, so the delay can't be told.",
+            time, -delay
+        ),
+    };
+    inbound.target = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    inbound.style = Style::Highlight as i32;
+    inbound
+}
+
+/// A duration in days, hours and minutes, leaving out leading units that are zero.
+fn format_uptime(uptime: Duration) -> String {
+    let minutes = uptime.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+fn count(n: usize, noun: &str) -> String {
+    if n == 1 {
+        format!("1 {}", noun)
+    } else {
+        format!("{} {}s", n, noun)
+    }
+}
+
+/// Answers the question for the whole room, so everyone sees what was asked.
+fn build_eight_ball_response(question: &str, mut inbound: ChatMessage) -> ChatMessage {
+    let answer = EIGHT_BALL_ANSWERS[rand::thread_rng().gen_range(0..EIGHT_BALL_ANSWERS.len())];
+    inbound.content = format!(
+        "-- {} asked the 8-ball: {}\n-- {}",
+        inbound.sender, question, answer
+    );
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    inbound.style = Style::Highlight as i32;
+    inbound
+}
+
+async fn build_direct_message_response(
+    target_user: &str,
+    msg: &str,
+    style: Style,
+    users: &HashMap<String, String>,
+    inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    if target_user == inbound.sender {
+        ChatMessage {
+            sender: "server".to_string(),
+            timestamp: timestamp(),
+            chatroom: inbound.chatroom,
+            content: String::from("You can't DM yourself."),
+            target: inbound.sender,
+            style: Style::Error as i32,
+            kind: Kind::System as i32,
+            ..Default::default()
+        }
+    } else if !users.contains_key(target_user) {
+        // a blocked sender is told the same as anyone else, only nothing is queued
+        if !is_blocked(state, target_user, &inbound.sender).await {
+            queue_offline_message(
+                state,
+                ChatMessage {
+                    sender: inbound.sender.clone(),
+                    timestamp: timestamp(),
+                    chatroom: inbound.chatroom.clone(),
+                    content: msg.to_string(),
+                    target: target_user.to_string(),
+                    style: style as i32,
+                    kind: Kind::DirectMessage as i32,
+                    id: inbound.id.clone(),
+                    ..Default::default()
+                },
+            )
+            .await;
+        }
+        ChatMessage {
+            sender: "server".to_string(),
+            timestamp: timestamp(),
+            chatroom: inbound.chatroom,
+            content: format!(
+                "No user named {} is currently connected, the message will be delivered when they join.",
+                target_user
+            ),
+            target: inbound.sender,
+            style: Style::Notice as i32,
+            kind: Kind::System as i32,
+            ..Default::default()
+        }
+    } else if is_blocked(state, target_user, &inbound.sender).await {
+        // the sender sees what a delivered message looks like, so blocks can't be probed
+        let copy = ChatMessage {
+            sender: inbound.sender.clone(),
+            timestamp: timestamp(),
+            chatroom: inbound.chatroom,
+            content: msg.to_string(),
+            target: target_user.to_string(),
+            style: style as i32,
+            kind: Kind::DirectMessage as i32,
+            id: inbound.id,
+            ..Default::default()
+        };
+        let receipt = super::delivery_receipt(&copy, Some(target_user))
+            .expect("a direct message to someone else is confirmed");
+        state.router.send_to_user(&inbound.sender, copy);
+        receipt
+    } else {
+        ChatMessage {
+            sender: inbound.sender,
+            timestamp: timestamp(),
+            chatroom: inbound.chatroom,
+            content: msg.to_string(),
+            target: target_user.to_string(),
+            style: style as i32,
+            kind: Kind::DirectMessage as i32,
+            id: inbound.id,
+            ..Default::default()
+        }
+    }
+}
+
+async fn is_blocked(state: &ServerState, user: &str, sender: &str) -> bool {
+    let blocks = state.blocks.lock().await;
+    blocks
+        .get(user)
+        .is_some_and(|blocked| blocked.contains(sender))
+}
+
+async fn build_block_response(
+    blocked: &str,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    inbound.target = std::mem::replace(&mut inbound.sender, String::from("server"));
+    inbound.kind = Kind::System as i32;
+    let added = blocked != inbound.target
+        && state
+            .blocks
+            .lock()
+            .await
+            .entry(inbound.target.clone())
+            .or_default()
+            .insert(blocked.to_string());
+    (inbound.content, inbound.style) = if blocked == inbound.target {
+        (
+            String::from("You can't block yourself."),
+            Style::Error as i32,
+        )
+    } else if added {
+        (
+            format!(
+                "-- Blocked {}, their direct messages and whispers won't reach you",
+                blocked
+            ),
+            Style::Notice as i32,
+        )
+    } else {
+        (
+            format!("{} is already blocked.", blocked),
+            Style::Error as i32,
+        )
+    };
+    inbound
+}
+
+async fn build_unblock_response(
+    blocked: &str,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    inbound.target = std::mem::replace(&mut inbound.sender, String::from("server"));
+    inbound.kind = Kind::System as i32;
+    let removed = {
+        let mut blocks = state.blocks.lock().await;
+        let removed = blocks
+            .get_mut(&inbound.target)
+            .is_some_and(|blocked_users| blocked_users.remove(blocked));
+        blocks.retain(|_, blocked_users| !blocked_users.is_empty());
+        removed
+    };
+    (inbound.content, inbound.style) = if removed {
+        (format!("-- Unblocked {}", blocked), Style::Notice as i32)
+    } else {
+        (format!("{} isn't blocked.", blocked), Style::Error as i32)
+    };
+    inbound
+}
+
+async fn queue_offline_message(state: &ServerState, message: ChatMessage) {
+    let mut guard = state.offline_dms.lock().await;
+    let queue = guard.entry(message.target.clone()).or_default();
+    if queue.len() >= MAX_QUEUED_DMS {
+        queue.remove(0);
+    }
+    queue.push(message);
+}
+
+/// The name a room is known by. Room names are case-insensitive, so `Rust` and `rust ` don't
+/// split users into two rooms.
+pub fn normalize_room_name(room: &str) -> Result<String, String> {
+    let room = room.trim();
+    if room.is_empty() {
+        Err(String::from("Room names can't be empty."))
+    } else if room.chars().any(char::is_control) {
+        Err(String::from("Room names can't contain control characters."))
+    } else if room.chars().count() > MAX_ROOM_NAME_CHARS {
+        Err(format!(
+            "Room names can be at most {} characters long.",
+            MAX_ROOM_NAME_CHARS
+        ))
+    } else {
+        Ok(room.to_lowercase())
+    }
+}
+
+async fn build_user_connection_response(
+    new_room: &str,
+    password: Option<JoinPassword>,
+    users: &mut HashMap<String, String>,
+    inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let new_room = match normalize_room_name(new_room) {
+        Ok(room) => room,
+        Err(reason) => {
+            return ChatMessage {
+                sender: "server".to_string(),
+                timestamp: timestamp(),
+                chatroom: inbound.chatroom,
+                content: reason,
+                target: inbound.sender,
+                style: Style::Error as i32,
+                kind: Kind::System as i32,
+                ..Default::default()
+            };
+        }
+    };
+    let new_room = new_room.as_str();
+    let occupants = users
+        .iter()
+        .filter(|(user, room)| *room == new_room && **user != inbound.sender)
+        .count();
+    let max_users = state.config.max_room_users;
+    if occupants >= max_users {
+        return ChatMessage {
+            sender: "server".to_string(),
+            timestamp: timestamp(),
+            chatroom: inbound.chatroom,
+            content: format!(
+                "{} is full, it has the maximum of {} users.",
+                new_room, max_users
+            ),
+            target: inbound.sender,
+            style: Style::Error as i32,
+            kind: Kind::System as i32,
+            ..Default::default()
+        };
+    }
+    if let Err(reason) = check_room_password(new_room, password, users, state).await {
+        return ChatMessage {
+            sender: "server".to_string(),
+            timestamp: timestamp(),
+            chatroom: inbound.chatroom,
+            content: reason,
+            target: inbound.sender,
+            style: Style::Error as i32,
+            kind: Kind::System as i32,
+            ..Default::default()
+        };
+    }
+    // rooms only exist while someone is in them, so joining an empty one creates it
+    let is_new_room = !users.values().any(|room| room == new_room);
+    if is_new_room {
+        let mut moderators = state.moderators.lock().await;
+        moderators.insert(new_room.to_string(), inbound.sender.clone());
+    }
+    let previous_room = users.insert(inbound.sender.to_string(), new_room.to_string());
+    if let Some(previous_room) = previous_room.filter(|r| !r.is_empty() && r != new_room) {
+        forget_room_if_empty(&previous_room, users, state).await;
+        state.router.send(ChatMessage {
+            sender: "server".to_string(),
+            timestamp: timestamp(),
+            content: format!("-- {} has left {}", inbound.sender, previous_room),
+            chatroom: previous_room,
+            target: String::new(),
+            style: Style::Notice as i32,
+            kind: Kind::Leave as i32,
+            ..Default::default()
+        });
+    }
+    let mut content = format!("-- {} has joined {}", inbound.sender, new_room);
+    match occupants {
+        _ if is_new_room => content.push_str(", a new room"),
+        // joining the room again while alone in it
+        0 => {}
+        1 => content.push_str(", 1 user was already here"),
+        occupants => content.push_str(&format!(", {} users were already here", occupants)),
+    }
+    if let Some(topic) = state.topics.lock().await.get(new_room) {
+        content = format!("{}\n-- Topic: {}", content, topic);
+    }
+    ChatMessage {
+        sender: "server".to_string(),
+        timestamp: timestamp(),
+        chatroom: new_room.to_string(),
+        content,
+        target: String::new(),
+        style: Style::Notice as i32,
+        kind: Kind::Join as i32,
+        ..Default::default()
+    }
+}
+
+async fn build_kick_response(
+    kicked: &str,
+    users: &mut HashMap<String, String>,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let moderator = state
+        .moderators
+        .lock()
+        .await
+        .get(&inbound.chatroom)
+        .cloned();
+    // admins may kick users from any room
+    let error = if moderator.as_ref() != Some(&inbound.sender) && !state.is_admin(&inbound.sender) {
+        Some(format!(
+            "Only the moderator of {} or an admin can kick users.",
+            inbound.chatroom
+        ))
+    } else if kicked == inbound.sender {
+        Some(String::from("You can't kick yourself."))
+    } else if users.get(kicked) != Some(&inbound.chatroom) {
+        Some(format!("No user named {} is in this room.", kicked))
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        inbound.target = inbound.sender;
+        inbound.sender = String::from("server");
+        inbound.kind = Kind::System as i32;
+        inbound.content = error;
+        inbound.style = Style::Error as i32;
+        return inbound;
+    }
+
+    // keep the name reserved but move the user back to the lobby
+    users.insert(kicked.to_string(), String::new());
+    forget_room_if_empty(&inbound.chatroom, users, state).await;
+    state.router.send(ChatMessage {
+        sender: "server".to_string(),
+        timestamp: timestamp(),
+        chatroom: String::new(),
+        content: format!(
+            "You were kicked from {} by {}. Type {}join <roomname> to enter a room.",
+            inbound.chatroom, inbound.sender, state.config.command_prefix
+        ),
+        target: kicked.to_string(),
+        style: Style::Error as i32,
+        kind: Kind::System as i32,
+        ..Default::default()
+    });
+    ChatMessage {
+        sender: "server".to_string(),
+        timestamp: timestamp(),
+        chatroom: inbound.chatroom,
+        content: format!("-- {} was kicked by {}", kicked, inbound.sender),
+        target: String::new(),
+        style: Style::Notice as i32,
+        kind: Kind::Leave as i32,
+        ..Default::default()
+    }
+}
+
+/// Drops the password and moderator of a room nobody is in anymore, joining it again creates it
+/// anew. What was said in a protected room goes as well, the next one to create it may not
+/// know the password.
+pub async fn forget_room_if_empty(
+    room: &str,
+    users: &HashMap<String, String>,
+    state: &ServerState,
+) {
+    if room.is_empty() || users.values().any(|r| r == room) {
+        return;
+    }
+    state.moderators.lock().await.remove(room);
+    if state.room_passwords.lock().await.remove(room).is_none() {
+        return;
+    }
+    let said = state.room_history.lock().await.remove(room);
+    let mut reactions = state.reactions.lock().await;
+    for message in said.into_iter().flatten() {
+        reactions.remove(&message.id);
+    }
+    state.topics.lock().await.remove(room);
+    if let Some(store) = state.history_store.as_ref() {
+        if let Err(e) = store.close_room(room) {
+            error!(error = %e, %room, "couldn't close the room's history");
+        }
+    }
+}
+
+/// The password given with `!join`, hashed before the users map is locked for the join.
+enum JoinPassword {
+    /// Whether it matched the password the room had then.
+    Checked {
+        against: RoomPassword,
+        matches: bool,
+    },
+    /// Hashed for creating the room with, which had no password then. `None` if hashing failed.
+    New(Option<RoomPassword>),
+}
+
+async fn hash_join_password(room: &str, password: String, state: &ServerState) -> JoinPassword {
+    let stored = state.room_passwords.lock().await.get(room).cloned();
+    tokio::task::spawn_blocking(move || match stored {
+        Some(stored) => JoinPassword::Checked {
+            matches: stored.matches(&password),
+            against: stored,
+        },
+        None => JoinPassword::New(RoomPassword::new(&password)),
+    })
+    .await
+    .unwrap_or(JoinPassword::New(None))
+}
+
+/// Checks the password given for a join, registering it if this join creates a protected room.
+async fn check_room_password(
+    room: &str,
+    password: Option<JoinPassword>,
+    users: &HashMap<String, String>,
+    state: &ServerState,
+) -> Result<(), String> {
+    let prefix = state.config.command_prefix;
+    let mut passwords = state.room_passwords.lock().await;
+    match (passwords.get(room), password) {
+        (Some(stored), Some(JoinPassword::Checked { against, matches })) if against == *stored => {
+            if matches {
+                Ok(())
+            } else {
+                Err(format!("Wrong password for {}.", room))
+            }
+        }
+        // the room was emptied or created while the password was hashed
+        (Some(_), Some(_)) | (None, Some(JoinPassword::Checked { .. })) => {
+            Err(format!("{} changed while you joined, try again.", room))
+        }
+        (Some(_), None) => Err(format!(
+            "{} is password protected, use {}join {} <password>.",
+            room, prefix, room
+        )),
+        (None, Some(_)) if users.values().any(|r| r == room) => Err(format!(
+            "{} already exists without a password, use {}join {}.",
+            room, prefix, room
+        )),
+        (None, Some(JoinPassword::New(hashed))) => {
+            let hashed =
+                hashed.ok_or_else(|| String::from("Couldn't set the room password, try again."))?;
+            passwords.insert(room.to_string(), hashed);
+            Ok(())
+        }
+        (None, None) => Ok(()),
+    }
+}
+
+async fn build_topic_response(
+    new_topic: Option<String>,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let mut topics = state.topics.lock().await;
+    let author = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    match new_topic {
+        None => {
+            inbound.target = author;
+            (inbound.content, inbound.style) = match topics.get(&inbound.chatroom) {
+                Some(topic) => (
+                    format!("-- Topic of {}: {}", inbound.chatroom, topic),
+                    Style::Highlight as i32,
+                ),
+                None => (
+                    format!("-- {} has no topic set.", inbound.chatroom),
+                    Style::Notice as i32,
+                ),
+            };
+        }
+        Some(new_topic) if new_topic.chars().count() > MAX_TOPIC_CHARS => {
+            inbound.target = author;
+            inbound.content = format!("Topics can be at most {} characters long.", MAX_TOPIC_CHARS);
+            inbound.style = Style::Error as i32;
+        }
+        Some(new_topic) => {
+            inbound.content = format!("-- {} set the topic to: {}", author, new_topic);
+            inbound.style = Style::Highlight as i32;
+            topics.insert(inbound.chatroom.clone(), new_topic);
+        }
+    }
+    inbound
+}
+
+/// Sets or, without a status or with [`ONLINE`], clears the sender's status. Control characters
+/// are dropped so a status can't mess with the terminals of everyone listing users.
+async fn build_status_response(
+    status: Option<String>,
+    mut inbound: ChatMessage,
+    state: &ServerState,
+) -> ChatMessage {
+    let user = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    let status: String = status
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    let status = status.trim();
+    if status.chars().count() > MAX_STATUS_CHARS {
+        inbound.target = user;
+        inbound.content = format!(
+            "Statuses can be at most {} characters long.",
+            MAX_STATUS_CHARS
+        );
+        inbound.style = Style::Error as i32;
+        return inbound;
+    }
+    let mut statuses = state.statuses.lock().await;
+    if status.is_empty() || status.eq_ignore_ascii_case(ONLINE) {
+        statuses.remove(&user);
+        inbound.content = format!("-- {} is now {}", user, ONLINE);
+    } else {
+        inbound.content = format!("-- {} is now {}", user, status);
+        statuses.insert(user, status.to_string());
+    }
+    inbound.style = Style::Notice as i32;
+    inbound
+}
+
+/// Lists one page of the users in the room, sorted by name.
+fn build_user_command_response(
+    page: usize,
+    mut inbound: ChatMessage,
+    users: &HashMap<String, String>,
+    statuses: &HashMap<String, String>,
+    prefix: char,
+) -> ChatMessage {
+    inbound.target = inbound.sender.clone();
+    inbound.sender = String::from("server");
+    inbound.kind = Kind::System as i32;
+    let mut users_in_room = users
+        .iter()
+        .filter(|e| e.1 == inbound.chatroom.as_str())
+        .map(|(user, _)| user)
+        .collect::<Vec<_>>();
+    users_in_room.sort();
+    let total = users_in_room.len();
+    let pages = total.div_ceil(USERS_PER_PAGE).max(1);
+    if page > pages {
+        inbound.content = format!(
+            "There's no page {}, the users in {} fit on {}.",
+            page,
+            inbound.chatroom,
+            count(pages, "page")
+        );
+        inbound.style = Style::Error as i32;
+        return inbound;
+    }
+    let start = (page - 1) * USERS_PER_PAGE;
+    let listed = users_in_room
+        .iter()
+        .skip(start)
+        .take(USERS_PER_PAGE)
+        .map(|user| match statuses.get(*user) {
+            Some(status) => format!("{} ({})", user, status),
+            None => user.to_string(),
+        })
+        .collect::<Vec<String>>();
+    let more = total - start - listed.len();
+    inbound.content = format!(
+        "-- {} in {}: {}",
+        count(total, "user"),
+        inbound.chatroom,
+        listed.join(", ")
+    );
+    if more > 0 {
+        inbound.content += &format!(
+            " and {} more, {}user {} for the next page",
+            more,
+            prefix,
+            page + 1
+        );
+    }
+    inbound.style = Style::Highlight as i32;
+    inbound
+}
+
+fn timestamp() -> i64 {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time broke..");
+    time.as_millis() as i64
+}
+
+/// GETs `url`, retrying timeouts, failed connections and server errors. Client errors are
+/// returned right away, as is whatever the last attempt ended with.
+async fn get_with_retry(client: &reqwest::Client, url: &str) -> Result<Response, Error> {
+    let mut delay = API_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let result = client.get(url).timeout(API_ATTEMPT_TIMEOUT).send().await;
+        let transient = match &result {
+            Ok(response) => response.status().is_server_error(),
+            Err(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+        };
+        if !transient || attempt == API_ATTEMPTS {
+            return result;
+        }
+        debug!(attempt, url, "retrying failed request");
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+async fn get_currency_conversion(client: &reqwest::Client, url: String) -> Result<Response, Error> {
+    get_with_retry(client, &url).await
+}
+
+/// How long `sender` has to wait before Binance may be asked for them, either because it
+/// rate limited the server or they used `!value` too recently. Records the request if they
+/// don't have to wait.
+async fn value_throttle(state: &ServerState, sender: &str) -> Option<Duration> {
+    let now = Instant::now();
+    if let Some(until) = *state.binance_backoff.lock().await {
+        if until > now {
+            return Some(until - now);
+        }
+    }
+    let mut requests = state.value_requests.lock().await;
+    let cooldown = state.config.value_cooldown;
+    requests.retain(|_, last| now.duration_since(*last) < cooldown);
+    if let Some(last) = requests.get(sender) {
+        return Some(cooldown - now.duration_since(*last));
+    }
+    requests.insert(sender.to_string(), now);
+    None
+}
+
+/// The wait a 429 response asks for in its `Retry-After` header, given in seconds by Binance.
+fn retry_after(headers: &HeaderMap) -> Duration {
+    headers
+        .get("Retry-After")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RATE_LIMIT_BACKOFF)
+}
+
+fn parse_conversion(body: &str) -> Conversion {
+    match serde_json::from_str(body) {
+        Ok(BinanceReply::Price(currency)) => currency
+            .price
+            .parse()
+            .map_or(Conversion::Invalid, Conversion::Price),
+        Ok(BinanceReply::Error(error)) if error.code == INVALID_SYMBOL => Conversion::UnknownSymbol,
+        Ok(BinanceReply::Error(error)) => {
+            warn!(code = error.code, msg = %error.msg, "binance rejected the request");
+            Conversion::Invalid
+        }
+        Err(_) => Conversion::Invalid,
+    }
+}
+
+/// The price change of a symbol over the last 24 hours in percent, `None` if Binance
+/// doesn't provide it.
+async fn get_price_change(client: &reqwest::Client, url: String) -> Option<f64> {
+    let response = get_with_retry(client, &url)
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    let change: PriceChange = serde_json::from_str(&response.text().await.ok()?).ok()?;
+    change.price_change_percent.parse().ok()
+}
+
+async fn get_top_hn(
+    client: &reqwest::Client,
+    api: &str,
+    feed: &str,
+    count: usize,
+) -> Result<Vec<HNStory>, Error> {
+    let url = format!("{}/v0/{}.json?print=pretty", api, feed);
+    let response = get_with_retry(client, &url).await?;
+    let response = response.text().await?;
+
+    let ids = response
+        .trim_start_matches("[ ")
         .trim_end_matches(" ]")
         .split(',')
         .map(|id| id.trim().to_string())
-        .take(10)
+        .take(count)
         .collect::<Vec<String>>();
 
     let responses = stream::iter(ids)
-    .map(|id| async move { get_story_info(&id).await })
-    .buffer_unordered(10)
-    .collect::<Vec<Result<HNStory, reqwest::Error>>>()
-    .await;
+        .map(|id| async move { get_story_info(client, api, &id).await })
+        .buffer_unordered(10)
+        .collect::<Vec<_>>()
+        .await;
 
-    Ok(responses.into_iter().filter_map(Result::ok).collect())
+    // stories that couldn't be fetched or were taken down since are left out
+    Ok(responses
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|story| !story.deleted && !story.dead)
+        .collect())
 }
 
-async fn get_story_info(id: &str) -> Result<HNStory, Error> {
-    let story_url = format!(
-        "https://hacker-news.firebaseio.com/v0/item/{}.json?print=pretty",
-        id
-    );
-    let story_response = reqwest::get(story_url).await?;
+async fn get_story_info(
+    client: &reqwest::Client,
+    api: &str,
+    id: &str,
+) -> Result<HNStory, Box<dyn std::error::Error + Send + Sync>> {
+    let story_url = format!("{}/v0/item/{}.json?print=pretty", api, id);
+    let story_response = get_with_retry(client, &story_url).await?;
     let story_data = story_response.text().await?;
-    let parsed_story: HNStory = serde_json::from_str(&story_data).unwrap();
-    Ok(parsed_story)
+    // the API answers `null` for items that don't exist, which fails to parse like any other
+    // unexpected reply
+    Ok(serde_json::from_str(&story_data)?)
+}
+
+/// The best match for `name`, `None` if there is none.
+async fn find_place(
+    client: &reqwest::Client,
+    api: &str,
+    name: &str,
+) -> Result<Option<Place>, Box<dyn std::error::Error + Send + Sync>> {
+    let url = reqwest::Url::parse_with_params(
+        &format!("{}/v1/search", api),
+        &[("name", name), ("count", "1")],
+    )
+    .expect("the geocoding API base is a valid URL");
+    let response = get_with_retry(client, url.as_str())
+        .await?
+        .error_for_status()?;
+    let results: GeocodingResults = serde_json::from_str(&response.text().await?)?;
+    Ok(results.results.and_then(|places| places.into_iter().next()))
+}
+
+async fn get_joke(
+    client: &reqwest::Client,
+    api: &str,
+) -> Result<Joke, Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!("{}/random_joke", api);
+    let response = get_with_retry(client, &url).await?.error_for_status()?;
+    Ok(serde_json::from_str(&response.text().await?)?)
+}
+
+/// The crate called `name`, `None` if there's none.
+async fn get_crate(
+    client: &reqwest::Client,
+    api: &str,
+    name: &str,
+) -> Result<Option<Crate>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut url = reqwest::Url::parse(&format!("{}/api/v1/crates/", api))
+        .expect("the crates.io API base is a valid URL");
+    url.path_segments_mut()
+        .expect("the crates.io API base is a valid URL")
+        .pop_if_empty()
+        .push(name);
+    let response = get_with_retry(client, url.as_str()).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let response = response.error_for_status()?;
+    let response: CrateResponse = serde_json::from_str(&response.text().await?)?;
+    Ok(Some(response.krate))
+}
+
+/// The summary of the article on `topic`, `None` if Wikipedia has none.
+async fn get_summary(
+    client: &reqwest::Client,
+    api: &str,
+    topic: &str,
+) -> Result<Option<Summary>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut url = reqwest::Url::parse(&format!("{}/api/rest_v1/page/summary/", api))
+        .expect("the Wikipedia API base is a valid URL");
+    // titles use underscores for spaces, the segment is percent-encoded
+    url.path_segments_mut()
+        .expect("the Wikipedia API base is a valid URL")
+        .pop_if_empty()
+        .push(&topic.replace(' ', "_"));
+    let response = get_with_retry(client, url.as_str()).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let response = response.error_for_status()?;
+    Ok(Some(serde_json::from_str(&response.text().await?)?))
+}
+
+async fn get_current_weather(
+    client: &reqwest::Client,
+    api: &str,
+    place: &Place,
+) -> Result<CurrentWeather, Box<dyn std::error::Error + Send + Sync>> {
+    let url = format!(
+        "{}/v1/forecast?latitude={}&longitude={}&current_weather=true",
+        api, place.latitude, place.longitude
+    );
+    let response = get_with_retry(client, &url).await?.error_for_status()?;
+    let forecast: Forecast = serde_json::from_str(&response.text().await?)?;
+    Ok(forecast.current_weather)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Server};
+    use prost::Message;
+
+    use super::super::state::Config;
+    use super::super::word_filter::WordFilter;
+    use super::*;
+
+    /// Canned responses of the mocked APIs: path and query, status and body.
+    type Routes = &'static [(&'static str, u16, &'static str)];
+
+    const PRICE: Routes = &[
+        (
+            "/api/v3/ticker/price?symbol=BTCEUR",
+            200,
+            r#"{"symbol":"BTCEUR","price":"25431.17000000"}"#,
+        ),
+        (
+            "/api/v3/ticker/24hr?symbol=BTCEUR",
+            200,
+            r#"{"symbol":"BTCEUR","priceChangePercent":"2.314"}"#,
+        ),
+        (
+            "/api/v3/ticker/price?symbol=FOOEUR",
+            400,
+            r#"{"code":-1121,"msg":"Invalid symbol."}"#,
+        ),
+    ];
+
+    const NEWS: Routes = &[
+        ("/v0/topstories.json?print=pretty", 200, "[ 1, 2, 3, 4, 5 ]"),
+        (
+            "/v0/item/1.json?print=pretty",
+            200,
+            r#"{"by":"pg","score":120,"time":1,"title":"First","url":"https://one.example"}"#,
+        ),
+        (
+            "/v0/item/2.json?print=pretty",
+            200,
+            r#"{"by":"dang","score":80,"time":2,"title":"Second"}"#,
+        ),
+        (
+            "/v0/item/3.json?print=pretty",
+            200,
+            r#"{"deleted":true,"id":3,"time":3,"type":"story"}"#,
+        ),
+        (
+            "/v0/item/4.json?print=pretty",
+            200,
+            r#"{"by":"spam","dead":true,"id":4,"time":4,"title":"Dead"}"#,
+        ),
+        ("/v0/item/5.json?print=pretty", 200, "null"),
+    ];
+
+    const WEATHER: Routes = &[
+        (
+            "/v1/search?name=Berlin&count=1",
+            200,
+            r#"{"results":[{"name":"Berlin","country":"Germany","latitude":52.52,"longitude":13.41}]}"#,
+        ),
+        (
+            "/v1/search?name=Atlantis&count=1",
+            200,
+            r#"{"generationtime_ms":0.5}"#,
+        ),
+        (
+            "/v1/forecast?latitude=52.52&longitude=13.41&current_weather=true",
+            200,
+            r#"{"current_weather":{"temperature":12.34,"windspeed":5.6,"weathercode":3}}"#,
+        ),
+    ];
+
+    /// Serves `routes` on an ephemeral port, anything else is a 404. Returns the base URL.
+    async fn mock_api(routes: Routes) -> String {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+                let path = request.uri().path_and_query().map(|p| p.as_str());
+                let (status, body) = routes
+                    .iter()
+                    .find(|(route, _, _)| Some(*route) == path)
+                    .map_or((404, ""), |(_, status, body)| (*status, *body));
+                hyper::Response::builder()
+                    .status(status)
+                    .body(Body::from(body))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    fn state(api: &str) -> Arc<ServerState> {
+        Arc::new(ServerState::new(Config {
+            binance_api: api.to_string(),
+            hacker_news_api: api.to_string(),
+            geocoding_api: api.to_string(),
+            weather_api: api.to_string(),
+            jokes_api: api.to_string(),
+            wikipedia_api: api.to_string(),
+            crates_api: api.to_string(),
+            ..Config::default()
+        }))
+    }
+
+    async fn state_with_users(users: &[(&str, &str)]) -> Arc<ServerState> {
+        let state = state("http://127.0.0.1:9");
+        for (user, room) in users {
+            let mut map = state.users.write().await;
+            map.insert(user.to_string(), room.to_string());
+        }
+        state
+    }
+
+    fn message(sender: &str, chatroom: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            sender: sender.to_string(),
+            chatroom: chatroom.to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn asks_users_in_the_lobby_to_join() {
+        let state = state_with_users(&[("alice", "")]).await;
+        let response = ChatMessage::into_response(message("alice", "", "hi"), &state).await;
+        assert_eq!(response.sender, "server");
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.chatroom, "");
+        assert_eq!(response.content, "Type !join <roomname> to enter a room.");
+    }
+
+    #[tokio::test]
+    async fn replies_quote_recent_messages() {
+        let state = state_with_users(&[("alice", "rust"), ("bob", "rust")]).await;
+        let quoted = ChatMessage {
+            id: "abc123".to_string(),
+            ..message(
+                "bob",
+                "rust",
+                "anyone still on tokio 0.2? asking for a very old project",
+            )
+        };
+        state
+            .room_history
+            .lock()
+            .await
+            .entry("rust".to_string())
+            .or_default()
+            .push_back(quoted);
+        let response = ChatMessage::into_response(
+            message("alice", "rust", "!reply abc123 sadly, yes"),
+            &state,
+        )
+        .await;
+        assert_eq!(response.sender, "alice");
+        assert_eq!(response.target, "");
+        assert_eq!(response.content, "sadly, yes");
+        assert_eq!(
+            response.reply_to,
+            Some(Quote {
+                id: "abc123".to_string(),
+                sender: "bob".to_string(),
+                content: "anyone still on tokio 0.2? asking for a very old project".to_string(),
+            })
+        );
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!reply zzz999 what?"), &state)
+                .await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(
+            response.content,
+            "There's no recent message zzz999 in this room."
+        );
+        assert_eq!(response.reply_to, None);
+    }
+
+    #[test]
+    fn generates_short_message_ids() {
+        let id = message_id();
+        assert_eq!(id.len(), MESSAGE_ID_LENGTH);
+        assert!(id.bytes().all(|c| MESSAGE_ID_CHARS.contains(&c)));
+        assert_ne!(message_id(), message_id());
+    }
+
+    #[test]
+    fn replaces_what_clients_make_up() {
+        let mut inbound = message("alice", "rust", "hi");
+        // a day in the future, posing as the server
+        inbound.timestamp = timestamp() + 24 * 60 * 60 * 1000;
+        inbound.style = Style::Error as i32;
+        inbound.kind = Kind::System as i32;
+        inbound.id = String::from("fake01");
+        let before = timestamp();
+        stamp_inbound(&mut inbound);
+        assert!((before..=timestamp()).contains(&inbound.timestamp));
+        assert_eq!(inbound.style(), Style::Plain);
+        assert_eq!(inbound.kind(), Kind::Chat);
+        assert_ne!(inbound.id, "fake01");
+    }
+
+    #[test]
+    fn strips_escape_sequences_and_control_characters() {
+        assert_eq!(strip_control("hi\x1B[2J\x1B[1;1Hthere"), "hithere");
+        assert_eq!(strip_control("\x1B[31mred\x1B[0m\x07\r"), "red");
+        assert_eq!(strip_control("\x1B]0;owned\x07title\x1Bc"), "title");
+        assert_eq!(strip_control("\u{9B}2J"), "2J");
+        assert_eq!(strip_control("fn a() {\n\tb()\n}"), "fn a() {\n\tb()\n}");
+    }
+
+    #[tokio::test]
+    async fn edits_own_recent_messages() {
+        let state = state_with_users(&[("alice", "rust"), ("bob", "rust")]).await;
+        let mut history = VecDeque::new();
+        for (id, sender, content) in [("a1", "alice", "helo"), ("b1", "bob", "hi")] {
+            history.push_back(ChatMessage {
+                id: id.to_string(),
+                kind: Kind::Chat as i32,
+                ..message(sender, "rust", content)
+            });
+        }
+        state
+            .room_history
+            .lock()
+            .await
+            .insert("rust".to_string(), history);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!edit a1 hello"), &state).await;
+        assert_eq!(response.kind(), Kind::Edit);
+        assert_eq!(response.id, "a1");
+        assert_eq!(response.sender, "alice");
+        assert_eq!(response.content, "hello");
+        assert!(response.edited);
+        let history = state.room_history.lock().await;
+        assert_eq!(history["rust"][0].content, "hello");
+        assert!(history["rust"][0].edited);
+        drop(history);
+
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!edit b1 bye"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.content, "You can only edit your own messages.");
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!edit zz9 what"), &state).await;
+        assert_eq!(
+            response.content,
+            "There's no recent message zz9 in this room."
+        );
+    }
+
+    #[tokio::test]
+    async fn deletes_own_messages_or_any_as_admin() {
+        let config = Config {
+            admins: vec!["root".to_string()],
+            ..Config::default()
+        };
+        let state = Arc::new(ServerState::new(config));
+        let mut history = VecDeque::new();
+        for (id, sender) in [("a1", "alice"), ("b1", "bob"), ("b2", "bob")] {
+            history.push_back(ChatMessage {
+                id: id.to_string(),
+                ..message(sender, "rust", "hi")
+            });
+        }
+        state
+            .room_history
+            .lock()
+            .await
+            .insert("rust".to_string(), history);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!delete b1"), &state).await;
+        assert_eq!(response.content, "You can only delete your own messages.");
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!delete a1"), &state).await;
+        assert_eq!(response.kind(), Kind::Delete);
+        assert_eq!(response.id, "a1");
+        assert_eq!(response.sender, "alice");
+        assert_eq!(response.chatroom, "rust");
+        let response =
+            ChatMessage::into_response(message("root", "rust", "!delete b1"), &state).await;
+        assert_eq!(response.kind(), Kind::Delete);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!delete a1"), &state).await;
+        assert_eq!(
+            response.content,
+            "There's no recent message a1 in this room."
+        );
+        let history = state.room_history.lock().await;
+        let ids: Vec<_> = history["rust"].iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["b2"]);
+    }
+
+    #[tokio::test]
+    async fn toggles_reactions_per_user() {
+        let state = state_with_users(&[("alice", "rust"), ("bob", "rust")]).await;
+        let history = VecDeque::from([ChatMessage {
+            id: "a1".to_string(),
+            ..message("alice", "rust", "hi")
+        }]);
+        state
+            .room_history
+            .lock()
+            .await
+            .insert("rust".to_string(), history);
+        let react = |sender: &'static str, content: &'static str| {
+            ChatMessage::into_response(message(sender, "rust", content), &state)
+        };
+        react("alice", "!react a1 👍").await;
+        react("bob", "!react a1 🎉").await;
+        let response = react("bob", "!react a1 👍").await;
+        assert_eq!(response.kind(), Kind::Reaction);
+        assert_eq!(response.id, "a1");
+        assert_eq!(response.content, "👍");
+        let reactions: Vec<_> = response
+            .reactions
+            .iter()
+            .map(|reaction| (reaction.emoji.as_str(), reaction.users.len()))
+            .collect();
+        assert_eq!(reactions, [("👍", 2), ("🎉", 1)]);
+        let response = react("bob", "!react a1 🎉").await;
+        assert_eq!(response.reactions.len(), 1);
+        assert_eq!(response.reactions[0].users, ["alice", "bob"]);
+
+        let response = react("bob", "!react a1 yes").await;
+        assert_eq!(
+            response.content,
+            "yes isn't an emoji, react with one like 👍."
+        );
+        let response = react("bob", "!react zz9 👍").await;
+        assert_eq!(
+            response.content,
+            "There's no recent message zz9 in this room."
+        );
+    }
+
+    #[test]
+    fn caps_the_emojis_per_message() {
+        let mut reactions = Vec::new();
+        for emoji in ["😀", "😁", "😂", "🤣", "😃", "😄", "😅", "😆", "😉", "😊"]
+        {
+            assert!(toggle_reaction(&mut reactions, emoji, "alice"));
+        }
+        assert!(!toggle_reaction(&mut reactions, "🙃", "bob"));
+        assert!(toggle_reaction(&mut reactions, "😀", "bob"));
+        assert!(toggle_reaction(&mut reactions, "😁", "alice"));
+        assert!(toggle_reaction(&mut reactions, "🙃", "bob"));
+        assert_eq!(reactions.len(), MAX_REACTIONS);
+    }
+
+    #[tokio::test]
+    async fn rejects_messages_over_the_length_limit() {
+        let config = Config {
+            max_message_chars: 5,
+            ..Config::default()
+        };
+        let state = Arc::new(ServerState::new(config));
+        // five characters, but more bytes than the limit
+        let response = ChatMessage::into_response(message("alice", "rust", "ééééé"), &state).await;
+        assert_eq!(response.content, "ééééé");
+        for content in ["toolong", "!roll 1d6 please", &"x".repeat(1 << 20)] {
+            let response =
+                ChatMessage::into_response(message("alice", "rust", content), &state).await;
+            assert_eq!(response.sender, "server");
+            assert_eq!(response.target, "alice");
+            assert_eq!(response.style(), Style::Error);
+            assert_eq!(
+                response.content,
+                "Message not sent, it exceeds the limit of 5 characters."
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_every_line_of_a_message() {
+        let config = Config {
+            max_message_chars: 12,
+            ..Config::default()
+        };
+        let state = Arc::new(ServerState::new(config));
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "fn a() {\n}"), &state).await;
+        assert_eq!(response.content, "fn a() {\n}");
+        assert_eq!(response.sender, "alice");
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "fn a() {\n    b()\n}"), &state)
+                .await;
+        assert_eq!(
+            response.content,
+            "Message not sent, it exceeds the limit of 12 characters."
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_the_users_in_the_room() {
+        let users = [("alice", "rust"), ("bob", "rust"), ("carol", "go")];
+        let state = state_with_users(&users).await;
+        let response = ChatMessage::into_response(message("alice", "rust", "!user"), &state).await;
+        assert_eq!(response.sender, "server");
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.chatroom, "rust");
+        assert_eq!(response.content, "-- 2 users in rust: alice, bob");
+    }
+
+    #[tokio::test]
+    async fn pages_through_large_rooms() {
+        let names = (0..USERS_PER_PAGE + 5)
+            .map(|n| format!("user{:02}", n))
+            .collect::<Vec<_>>();
+        let users = names
+            .iter()
+            .map(|name| (name.as_str(), "rust"))
+            .collect::<Vec<_>>();
+        let state = state_with_users(&users).await;
+        let response = ChatMessage::into_response(message("user00", "rust", "!user"), &state).await;
+        let expected = format!(
+            "-- {} users in rust: {} and 5 more, !user 2 for the next page",
+            USERS_PER_PAGE + 5,
+            names[..USERS_PER_PAGE].join(", ")
+        );
+        assert_eq!(response.content, expected);
+        let response =
+            ChatMessage::into_response(message("user00", "rust", "!user 2"), &state).await;
+        let expected = format!(
+            "-- {} users in rust: {}",
+            USERS_PER_PAGE + 5,
+            names[USERS_PER_PAGE..].join(", ")
+        );
+        assert_eq!(response.content, expected);
+        let response =
+            ChatMessage::into_response(message("user00", "rust", "!user 3"), &state).await;
+        assert_eq!(
+            response.content,
+            "There's no page 3, the users in rust fit on 2 pages."
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_statuses_with_the_users() {
+        let state = state_with_users(&[("alice", "rust"), ("bob", "rust")]).await;
+        let response =
+            ChatMessage::into_response(message("bob", "rust", "!status  out\u{1b}[2J "), &state)
+                .await;
+        assert_eq!(response.target, "");
+        assert_eq!(response.content, "-- bob is now out[2J");
+        let response = ChatMessage::into_response(message("alice", "rust", "!user"), &state).await;
+        assert_eq!(response.content, "-- 2 users in rust: alice, bob (out[2J)");
+
+        ChatMessage::into_response(message("bob", "rust", "!status"), &state).await;
+        assert!(state.statuses.lock().await.is_empty());
+        let status = "z".repeat(MAX_STATUS_CHARS + 1);
+        let response = ChatMessage::into_response(
+            message("bob", "rust", &format!("!status {}", status)),
+            &state,
+        )
+        .await;
+        assert_eq!(response.target, "bob");
+        assert_eq!(response.style(), Style::Error);
+    }
+
+    #[tokio::test]
+    async fn announces_joins_to_the_room() {
+        let state = state_with_users(&[("alice", ""), ("bob", ""), ("carol", "")]).await;
+        let response = ChatMessage::into_response(message("alice", "", "!join rust"), &state).await;
+        assert_eq!(response.sender, "server");
+        assert_eq!(response.target, "");
+        assert_eq!(response.chatroom, "rust");
+        assert_eq!(response.content, "-- alice has joined rust, a new room");
+        assert_eq!(response.kind(), Kind::Join);
+        assert_eq!(state.users.read().await.get("alice").unwrap(), "rust");
+
+        let response = ChatMessage::into_response(message("bob", "", "!join rust"), &state).await;
+        assert_eq!(
+            response.content,
+            "-- bob has joined rust, 1 user was already here"
+        );
+        let response = ChatMessage::into_response(message("carol", "", "!join rust"), &state).await;
+        assert_eq!(
+            response.content,
+            "-- carol has joined rust, 2 users were already here"
+        );
+    }
+
+    #[tokio::test]
+    async fn emptied_rooms_lose_their_moderator() {
+        let state = Arc::new(ServerState::new(Config::default()));
+        for user in ["alice", "bob"] {
+            state
+                .users
+                .write()
+                .await
+                .insert(user.to_string(), String::new());
+            ChatMessage::into_response(message(user, "", "!join rust"), &state).await;
+        }
+        ChatMessage::into_response(message("alice", "rust", "!join go"), &state).await;
+        assert!(state.moderators.lock().await.contains_key("rust"));
+        ChatMessage::into_response(message("bob", "rust", "!join go"), &state).await;
+        let moderators = state.moderators.lock().await;
+        assert!(!moderators.contains_key("rust"));
+        assert_eq!(moderators["go"], "alice");
+    }
+
+    #[tokio::test]
+    async fn only_admins_use_privileged_commands() {
+        let state = Arc::new(ServerState::new(Config {
+            admins: vec![String::from("root")],
+            ..Config::default()
+        }));
+        // bob joins first and moderates rust
+        for (user, content) in [
+            ("bob", "!join rust"),
+            ("alice", "!join rust"),
+            ("root", "!join go"),
+        ] {
+            state
+                .users
+                .write()
+                .await
+                .insert(user.to_string(), String::new());
+            ChatMessage::into_response(message(user, "", content), &state).await;
+        }
+        let response =
+            ChatMessage::into_response(message("bob", "rust", "!announce hi"), &state).await;
+        assert_eq!(response.target, "bob");
+        assert_eq!(response.style(), Style::Error);
+        assert_eq!(
+            response.content,
+            "Permission denied, !announce is for admins only."
+        );
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!kick bob"), &state).await;
+        assert_eq!(
+            response.content,
+            "Only the moderator of rust or an admin can kick users."
+        );
+
+        let response =
+            ChatMessage::into_response(message("root", "go", "!announce hi"), &state).await;
+        assert_eq!(response.kind(), Kind::Announcement);
+        assert_eq!(response.content, "-- Announcement from root: hi");
+        let response =
+            ChatMessage::into_response(message("root", "rust", "!kick alice"), &state).await;
+        assert_eq!(response.content, "-- alice was kicked by root");
+    }
+
+    #[tokio::test]
+    async fn bans_and_unbans_names() {
+        let state = Arc::new(ServerState::new(Config {
+            admins: vec![String::from("root")],
+            ..Config::default()
+        }));
+        let ban = |content: &str| message("root", "rust", content);
+        let response = ChatMessage::into_response(ban("!ban mallory"), &state).await;
+        assert_eq!(response.target, "root");
+        assert_eq!(response.content, "-- mallory is banned");
+        assert!(state.bans.lock().await.contains("mallory"));
+        let response = ChatMessage::into_response(ban("!ban mallory"), &state).await;
+        assert_eq!(response.content, "mallory is already banned.");
+        let response = ChatMessage::into_response(ban("!ban root"), &state).await;
+        assert_eq!(response.content, "You can't ban yourself.");
+
+        let response = ChatMessage::into_response(ban("!unban mallory"), &state).await;
+        assert_eq!(response.content, "-- mallory is no longer banned");
+        let response = ChatMessage::into_response(ban("!unban mallory"), &state).await;
+        assert_eq!(response.content, "mallory isn't banned.");
+        assert!(!state.bans.lock().await.contains("mallory"));
+    }
+
+    #[tokio::test]
+    async fn tells_new_rooms_from_occupied_ones() {
+        let state = state_with_users(&[("alice", ""), ("bob", ""), ("carol", "")]).await;
+        let join = |user: &str, room: &str| {
+            let inbound = message(user, "", &format!("!join {}", room));
+            let state = state.clone();
+            async move { ChatMessage::into_response(inbound, &state).await.content }
+        };
+        assert_eq!(
+            join("alice", "rust").await,
+            "-- alice has joined rust, a new room"
+        );
+        assert_eq!(
+            join("bob", "rust").await,
+            "-- bob has joined rust, 1 user was already here"
+        );
+        assert_eq!(
+            join("alice", "go").await,
+            "-- alice has joined go, a new room"
+        );
+        assert_eq!(
+            join("bob", "go").await,
+            "-- bob has joined go, 1 user was already here"
+        );
+        // everyone left rust, so joining it creates it again
+        assert_eq!(
+            join("carol", "rust").await,
+            "-- carol has joined rust, a new room"
+        );
+    }
+
+    #[tokio::test]
+    async fn matches_commands_regardless_of_case() {
+        let state = state_with_users(&[("alice", "")]).await;
+        let response = ChatMessage::into_response(message("alice", "", "!JOIN rust"), &state).await;
+        assert_eq!(response.content, "-- alice has joined rust, a new room");
+        let response = ChatMessage::into_response(message("alice", "rust", "!User"), &state).await;
+        assert_eq!(response.content, "-- 1 user in rust: alice");
+    }
+
+    #[tokio::test]
+    async fn normalizes_room_names() {
+        let state = state_with_users(&[("alice", ""), ("bob", "")]).await;
+        let response = ChatMessage::into_response(message("alice", "", "!join Rust"), &state).await;
+        assert_eq!(response.chatroom, "rust");
+        assert_eq!(response.content, "-- alice has joined rust, a new room");
+        ChatMessage::into_response(message("bob", "", "!join RUST"), &state).await;
+        assert_eq!(state.users.read().await.get("bob").unwrap(), "rust");
+    }
+
+    #[test]
+    fn rejects_invalid_room_names() {
+        assert_eq!(normalize_room_name("rust \t").unwrap(), "rust");
+        assert!(normalize_room_name("  ").is_err());
+        assert!(normalize_room_name("ru\u{7}st").is_err());
+        assert!(normalize_room_name(&"r".repeat(MAX_ROOM_NAME_CHARS + 1)).is_err());
+        assert!(normalize_room_name(&"r".repeat(MAX_ROOM_NAME_CHARS)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn rolls_dice_for_the_room() {
+        let state = state_with_users(&[("alice", "rust")]).await;
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!roll 3d1+2"), &state).await;
+        assert_eq!(response.target, "");
+        assert_eq!(response.content, "-- alice rolled 3d1+2: [1, 1, 1] +2 = 5");
+        let response = ChatMessage::into_response(message("alice", "rust", "!roll"), &state).await;
+        let total: u32 = response
+            .content
+            .rsplit(' ')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=6).contains(&total));
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!roll 101d6"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.style(), Style::Error);
+    }
+
+    #[tokio::test]
+    async fn puts_the_prefix_only_in_what_the_server_wrote() {
+        let state = Arc::new(ServerState::new(Config {
+            command_prefix: '/',
+            ..Config::default()
+        }));
+        state
+            .users
+            .write()
+            .await
+            .insert(String::from("alice"), String::from("rust"));
+        let response = ChatMessage::into_response(
+            message("alice", "rust", "/8ball is !help a command?"),
+            &state,
+        )
+        .await;
+        assert!(response
+            .content
+            .starts_with("-- alice asked the 8-ball: is !help a command?\n"));
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "/topic try !help"), &state).await;
+        assert!(
+            response.content.contains("try !help"),
+            "{}",
+            response.content
+        );
+        let response = ChatMessage::into_response(message("alice", "rust", "/8ball"), &state).await;
+        assert_eq!(response.content, "Usage: /8ball <question>");
+        let response = ChatMessage::into_response(message("alice", "", "hi"), &state).await;
+        assert_eq!(response.content, "Type /join <roomname> to enter a room.");
+    }
+
+    #[tokio::test]
+    async fn answers_questions_with_the_8_ball() {
+        let state = state_with_users(&[("alice", "rust")]).await;
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!8ball will it compile?"), &state)
+                .await;
+        assert_eq!(response.target, "");
+        let (question, answer) = response.content.split_once('\n').unwrap();
+        assert_eq!(question, "-- alice asked the 8-ball: will it compile?");
+        assert!(EIGHT_BALL_ANSWERS.contains(&answer.strip_prefix("-- ").unwrap()));
+        let response = ChatMessage::into_response(message("alice", "rust", "!8ball"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.content, "Usage: !8ball <question>");
+    }
+
+    #[tokio::test]
+    async fn shows_the_weather_to_the_room() {
+        let state = state(&mock_api(WEATHER).await);
+        state
+            .users
+            .write()
+            .await
+            .insert("alice".to_string(), "rust".to_string());
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!weather Berlin"), &state).await;
+        assert_eq!(response.target, "");
+        assert_eq!(
+            response.content,
+            "-- Weather in Berlin, Germany: 12.3°C, overcast, wind 6 km/h"
+        );
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!weather 52.52, 13.41"), &state)
+                .await;
+        assert_eq!(
+            response.content,
+            "-- Weather in 52.52, 13.41: 12.3°C, overcast, wind 6 km/h"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_places_and_failures() {
+        let state = state(&mock_api(WEATHER).await);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!weather Atlantis"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.content, "Couldn't find a place called Atlantis.");
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!weather Paris"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.content, "Couldn't retrieve the weather for Paris.");
+    }
+
+    #[tokio::test]
+    async fn tells_jokes_to_the_room() {
+        let routes: Routes = &[(
+            "/random_joke",
+            200,
+            r#"{"type":"programming","setup":"Why do programmers wear glasses?","punchline":"They can't C#.","id":1}"#,
+        )];
+        let state = state(&mock_api(routes).await);
+        let response = ChatMessage::into_response(message("alice", "rust", "!joke"), &state).await;
+        assert_eq!(response.target, "");
+        assert_eq!(
+            response.content,
+            "-- Why do programmers wear glasses?\n-- They can't C#."
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_failing_joke_requests() {
+        let state = state(&mock_api(&[]).await);
+        let response = ChatMessage::into_response(message("alice", "rust", "!joke"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.style(), Style::Error);
+    }
+
+    const WIKI: Routes = &[
+        (
+            "/api/rest_v1/page/summary/Rust_(programming_language)",
+            200,
+            r#"{"type":"standard","title":"Rust (programming language)","extract":"Rust is a general-purpose programming language emphasizing performance, type safety and concurrency.","content_urls":{"desktop":{"page":"https://en.wikipedia.org/wiki/Rust_(programming_language)"}}}"#,
+        ),
+        (
+            "/api/rest_v1/page/summary/Mercury",
+            200,
+            r#"{"type":"disambiguation","title":"Mercury","extract":"Mercury may refer to:"}"#,
+        ),
+    ];
+
+    #[tokio::test]
+    async fn shows_wikipedia_summaries_to_the_room() {
+        let state = state(&mock_api(WIKI).await);
+        let response = ChatMessage::into_response(
+            message("alice", "rust", "!wiki Rust (programming language)"),
+            &state,
+        )
+        .await;
+        assert_eq!(response.target, "");
+        assert_eq!(
+            response.content,
+            "-- Rust (programming language): Rust is a general-purpose programming language emphasizing performance, type safety and concurrency. https://en.wikipedia.org/wiki/Rust_(programming_language)"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_ambiguous_and_missing_articles() {
+        let state = state(&mock_api(WIKI).await);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!wiki Mercury"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(
+            response.content,
+            "Mercury could mean several articles, try being more specific."
+        );
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!wiki Atlantis"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.content, "Wikipedia has no article on Atlantis.");
+    }
+
+    #[tokio::test]
+    async fn looks_up_crates() {
+        let routes: Routes = &[(
+            "/api/v1/crates/serde",
+            200,
+            r#"{"crate":{"name":"serde","max_version":"1.0.197","description":"A generic serialization/deserialization framework\n","downloads":312345678},"versions":[]}"#,
+        )];
+        let state = state(&mock_api(routes).await);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!crates serde"), &state).await;
+        assert_eq!(response.target, "");
+        assert_eq!(
+            response.content,
+            "-- serde 1.0.197: A generic serialization/deserialization framework, 312,345,678 downloads https://crates.io/crates/serde"
+        );
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!crates nope"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(
+            response.content,
+            "There's no crate called nope on crates.io."
+        );
+    }
+
+    #[test]
+    fn groups_thousands() {
+        assert_eq!(thousands(0), "0");
+        assert_eq!(thousands(999), "999");
+        assert_eq!(thousands(1000), "1,000");
+        assert_eq!(thousands(312345678), "312,345,678");
+    }
+
+    #[test]
+    fn truncates_at_word_boundaries() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("the quick brown fox", 12), "the quick…");
+        assert_eq!(truncate("unbreakable", 4), "unbr…");
+    }
+
+    #[test]
+    fn parses_coordinates() {
+        assert_eq!(parse_coordinates("52.52,13.41"), Some((52.52, 13.41)));
+        assert_eq!(parse_coordinates("-33.9 151.2"), Some((-33.9, 151.2)));
+        assert_eq!(parse_coordinates("95,10"), None);
+        assert_eq!(parse_coordinates("New York"), None);
+        assert_eq!(parse_coordinates("Berlin"), None);
+    }
+
+    #[tokio::test]
+    async fn calculates_for_the_sender() {
+        let state = state_with_users(&[("alice", "rust")]).await;
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!calc (3+4)*2"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.content, "-- (3+4)*2 = 14");
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!calc 1/0"), &state).await;
+        assert_eq!(
+            response.content,
+            "Couldn't calculate that: Division by zero."
+        );
+        assert_eq!(response.style(), Style::Error);
+    }
+
+    #[tokio::test]
+    async fn reports_the_uptime_to_the_sender() {
+        let state = state_with_users(&[
+            ("alice", "rust"),
+            ("bob", "rust"),
+            ("carol", ""),
+            ("dave", ""),
+        ])
+        .await;
+        // checked the name, but hasn't connected yet
+        state
+            .reservations
+            .lock()
+            .await
+            .insert("dave".to_string(), Instant::now());
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!uptime"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.content, "-- Up for 0m, 3 users online in 1 room");
+    }
+
+    #[tokio::test]
+    async fn reports_message_counts() {
+        let state = state("http://127.0.0.1:9");
+        state
+            .room_messages
+            .lock()
+            .await
+            .insert("rust".to_string(), 1);
+        state.total_messages.store(5, Ordering::Relaxed);
+        state.direct_messages.store(2, Ordering::Relaxed);
+        for _ in 0..2 {
+            ChatMessage::into_response(message("alice", "rust", "!roll"), &state).await;
+        }
+        let response = ChatMessage::into_response(message("alice", "rust", "!stats"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(
+            response.content,
+            "-- 1 message in rust, 5 in all rooms and 2 direct messages since the server started\nMost used commands: !roll 2, !stats 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_pings_with_their_delay() {
+        let state = state("http://127.0.0.1:9");
+        let ping = |content| ChatMessage {
+            timestamp: 1_700_000_000_250,
+            ..message("alice", "rust", content)
+        };
+        let response = ChatMessage::into_response(ping("!ping"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(
+            response.content,
+            "-- pong, your ping arrived at 22:13:20.250 UTC"
+        );
+        let response = ChatMessage::into_response(ping("!ping 1700000000200"), &state).await;
+        assert_eq!(
+            response.content,
+            "-- pong, your ping took 50 ms to arrive at 22:13:20.250 UTC"
+        );
+        let response = ChatMessage::into_response(ping("!ping 1700000000300"), &state).await;
+        assert!(response
+            .content
+            .ends_with("Your clock is 50 ms ahead of the server's, so the delay can't be told."));
+    }
+
+    #[test]
+    fn formats_the_uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(59)), "0m");
+        assert_eq!(format_uptime(Duration::from_secs(2 * 3600 + 300)), "2h 5m");
+        assert_eq!(
+            format_uptime(Duration::from_secs(3 * 86400 + 60)),
+            "3d 0h 1m"
+        );
+    }
+
+    #[tokio::test]
+    async fn masks_or_rejects_banned_words() {
+        let filtered = |reject| {
+            Arc::new(ServerState::new(Config {
+                word_filter: Some(WordFilter::new("darn", reject)),
+                ..Config::default()
+            }))
+        };
+        let state = filtered(false);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "Darn, darned"), &state).await;
+        assert_eq!(response.content, "****, darned");
+        assert_eq!(response.target, "");
+
+        let state = filtered(true);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!me says darn"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.style(), Style::Error);
+    }
+
+    #[tokio::test]
+    async fn sends_direct_messages_to_their_target() {
+        let state = state_with_users(&[("alice", "rust"), ("bob", "go")]).await;
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!dm bob hi there"), &state).await;
+        assert_eq!(response.sender, "alice");
+        assert_eq!(response.target, "bob");
+        assert_eq!(response.chatroom, "rust");
+        assert_eq!(response.content, "hi there");
+        assert_eq!(response.kind(), Kind::DirectMessage);
+    }
+
+    #[tokio::test]
+    async fn answers_incomplete_direct_messages_with_their_usage() {
+        let state = state_with_users(&[("alice", "rust"), ("bob", "go")]).await;
+        let mut inbox = state.router.subscribe_user("bob");
+        for content in ["!dm", "!dm bob", "!dm bob   "] {
+            let response =
+                ChatMessage::into_response(message("alice", "rust", content), &state).await;
+            assert_eq!(response.sender, "server");
+            assert_eq!(response.target, "alice");
+            assert_eq!(response.content, "Usage: !dm <user> <message>");
+        }
+        assert!(inbox.try_recv().is_err());
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!dm bob hi"), &state).await;
+        assert_eq!(response.target, "bob");
+        assert_eq!(response.content, "hi");
+    }
+
+    #[tokio::test]
+    async fn tells_blocked_senders_an_offline_user_is_offline() {
+        let state = state_with_users(&[("mallory", "rust")]).await;
+        state.blocks.lock().await.insert(
+            String::from("alice"),
+            HashSet::from([String::from("mallory")]),
+        );
+        let response =
+            ChatMessage::into_response(message("mallory", "rust", "!dm alice hey"), &state).await;
+        let unblocked =
+            ChatMessage::into_response(message("mallory", "rust", "!dm bob hey"), &state).await;
+        assert_eq!(response.content, unblocked.content.replace("bob", "alice"));
+        assert_eq!(response.kind(), Kind::System);
+        let queued = state.offline_dms.lock().await;
+        assert!(!queued.contains_key("alice"));
+        assert!(queued.contains_key("bob"));
+    }
+
+    #[tokio::test]
+    async fn refuses_direct_messages_to_yourself() {
+        let state = state_with_users(&[("alice", "rust")]).await;
+        let mut inbox = state.router.subscribe_user("alice");
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!dm alice hi me"), &state).await;
+        assert_eq!(response.sender, "server");
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.content, "You can't DM yourself.");
+        assert_eq!(response.style(), Style::Error);
+        assert!(inbox.try_recv().is_err());
+        assert!(state.offline_dms.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn queues_direct_messages_to_offline_users() {
+        let state = state_with_users(&[("alice", "rust")]).await;
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!dm bob later"), &state).await;
+        assert_eq!(response.sender, "server");
+        assert_eq!(response.target, "alice");
+        assert!(response.content.starts_with("No user named bob"));
+        let queued = state.offline_dms.lock().await;
+        assert_eq!(queued.get("bob").unwrap()[0].content, "later");
+    }
+
+    #[tokio::test]
+    async fn shows_the_value_of_a_currency() {
+        let state = state(&mock_api(PRICE).await);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!value btc"), &state).await;
+        assert_eq!(response.sender, "server");
+        assert_eq!(response.target, "");
+        assert_eq!(response.chatroom, "rust");
+        assert_eq!(
+            response.content,
+            "$BTC is currently worth 25431.17€, +2.31% in 24h"
+        );
+    }
+
+    #[tokio::test]
+    async fn shows_the_value_of_an_amount_without_the_change() {
+        let routes: Routes = &PRICE[..1];
+        let state = state(&mock_api(routes).await);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!value BTC 2"), &state).await;
+        assert_eq!(response.content, "2 BTC is currently worth 50862.34€");
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_currencies() {
+        let state = state(&mock_api(PRICE).await);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!value foo"), &state).await;
+        assert_eq!(response.sender, "server");
+        assert_eq!(response.content, "Unknown symbol FOO, try e.g. !value BTC");
+        assert_eq!(response.style(), Style::Error);
+    }
+
+    #[tokio::test]
+    async fn keeps_symbols_from_adding_query_parameters() {
+        let routes: Routes = &[(
+            "/api/v3/ticker/price?symbol=BTC%26SYMBOL%3DETHEUR",
+            400,
+            r#"{"code":-1121,"msg":"Invalid symbol."}"#,
+        )];
+        let state = state(&mock_api(routes).await);
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!value btc&symbol=eth"), &state)
+                .await;
+        assert_eq!(
+            response.content,
+            "Unknown symbol BTC&SYMBOL=ETH, try e.g. !value BTC"
+        );
+    }
+
+    #[tokio::test]
+    async fn throttles_repeated_value_requests() {
+        let state = state(&mock_api(PRICE).await);
+        ChatMessage::into_response(message("alice", "rust", "!value btc"), &state).await;
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!value btc"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert!(response.content.starts_with("Rate limited"));
+    }
+
+    #[tokio::test]
+    async fn sends_the_news_once_retrieved() {
+        let state = state(&mock_api(NEWS).await);
+        let mut inbox = state.router.subscribe_user("alice");
+        let response = ChatMessage::into_response(message("alice", "rust", "!news"), &state).await;
+        assert_eq!(response.sender, "server");
+        assert_eq!(response.target, "alice");
+        assert_eq!(response.content, "Retrieving News ...");
+
+        let news = inbox.recv().await.unwrap();
+        assert_eq!(news.sender, "server");
+        assert_eq!(news.target, "alice");
+        assert_eq!(news.chatroom, "rust");
+        assert!(news
+            .content
+            .contains("▲ 120 \t\"First\" by pg\n\thttps://one.example"));
+        assert!(news.content.contains("▲ 80 \t\"Second\" by dang"));
+        // the deleted, dead and missing items are left out
+        assert_eq!(news.content.matches('▲').count(), 2);
+    }
+
+    #[tokio::test]
+    async fn news_compress_by_over_a_third() {
+        let titles = [
+            "Show HN: A chat server in Rust",
+            "Why HTTP/2 multiplexing matters",
+            "The state of async Rust in 2023",
+            "Ask HN: What are you working on?",
+            "A deep dive into gRPC streaming",
+            "SQLite is not a toy database",
+            "Writing a deflate decoder by hand",
+            "How we cut our cloud bill in half",
+            "Terminal UIs are making a comeback",
+            "Understanding Huffman coding",
+        ];
+        let ids: Vec<String> = (1..=titles.len()).map(|id| id.to_string()).collect();
+        let mut routes = vec![(
+            String::from("/v0/topstories.json?print=pretty"),
+            format!("[ {} ]", ids.join(", ")),
+        )];
+        for (id, title) in ids.iter().zip(titles) {
+            let story = format!(
+                r#"{{"by":"user{id}","score":{id}7,"time":1,"title":"{title}","url":"https://news{id}.example/{id}"}}"#
+            );
+            routes.push((format!("/v0/item/{}.json?print=pretty", id), story));
+        }
+        let leak = |text: String| &*Box::leak(text.into_boxed_str());
+        let routes: Vec<_> = routes
+            .into_iter()
+            .map(|(route, body)| (leak(route), 200, leak(body)))
+            .collect();
+        let state = state(&mock_api(Box::leak(routes.into_boxed_slice())).await);
+        let mut inbox = state.router.subscribe_user("alice");
+        ChatMessage::into_response(message("alice", "rust", "!news"), &state).await;
+
+        let news = inbox.recv().await.unwrap().encode_to_vec();
+        let compressed = crate::gzip::compress(&news);
+        // 790 bytes to 491 at the time of writing, little of it repeats besides the layout
+        assert!(
+            compressed.len() * 3 < news.len() * 2,
+            "{} bytes compressed to {}",
+            news.len(),
+            compressed.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_news_feeds() {
+        let state = state("http://127.0.0.1:9");
+        let response =
+            ChatMessage::into_response(message("alice", "rust", "!news best"), &state).await;
+        assert_eq!(response.target, "alice");
+        assert!(response.content.starts_with("Unknown feed best"));
+        assert_eq!(response.style(), Style::Error);
+    }
+
+    #[tokio::test]
+    async fn rejects_news_counts_out_of_range() {
+        let state = state("http://127.0.0.1:9");
+        for count in ["31", "99999999999999999999"] {
+            let content = format!("!news {}", count);
+            let response =
+                ChatMessage::into_response(message("alice", "rust", &content), &state).await;
+            assert_eq!(response.content, "At most 30 stories can be shown at once.");
+            assert_eq!(response.style(), Style::Error);
+        }
+    }
+
+    #[test]
+    fn parses_a_price() {
+        let body = r#"{"symbol":"BTCEUR","price":"25431.17000000"}"#;
+        assert_eq!(parse_conversion(body), Conversion::Price(25431.17));
+    }
+
+    #[test]
+    fn reports_an_invalid_symbol() {
+        let body = r#"{"code":-1121,"msg":"Invalid symbol."}"#;
+        assert_eq!(parse_conversion(body), Conversion::UnknownSymbol);
+    }
+
+    #[test]
+    fn rejects_unexpected_bodies() {
+        assert_eq!(parse_conversion(""), Conversion::Invalid);
+        assert_eq!(
+            parse_conversion(r#"{"code":-1003,"msg":"Too many requests."}"#),
+            Conversion::Invalid
+        );
+        assert_eq!(
+            parse_conversion(r#"{"symbol":"BTCEUR","price":"a lot"}"#),
+            Conversion::Invalid
+        );
+    }
+
+    #[test]
+    fn reads_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), DEFAULT_RATE_LIMIT_BACKOFF);
+        headers.insert("Retry-After", "30".parse().unwrap());
+        assert_eq!(retry_after(&headers), Duration::from_secs(30));
+    }
 }
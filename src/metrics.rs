@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use super::command;
+
+/// Counters exported in the Prometheus text format on `/metrics`.
+pub struct Metrics {
+    messages: AtomicU64,
+    active_connections: AtomicI64,
+    /// One counter per known command, created up front so counting doesn't take a lock.
+    commands: BTreeMap<&'static str, AtomicU64>,
+    api_errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            messages: AtomicU64::default(),
+            active_connections: AtomicI64::default(),
+            commands: command::names()
+                .map(|name| (name, AtomicU64::default()))
+                .collect(),
+            api_errors: Mutex::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn message_received(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn command_invoked(&self, command: &'static str) {
+        if let Some(count) = self.commands.get(command) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The commands invoked at least once, the most used first.
+    pub fn command_counts(&self) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<_> = self
+            .commands
+            .iter()
+            .map(|(command, count)| (*command, count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    pub fn api_error(&self, api: &'static str) {
+        *self.api_errors.lock().unwrap().entry(api).or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP convers_messages_total Messages received from clients."
+        );
+        let _ = writeln!(out, "# TYPE convers_messages_total counter");
+        let _ = writeln!(
+            out,
+            "convers_messages_total {}",
+            self.messages.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP convers_active_connections Currently open chat streams."
+        );
+        let _ = writeln!(out, "# TYPE convers_active_connections gauge");
+        let _ = writeln!(
+            out,
+            "convers_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP convers_commands_total Commands invoked, by command."
+        );
+        let _ = writeln!(out, "# TYPE convers_commands_total counter");
+        for (command, count) in &self.commands {
+            let _ = writeln!(
+                out,
+                "convers_commands_total{{command=\"{}\"}} {}",
+                command,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP convers_api_errors_total Failed requests to external APIs, by API."
+        );
+        let _ = writeln!(out, "# TYPE convers_api_errors_total counter");
+        for (api, count) in self.api_errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "convers_api_errors_total{{api=\"{}\"}} {}", api, count);
+        }
+        out
+    }
+}
+
+/// Serves `metrics` on `GET /metrics` until the server fails.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let response = respond(&request, &metrics);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    Server::bind(&addr).serve(make_service).await
+}
+
+fn respond(request: &Request<Body>, metrics: &Metrics) -> Response<Body> {
+    let response = if request.method() == Method::GET && request.uri().path() == "/metrics" {
+        Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics.render()))
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+    };
+    response.unwrap()
+}
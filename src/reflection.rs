@@ -0,0 +1,234 @@
+//! A read-only gRPC server reflection service (`grpc.reflection.v1alpha`), so tools like
+//! `grpcurl` can list and call the chat service without a copy of the proto. Only listing
+//! services and looking up files by name or symbol are supported, the chat proto has no
+//! extensions to report.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures_core::Stream;
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorSet};
+use tonic::{Code, Request, Response, Status, Streaming};
+
+use proto::server_reflection_request::MessageRequest;
+use proto::server_reflection_response::MessageResponse;
+use proto::server_reflection_server::ServerReflection;
+use proto::{
+    ErrorResponse, FileDescriptorResponse, ListServiceResponse, ServerReflectionRequest,
+    ServerReflectionResponse, ServiceResponse,
+};
+
+pub mod proto {
+    tonic::include_proto!("grpc.reflection.v1alpha");
+}
+
+pub use proto::server_reflection_server::ServerReflectionServer;
+
+/// Descriptors of `chat.proto` as written by the build script.
+const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("chat_descriptor");
+
+pub struct Reflection {
+    index: Arc<Index>,
+}
+
+impl Default for Reflection {
+    fn default() -> Reflection {
+        let descriptors = FileDescriptorSet::decode(FILE_DESCRIPTOR_SET)
+            .expect("the build script writes a valid descriptor set");
+        Reflection {
+            index: Arc::new(Index::new(descriptors)),
+        }
+    }
+}
+
+/// What reflection requests are answered from, built once from the descriptor set.
+struct Index {
+    /// Fully qualified names of the services, e.g. `chat.Chat`.
+    services: Vec<String>,
+    /// Encoded `FileDescriptorProto` of each file, by file name.
+    files: HashMap<String, Vec<u8>>,
+    /// File each fully qualified service, method, message and enum is defined in.
+    symbols: HashMap<String, String>,
+}
+
+impl Index {
+    fn new(descriptors: FileDescriptorSet) -> Index {
+        let mut index = Index {
+            services: Vec::new(),
+            files: HashMap::new(),
+            symbols: HashMap::new(),
+        };
+        for file in descriptors.file {
+            let name = file.name().to_string();
+            let prefix = match file.package() {
+                "" => String::new(),
+                package => format!("{}.", package),
+            };
+            for service in &file.service {
+                let service_name = format!("{}{}", prefix, service.name());
+                for method in &service.method {
+                    let method_name = format!("{}.{}", service_name, method.name());
+                    index.symbols.insert(method_name, name.clone());
+                }
+                index.symbols.insert(service_name.clone(), name.clone());
+                index.services.push(service_name);
+            }
+            for message in &file.message_type {
+                index.add_message(&prefix, message, &name);
+            }
+            for enumeration in &file.enum_type {
+                let enum_name = format!("{}{}", prefix, enumeration.name());
+                index.symbols.insert(enum_name, name.clone());
+            }
+            index.files.insert(name, file.encode_to_vec());
+        }
+        index
+    }
+
+    fn add_message(&mut self, prefix: &str, message: &DescriptorProto, file: &str) {
+        let name = format!("{}{}", prefix, message.name());
+        let nested_prefix = format!("{}.", name);
+        for nested in &message.nested_type {
+            self.add_message(&nested_prefix, nested, file);
+        }
+        for enumeration in &message.enum_type {
+            let enum_name = format!("{}{}", nested_prefix, enumeration.name());
+            self.symbols.insert(enum_name, file.to_string());
+        }
+        self.symbols.insert(name, file.to_string());
+    }
+
+    fn respond(&self, request: ServerReflectionRequest) -> ServerReflectionResponse {
+        let response = match &request.message_request {
+            Some(MessageRequest::ListServices(_)) => {
+                MessageResponse::ListServicesResponse(ListServiceResponse {
+                    service: self
+                        .services
+                        .iter()
+                        .map(|name| ServiceResponse { name: name.clone() })
+                        .collect(),
+                })
+            }
+            Some(MessageRequest::FileByFilename(file)) => self.file(file),
+            Some(MessageRequest::FileContainingSymbol(symbol)) => match self.symbols.get(symbol) {
+                Some(file) => self.file(file),
+                None => error(Code::NotFound, format!("unknown symbol {}", symbol)),
+            },
+            _ => error(Code::Unimplemented, String::from("unsupported request")),
+        };
+        ServerReflectionResponse {
+            valid_host: request.host.clone(),
+            original_request: Some(request),
+            message_response: Some(response),
+        }
+    }
+
+    /// The descriptor of `file`, none of the chat protos import others so there are no
+    /// dependencies to send along.
+    fn file(&self, file: &str) -> MessageResponse {
+        match self.files.get(file) {
+            Some(descriptor) => MessageResponse::FileDescriptorResponse(FileDescriptorResponse {
+                file_descriptor_proto: vec![descriptor.clone()],
+            }),
+            None => error(Code::NotFound, format!("unknown file {}", file)),
+        }
+    }
+}
+
+fn error(code: Code, message: String) -> MessageResponse {
+    MessageResponse::ErrorResponse(ErrorResponse {
+        error_code: code as i32,
+        error_message: message,
+    })
+}
+
+#[tonic::async_trait]
+impl ServerReflection for Reflection {
+    type ServerReflectionInfoStream =
+        Pin<Box<dyn Stream<Item = Result<ServerReflectionResponse, Status>> + Send + 'static>>;
+
+    async fn server_reflection_info(
+        &self,
+        request: Request<Streaming<ServerReflectionRequest>>,
+    ) -> Result<Response<Self::ServerReflectionInfoStream>, Status> {
+        let mut requests = request.into_inner();
+        let index = self.index.clone();
+        let output_stream = async_stream::try_stream! {
+            while let Some(request) = requests.message().await? {
+                yield index.respond(request);
+            }
+        };
+        Ok(Response::new(
+            Box::pin(output_stream) as Self::ServerReflectionInfoStream
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(message_request: MessageRequest) -> ServerReflectionRequest {
+        ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(message_request),
+        }
+    }
+
+    #[test]
+    fn lists_the_chat_service() {
+        let response = Reflection::default()
+            .index
+            .respond(request(MessageRequest::ListServices(String::new())));
+        match response.message_response {
+            Some(MessageResponse::ListServicesResponse(list)) => {
+                let names: Vec<_> = list.service.into_iter().map(|s| s.name).collect();
+                assert_eq!(names, ["chat.Chat"]);
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn finds_the_file_defining_a_symbol() {
+        let index = Reflection::default().index;
+        for symbol in [
+            "chat.Chat",
+            "chat.Chat.LiveChat",
+            "chat.ChatMessage",
+            "chat.Kind",
+        ] {
+            let response = index.respond(request(MessageRequest::FileContainingSymbol(
+                symbol.to_string(),
+            )));
+            match response.message_response {
+                Some(MessageResponse::FileDescriptorResponse(files)) => {
+                    let file = prost_types::FileDescriptorProto::decode(
+                        files.file_descriptor_proto[0].as_slice(),
+                    )
+                    .unwrap();
+                    assert_eq!(file.name(), "chat.proto");
+                }
+                other => panic!("unexpected response for {}: {:?}", symbol, other),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_unknown_symbols() {
+        let response =
+            Reflection::default()
+                .index
+                .respond(request(MessageRequest::FileContainingSymbol(
+                    "chat.Nope".to_string(),
+                )));
+        match response.message_response {
+            Some(MessageResponse::ErrorResponse(error)) => {
+                assert_eq!(error.error_code, Code::NotFound as i32)
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+
+use super::chat::Style;
+use super::message_parser::message_id;
+use super::{timestamp, ChatMessage, Kind};
+
+/// Delivers messages through one broadcast channel per room and one per user, so a message only
+/// reaches the connections that are meant to see it instead of every connection filtering
+/// every message.
+///
+/// Room messages (empty `target`) go to the channel of their `chatroom`. Targeted messages go to
+/// the channel of their `target` and, for direct messages, also back to their `sender`.
+/// Announcements go to the channel of every user. Messages without an id, like the notices the
+/// server makes up, are given one on the way out.
+/// Channels are created on first subscription and torn down once nobody listens anymore.
+pub struct Router {
+    rooms: Mutex<HashMap<String, broadcast::Sender<ChatMessage>>>,
+    users: Mutex<HashMap<String, broadcast::Sender<ChatMessage>>>,
+    /// Messages a channel buffers for its slowest receiver before that one starts missing some.
+    capacity: usize,
+}
+
+/// A receiver handed from a connection's input loop to its output stream.
+///
+/// Subscribing happens in the input loop before the message that caused it is routed, so e.g. a
+/// joining user always receives their own join announcement.
+pub enum Subscription {
+    Room(Option<broadcast::Receiver<ChatMessage>>),
+    User(String, broadcast::Receiver<ChatMessage>),
+}
+
+impl Router {
+    pub fn new(capacity: usize) -> Router {
+        Router {
+            rooms: Mutex::default(),
+            users: Mutex::default(),
+            capacity,
+        }
+    }
+
+    pub fn send(&self, mut message: ChatMessage) {
+        stamp(&mut message);
+        if message.kind() == Kind::Announcement {
+            let channels = self.users.lock().unwrap();
+            for tx in channels.values() {
+                // stale channels are cleaned up by the next subscription
+                let _ = tx.send(message.clone());
+            }
+        } else if message.target.is_empty() {
+            let room = message.chatroom.clone();
+            Router::deliver(&self.rooms, &room, message);
+        } else {
+            let target = message.target.clone();
+            // only direct messages, the server's notices would otherwise reach a user named
+            // like their sender
+            if message.kind() == Kind::DirectMessage && message.sender != target {
+                Router::deliver(&self.users, &message.sender, message.clone());
+            }
+            Router::deliver(&self.users, &target, message);
+        }
+    }
+
+    /// Delivers a message to a single user as is, e.g. to replay room messages to a joining user.
+    pub fn send_to_user(&self, user: &str, mut message: ChatMessage) {
+        stamp(&mut message);
+        Router::deliver(&self.users, user, message);
+    }
+
+    /// Subscribes to a room, or to nothing while in the lobby.
+    pub fn subscribe_room(&self, room: &str) -> Option<broadcast::Receiver<ChatMessage>> {
+        if room.is_empty() {
+            return None;
+        }
+        Some(self.subscribe(&self.rooms, room))
+    }
+
+    pub fn subscribe_user(&self, user: &str) -> broadcast::Receiver<ChatMessage> {
+        self.subscribe(&self.users, user)
+    }
+
+    fn deliver(
+        channels: &Mutex<HashMap<String, broadcast::Sender<ChatMessage>>>,
+        key: &str,
+        message: ChatMessage,
+    ) {
+        let mut channels = channels.lock().unwrap();
+        if let Some(tx) = channels.get(key) {
+            // sending only fails when every receiver is gone, which makes the channel stale
+            if tx.send(message).is_err() {
+                channels.remove(key);
+            }
+        }
+    }
+
+    fn subscribe(
+        &self,
+        channels: &Mutex<HashMap<String, broadcast::Sender<ChatMessage>>>,
+        key: &str,
+    ) -> broadcast::Receiver<ChatMessage> {
+        let mut channels = channels.lock().unwrap();
+        channels.retain(|_, tx| tx.receiver_count() > 0);
+        channels
+            .entry(key.to_string())
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .subscribe()
+    }
+}
+
+/// The receiving end of a connection, following the subscriptions its input loop hands over.
+pub struct Inbox {
+    subscriptions: mpsc::UnboundedReceiver<Subscription>,
+    room_rx: Option<broadcast::Receiver<ChatMessage>>,
+    user_rx: Option<broadcast::Receiver<ChatMessage>>,
+    /// Whose connection this is, once the input loop knows.
+    user: Option<String>,
+}
+
+impl Inbox {
+    pub fn new(subscriptions: mpsc::UnboundedReceiver<Subscription>) -> Inbox {
+        Inbox {
+            subscriptions,
+            room_rx: None,
+            user_rx: None,
+            user: None,
+        }
+    }
+
+    /// Waits for the next message of either subscription, `None` once the input loop is gone.
+    pub async fn next(&mut self) -> Option<ChatMessage> {
+        loop {
+            // polled in order, so user messages sent after a room notice (like the history replay
+            // after a join announcement) are never yielded before it
+            tokio::select! {
+                biased;
+                subscription = self.subscriptions.recv() => match subscription {
+                    Some(Subscription::Room(rx)) => self.room_rx = rx,
+                    Some(Subscription::User(user, rx)) => {
+                        self.user_rx = Some(rx);
+                        self.user = Some(user);
+                    }
+                    // the input loop is gone, but what it sent to this user last (like the reason
+                    // for closing the connection) should still get out
+                    None => return self.user_rx.as_mut()?.try_recv().ok(),
+                },
+                Some(message) = next_message(&mut self.room_rx) => return Some(message),
+                Some(message) = next_message(&mut self.user_rx) => return Some(message),
+            }
+        }
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn leave_room(&mut self) {
+        self.room_rx = None;
+    }
+}
+
+fn stamp(message: &mut ChatMessage) {
+    if message.id.is_empty() {
+        message.id = message_id();
+    }
+}
+
+/// Receives the next message of a subscription, never resolving while there is none. A receiver
+/// that fell more than the channel's capacity behind gets a resync hint instead of the messages
+/// it missed, and goes on with the oldest one still buffered.
+async fn next_message(rx: &mut Option<broadcast::Receiver<ChatMessage>>) -> Option<ChatMessage> {
+    let rx = match rx {
+        Some(rx) => rx,
+        None => return std::future::pending().await,
+    };
+    match rx.recv().await {
+        Ok(message) => Some(message),
+        Err(RecvError::Lagged(missed)) => Some(resync_hint(missed)),
+        Err(RecvError::Closed) => None,
+    }
+}
+
+fn resync_hint(missed: u64) -> ChatMessage {
+    ChatMessage {
+        sender: "server".to_string(),
+        timestamp: timestamp(),
+        content: format!("-- Missed {} messages, catching up.", missed),
+        style: Style::Notice as i32,
+        kind: Kind::Resync as i32,
+        id: message_id(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn copies_only_direct_messages_to_their_sender() {
+        let router = Router::new(8);
+        let mut server = router.subscribe_user("server");
+        let mut alice = router.subscribe_user("alice");
+        let mut bob = router.subscribe_user("bob");
+        router.send(ChatMessage {
+            sender: "server".to_string(),
+            target: "alice".to_string(),
+            content: "-- bob mentioned you".to_string(),
+            kind: Kind::System as i32,
+            ..Default::default()
+        });
+        assert_eq!(alice.recv().await.unwrap().content, "-- bob mentioned you");
+        assert!(server.try_recv().is_err());
+
+        router.send(ChatMessage {
+            sender: "bob".to_string(),
+            target: "alice".to_string(),
+            content: "hi".to_string(),
+            kind: Kind::DirectMessage as i32,
+            ..Default::default()
+        });
+        assert_eq!(alice.recv().await.unwrap().content, "hi");
+        assert_eq!(bob.recv().await.unwrap().content, "hi");
+    }
+
+    #[tokio::test]
+    async fn lagging_receivers_are_told_to_resync() {
+        let router = Router::new(2);
+        let (subscriptions, subscription_rx) = mpsc::unbounded_channel();
+        let mut inbox = Inbox::new(subscription_rx);
+        let _ = subscriptions.send(Subscription::Room(router.subscribe_room("rust")));
+        let send = |content: &str| {
+            router.send(ChatMessage {
+                chatroom: "rust".to_string(),
+                content: content.to_string(),
+                ..Default::default()
+            })
+        };
+        send("one");
+        assert_eq!(inbox.next().await.unwrap().content, "one");
+        for content in ["two", "three", "four", "five"] {
+            send(content);
+        }
+        let hint = inbox.next().await.unwrap();
+        assert_eq!(hint.kind(), Kind::Resync);
+        assert_eq!(hint.content, "-- Missed 2 messages, catching up.");
+        assert_eq!(inbox.next().await.unwrap().content, "four");
+    }
+}
@@ -1,95 +1,451 @@
 #![allow(dead_code)]
 
 use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use chat::chat_server::{Chat, ChatServer};
-use chat::{ChatMessage, NameCheckRequest, NameCheckResponse};
-use colored::Colorize;
+use chat::{
+    ChatMessage, GetHistoryRequest, GetHistoryResponse, Kind, ListRoomsRequest, ListRoomsResponse,
+    NameCheckRequest, NameCheckResponse, Room, SendMessageResponse, Style,
+};
+use command::Command;
+use compression::Gzip;
 use futures_core::Stream;
-use lazy_static::lazy_static;
-use tokio::sync::{broadcast, Mutex};
+use message_parser::{
+    forget_room_if_empty, normalize_room_name, stamp_inbound, strip_control, AWAY,
+};
+use reflection::{Reflection, ServerReflectionServer};
+use router::{Inbox, Subscription};
+use state::{Config, ServerState};
+use tokio::sync::{mpsc, Mutex, Notify};
 use tonic::{transport::Server, Request, Response, Status};
+use tracing::{error, info, info_span, warn, Instrument};
 
+pub mod ban_list;
+pub mod calc;
+pub mod command;
+mod compression;
+pub mod config_file;
+mod gzip;
+pub mod history_store;
+#[cfg(test)]
+mod integration_tests;
+mod logging;
 pub mod message_parser;
+pub mod metrics;
+pub mod reflection;
+pub mod router;
+pub mod state;
+pub mod word_filter;
 
 pub mod chat {
     tonic::include_proto!("chat");
 }
 
-lazy_static! {
-    static ref TX: broadcast::Sender<ChatMessage> = {
-        let (tx, _) = broadcast::channel(100);
-        tx
-    };
-    static ref USERMAP: Arc<Mutex<HashMap<String, String>>> = {
-        let map: HashMap<String, String> = HashMap::new();
-        Arc::new(Mutex::from(map))
-    };
+/// Maximum number of direct messages held for a single offline user, older ones are dropped first.
+const MAX_QUEUED_DMS: usize = 50;
+
+/// How long a name confirmed by `check_for_name` stays reserved without a `live_chat` connection.
+const NAME_RESERVATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of recent messages per room that are replayed to users joining it.
+const HISTORY_LENGTH: usize = 20;
+
+/// Number of messages `get_history` returns unless asked for fewer, and at most.
+const HISTORY_PAGE_LENGTH: usize = 50;
+const MAX_HISTORY_PAGE_LENGTH: usize = 200;
+
+/// Maximum number of characters in a room name.
+const MAX_ROOM_NAME_CHARS: usize = 32;
+
+/// Maximum number of characters in a user's status.
+const MAX_STATUS_CHARS: usize = 40;
+
+/// Maximum number of characters in a room topic.
+const MAX_TOPIC_CHARS: usize = 120;
+
+/// Typing events a user sends faster than this are dropped. They don't count towards the flood
+/// protection, but each one is still relayed to the whole room.
+const TYPING_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long before the idle timeout an idle user is warned, capped at half the timeout.
+const IDLE_WARNING: Duration = Duration::from_secs(60);
+
+/// Dead peers are detected with HTTP/2 keepalive pings rather than application-level messages:
+/// the server pings every connection this often, and when a ping isn't acknowledged within
+/// `KEEPALIVE_TIMEOUT` hyper closes the connection. That ends the `live_chat` input loop with an
+/// error, which runs the regular disconnect cleanup.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct ChatService {
+    state: Arc<ServerState>,
 }
 
-#[derive(Default, Debug)]
-pub struct ChatService {}
+impl ChatService {
+    pub fn new(state: Arc<ServerState>) -> ChatService {
+        ChatService { state }
+    }
+}
 
 #[tonic::async_trait]
 impl Chat for ChatService {
     type LiveChatStream = Pin<Box<dyn Stream<Item = Result<ChatMessage, Status>> + Send + 'static>>;
 
+    /// Name lifecycle: an available name is reserved right away by inserting it into the user map
+    /// with an empty room (the lobby), so two clients can't both be told the same name is free.
+    /// The first `live_chat` message sent under that name claims the reservation. If no connection
+    /// claims it within `NAME_RESERVATION_TIMEOUT` the name is released again, and a claimed name
+    /// is released when its connection ends.
     async fn check_for_name(
         &self,
-        request: Request<NameCheckRequest>
+        request: Request<NameCheckRequest>,
     ) -> Result<Response<NameCheckResponse>, Status> {
-        let guard = USERMAP.lock().await;
-        let available = !guard.contains_key(&request.into_inner().name);
-        Ok(Response::new(NameCheckResponse { available }))
+        if let Some(refusal) = refuse_connection(&self.state, request.remote_addr()).await {
+            return Err(refusal);
+        }
+        let name = request.into_inner().name;
+        let mut guard = self.state.users.write().await;
+        let available = !is_servers_name(&name)
+            && !guard.contains_key(&name)
+            && !self.state.bans.lock().await.contains(&name);
+        if available {
+            guard.insert(name.clone(), String::new());
+            reserve_name(&self.state, name).await;
+        }
+        Ok(Response::new(NameCheckResponse {
+            available,
+            command_prefix: self.state.config.command_prefix.to_string(),
+        }))
+    }
+
+    /// Lists the rooms users are in right now. Rooms only exist while they have users, so this
+    /// reads the user map rather than the router, and leaves out the lobby.
+    async fn list_rooms(
+        &self,
+        _request: Request<ListRoomsRequest>,
+    ) -> Result<Response<ListRoomsResponse>, Status> {
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for room in self
+            .state
+            .users
+            .read()
+            .await
+            .values()
+            .filter(|room| !room.is_empty())
+        {
+            *counts.entry(room.clone()).or_default() += 1;
+        }
+        let topics = self.state.topics.lock().await;
+        let passwords = self.state.room_passwords.lock().await;
+        let mut rooms: Vec<Room> = counts
+            .into_iter()
+            .map(|(name, users)| Room {
+                topic: topics.get(&name).cloned().unwrap_or_default(),
+                password_protected: passwords.contains_key(&name),
+                name,
+                users,
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Response::new(ListRoomsResponse {
+            rooms,
+            max_users: self.state.config.max_room_users as u32,
+        }))
+    }
+
+    /// Reads a page of a room's persistent history. Anyone may read it without being in the room,
+    /// like `list_rooms`, except for password protected rooms.
+    async fn get_history(
+        &self,
+        request: Request<GetHistoryRequest>,
+    ) -> Result<Response<GetHistoryResponse>, Status> {
+        if let Some(refusal) = refuse_banned_address(&self.state, request.remote_addr()) {
+            return Err(refusal);
+        }
+        let client = request.remote_addr().map(|addr| addr.ip().to_string());
+        let request = request.into_inner();
+        let room = normalize_room_name(&request.room).map_err(Status::invalid_argument)?;
+        if let Some(wait) = history_throttle(&self.state, client.unwrap_or_default()).await {
+            return Err(Status::resource_exhausted(format!(
+                "try again in {}ms",
+                wait.as_millis()
+            )));
+        }
+        let stored = self.state.room_passwords.lock().await.get(&room).cloned();
+        if let Some(stored) = stored {
+            // hashing takes long enough to stall other connections on this thread
+            let password = request.password.clone();
+            let matches = tokio::task::spawn_blocking(move || stored.matches(&password))
+                .await
+                .unwrap_or(false);
+            if !matches {
+                return Err(Status::permission_denied("the room is password protected"));
+            }
+        }
+        let store = self
+            .state
+            .history_store
+            .clone()
+            .ok_or_else(|| Status::unavailable("the server doesn't keep a history"))?;
+        let limit = match request.limit as usize {
+            0 => HISTORY_PAGE_LENGTH,
+            limit => limit.min(MAX_HISTORY_PAGE_LENGTH),
+        };
+        let before = Some(request.before).filter(|before| *before > 0);
+        let page = tokio::task::spawn_blocking(move || store.page(&room, before, limit)).await;
+        let (messages, more) = page
+            .map_err(std::io::Error::from)
+            .and_then(|page| page)
+            .map_err(|e| {
+                error!(error = %e, "couldn't read the history");
+                Status::internal("couldn't read the history")
+            })?;
+        Ok(Response::new(GetHistoryResponse { messages, more }))
+    }
+
+    /// Posts a single message for clients that don't hold a `live_chat` connection, like bots and
+    /// webhooks. It's handled like a streamed message, commands included, and the server's
+    /// response is returned as the ack since the sender has no stream to receive it on.
+    ///
+    /// Nothing authenticates the sender, so to keep bots from speaking for others a message is
+    /// refused while its sender is a connected or reserved name, and `!join` is refused because
+    /// it would take the name. Admin commands are refused as well. Each client address may post
+    /// once per `bot_message_interval`.
+    async fn send_message(
+        &self,
+        request: Request<ChatMessage>,
+    ) -> Result<Response<SendMessageResponse>, Status> {
+        if let Some(refusal) = refuse_banned_address(&self.state, request.remote_addr()) {
+            return Err(refusal);
+        }
+        let remote = request.remote_addr().map(|addr| addr.ip().to_string());
+        let mut message = request.into_inner();
+        if message.sender.is_empty() || is_servers_name(&message.sender) {
+            return Err(Status::invalid_argument(
+                "sender must be set and can't be server",
+            ));
+        }
+        message.content = strip_control(&message.content);
+        if message.chatroom.is_empty() || message.content.trim().is_empty() {
+            return Err(Status::invalid_argument("chatroom and content must be set"));
+        }
+        message.chatroom =
+            normalize_room_name(&message.chatroom).map_err(Status::invalid_argument)?;
+        // a bot gives no password, so it can't post to or set the topic of a protected room
+        if self
+            .state
+            .room_passwords
+            .lock()
+            .await
+            .contains_key(&message.chatroom)
+        {
+            return Err(Status::permission_denied("the room is password protected"));
+        }
+        let command =
+            command::parse_with_prefix(&message.content, self.state.config.command_prefix);
+        if matches!(command, Command::Join { .. }) {
+            return Err(Status::invalid_argument(
+                "joining a room needs a live_chat connection",
+            ));
+        }
+        // a bot could pose as an admin who isn't connected
+        if command.is_privileged() {
+            return Err(Status::permission_denied(
+                "admin commands need a live_chat connection",
+            ));
+        }
+        if self.state.users.read().await.contains_key(&message.sender) {
+            return Err(Status::permission_denied(format!(
+                "{} is in use",
+                message.sender
+            )));
+        }
+        let client = remote.unwrap_or_else(|| message.sender.clone());
+        if let Some(wait) = bot_throttle(&self.state, client).await {
+            return Err(Status::resource_exhausted(format!(
+                "try again in {}ms",
+                wait.as_millis()
+            )));
+        }
+        self.state.metrics.message_received();
+        stamp_inbound(&mut message);
+        let response = ChatMessage::into_response(message, &self.state).await;
+        record_history(&self.state, &response).await;
+        count_message(&self.state, &response).await;
+        self.state.router.send(response.clone());
+        Ok(Response::new(SendMessageResponse {
+            response: Some(response),
+        }))
     }
 
     async fn live_chat(
         &self,
         request: Request<tonic::Streaming<ChatMessage>>,
     ) -> Result<Response<Self::LiveChatStream>, Status> {
+        if let Some(refusal) = refuse_connection(&self.state, request.remote_addr()).await {
+            return Err(refusal);
+        }
+        let span = info_span!("connection", remote = ?request.remote_addr());
+        span.in_scope(|| info!("client connected"));
+        let state = self.state.clone();
+        state.metrics.connection_opened();
         let mut input_stream = request.into_inner();
         let room = Arc::new(Mutex::new(String::new()));
         let room_copy = room.clone();
+        let state_copy = state.clone();
         let user = Arc::new(Mutex::new(String::new()));
-        let user_copy = user.clone();
+        let (subscriptions, subscription_rx) = mpsc::unbounded_channel();
+        let disconnect = Arc::new(Notify::new());
 
         tokio::spawn(async move {
-            while let Ok(Some(message)) = input_stream.message().await {
+            // the name this connection speaks for, taken from its first message. There's no
+            // command to rename, so it stays the same for as long as the connection lasts.
+            let mut name: Option<String> = None;
+            // whether the status was set to away by the idle warning rather than by the user
+            let mut auto_away = false;
+            let mut idle = IdleTimer::new(state.config.idle_timeout);
+            loop {
+                let next = tokio::select! {
+                    next = idle.watch(input_stream.message()) => next,
+                    _ = disconnect.notified() => break,
+                };
+                let mut message = match next {
+                    Some(Ok(Some(message))) => message,
+                    Some(_) => break,
+                    None if idle.warned => {
+                        let user_guard = user.lock().await;
+                        let room_guard = room.lock().await;
+                        info!(user = %user_guard, "closing idle connection");
+                        send_notice(&state, &room_guard, &user_guard, "Disconnected for inactivity.");
+                        break;
+                    }
+                    None => {
+                        idle.warned = true;
+                        let user_guard = user.lock().await;
+                        let room_guard = room.lock().await;
+                        send_notice(&state, &room_guard, &user_guard, "You will be disconnected for inactivity soon, send anything (an empty line will do) to stay.");
+                        auto_away = set_away(&state, &user_guard).await;
+                        continue;
+                    }
+                };
+                idle.reset();
+                if auto_away {
+                    if let Some(name) = name.as_ref() {
+                        state.statuses.lock().await.remove(name);
+                    }
+                    auto_away = false;
+                }
+                match name.as_ref() {
+                    Some(name) if *name != message.sender => {
+                        warn!(user = %name, claimed = %message.sender, "ignoring spoofed sender");
+                        message.sender = name.clone();
+                    }
+                    Some(_) => {}
+                    None if is_servers_name(&message.sender) => {
+                        warn!(user = %message.sender, "refusing connection posing as the server");
+                        break;
+                    }
+                    None => {
+                        // checked first so the reservation of a name banned since it was reserved
+                        // is left to expire, releasing the name along with it
+                        if state.bans.lock().await.contains(&message.sender) {
+                            warn!(user = %message.sender, "refusing connection for a banned name");
+                            break;
+                        }
+                        let reserved = state.reservations.lock().await.remove(&message.sender).is_some();
+                        // a name that's taken without a reservation belongs to another connection
+                        if !reserved && state.users.read().await.contains_key(&message.sender) {
+                            warn!(user = %message.sender, "refusing connection for a name in use");
+                            break;
+                        }
+                        let _ = subscriptions.send(Subscription::User(message.sender.clone(), state.router.subscribe_user(&message.sender)));
+                        state.disconnects.lock().await.insert(message.sender.clone(), disconnect.clone());
+                        name = Some(message.sender.clone());
+                    }
+                }
+                // the room is the one the server has the user in, a client naming another one could
+                // post to or read a password protected room without the password
+                message.chatroom = state.users.read().await.get(&message.sender).cloned().unwrap_or_default();
+                // only what users send is stripped, the server's own messages are built from it later
+                message.content = strip_control(&message.content);
+                // blank lines carry nothing worth broadcasting, commands always have content
+                if message.content.trim().is_empty() {
+                    continue;
+                }
+                state.metrics.message_received();
+                let command = command::parse_with_prefix(&message.content, state.config.command_prefix);
+                let is_typing = matches!(command, Command::Typing { .. });
+                // typing events come in on their own while the user types, they don't count as flooding
+                // and are dropped quietly when they come too fast
+                if is_typing {
+                    if typing_too_fast(&state, &message.sender).await {
+                        continue;
+                    }
+                } else if let Some(remaining) = flood_mute(&state, &message.sender).await {
+                    let notice = format!("You're sending messages too fast, you're muted for {}s.", remaining.as_secs().max(1));
+                    send_notice(&state, &room.lock().await, &message.sender, &notice);
+                    continue;
+                }
+                stamp_inbound(&mut message);
                 let mut user_guard = user.lock().await;
                 *user_guard = message.sender.clone();
                 let mut room_guard = room.lock().await;
                 *room_guard = message.chatroom.clone();
+                let is_first_room = message.chatroom.is_empty();
+                let is_join = matches!(command, Command::Join { .. });
+                let joined;
 
                 {
-                    let mut guard = USERMAP.lock().await;
-                    let server_response = ChatMessage::into_response(message, &mut guard, &*TX).await;
+                    let server_response = ChatMessage::into_response(message, &state).await;
+                    // rejected joins are answered to the sender only, join announcements go to the room
+                    joined = is_join && server_response.target.is_empty();
+                    if joined {
+                        let _ = subscriptions.send(Subscription::Room(state.router.subscribe_room(&server_response.chatroom)));
+                    }
                     *room_guard = server_response.chatroom.clone();
-                    let _ = TX.send(server_response);
+                    record_history(&state, &server_response).await;
+                    count_message(&state, &server_response).await;
+                    state.router.send(server_response);
+                }
+                if joined {
+                    // replayed after the join announcement, which makes clients clear the screen
+                    replay_history(&state, &room_guard, &user_guard).await;
+                }
+                if joined && is_first_room {
+                    send_motd(&state, &room_guard, &user_guard);
+                }
+                if joined {
+                    flush_offline_messages(&state, &user_guard).await;
                 }
             }
             {
                 let user_guard = user.lock().await;
                 let room_guard = room.lock().await;
-                remove_user_from_map(&user_guard).await;
-                send_disconnect_message(&room_guard, &user_guard);
+                info!(user = %user_guard, "client disconnected");
+                state.metrics.connection_closed();
+                remove_user_from_map(&state, &user_guard).await;
+                send_disconnect_message(&state, &room_guard, &user_guard);
             }
-        });
+        }.instrument(span));
 
         let output_stream = async_stream::try_stream! {
-            while let Ok(message) = TX.subscribe().recv().await {
-                {
-                    let room = room_copy.lock().await;
-                    let user = user_copy.lock().await;
-                    if (message.target.is_empty()) {
-                        if message.chatroom == *room {
-                            yield message;
-                        }
-                    } else if (message.target == *user || message.sender == *user) {
-                        yield message;
-                    }
+            let mut inbox = Inbox::new(subscription_rx);
+            while let Some(message) = inbox.next().await {
+                if message.kind() == Kind::System && !message.target.is_empty() && message.chatroom.is_empty() {
+                    // the server moved this user back to the lobby, e.g. after a kick
+                    room_copy.lock().await.clear();
+                    inbox.leave_room();
+                }
+                let receipt = delivery_receipt(&message, inbox.user());
+                yield message;
+                if let Some(receipt) = receipt {
+                    state_copy.router.send(receipt);
                 }
             }
         };
@@ -100,40 +456,406 @@ impl Chat for ChatService {
     }
 }
 
-async fn remove_user_from_map(user: &String) {
-    let mut guard = USERMAP.lock().await;
-    guard.remove(user);
+/// Tracks when a connection last sent something. Any inbound message counts as activity,
+/// including the empty lines clients send when enter is pressed on an empty prompt, so users
+/// who are only reading get a warning first and can stay connected by answering it.
+struct IdleTimer {
+    timeout: Option<Duration>,
+    last_activity: Instant,
+    warned: bool,
+}
+
+impl IdleTimer {
+    fn new(timeout: Option<Duration>) -> IdleTimer {
+        IdleTimer {
+            timeout,
+            last_activity: Instant::now(),
+            warned: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_activity = Instant::now();
+        self.warned = false;
+    }
+
+    /// Resolves to the output of `next`, or to `None` once the next idle deadline passes first,
+    /// which is the warning until one was sent and the disconnect after that.
+    async fn watch<F: std::future::Future>(&self, next: F) -> Option<F::Output> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Some(next.await),
+        };
+        let deadline = if self.warned {
+            self.last_activity + timeout
+        } else {
+            self.last_activity + timeout - IDLE_WARNING.min(timeout / 2)
+        };
+        tokio::time::timeout_at(deadline.into(), next).await.ok()
+    }
+}
+
+/// What tells the sender of a direct message that it was handed to the connection of `user`,
+/// if it's a direct message to them. Senders get a copy of their direct messages too, only the
+/// recipient's is confirmed.
+fn delivery_receipt(message: &ChatMessage, user: Option<&str>) -> Option<ChatMessage> {
+    let delivered = message.kind() == Kind::DirectMessage
+        && message.sender != message.target
+        && user == Some(message.target.as_str());
+    if !delivered {
+        return None;
+    }
+    Some(ChatMessage {
+        sender: "server".to_string(),
+        timestamp: timestamp(),
+        chatroom: message.chatroom.clone(),
+        content: format!("Delivered to {}.", message.target),
+        target: message.sender.clone(),
+        style: Style::Notice as i32,
+        kind: Kind::Delivered as i32,
+        id: message.id.clone(),
+        ..Default::default()
+    })
+}
+
+async fn reserve_name(state: &Arc<ServerState>, name: String) {
+    let reserved_at = Instant::now();
+    state
+        .reservations
+        .lock()
+        .await
+        .insert(name.clone(), reserved_at);
+    let state = state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(NAME_RESERVATION_TIMEOUT).await;
+        let mut users = state.users.write().await;
+        let mut reservations = state.reservations.lock().await;
+        // only release the name if this exact reservation was never claimed
+        if reservations.get(&name) == Some(&reserved_at) {
+            reservations.remove(&name);
+            users.remove(&name);
+        }
+    });
+}
+
+/// Whether users would take `name` for the server, which sends its notices under it.
+fn is_servers_name(name: &str) -> bool {
+    name.trim().eq_ignore_ascii_case("server")
+}
+
+/// The error to refuse a request with if it comes from a banned address.
+fn refuse_banned_address(state: &ServerState, remote: Option<SocketAddr>) -> Option<Status> {
+    let ip = remote?.ip();
+    if !state.config.banned_ips.contains(&ip) {
+        return None;
+    }
+    warn!(%ip, "refusing a banned address");
+    Some(Status::permission_denied("this address is banned"))
+}
+
+/// The error to refuse a name check or chat connection with if it comes from a banned address,
+/// or from one that opened `connection_limit` of them within the last `connection_window`.
+///
+/// The address is the TCP peer's. Behind a reverse proxy that's the proxy's for every client, so
+/// the limit should be left to the proxy there. Taking the client's address from metadata the
+/// proxy adds, like `x-forwarded-for`, would let any client without a proxy claim another's.
+async fn refuse_connection(state: &ServerState, remote: Option<SocketAddr>) -> Option<Status> {
+    if let Some(refusal) = refuse_banned_address(state, remote) {
+        return Some(refusal);
+    }
+    let config = &state.config;
+    // requests that don't come in over TCP have no address to limit
+    let ip = remote?.ip();
+    if config.connection_limit == 0 {
+        return None;
+    }
+    let now = Instant::now();
+    let mut attempts = state.connection_attempts.lock().await;
+    attempts.retain(|_, recent| {
+        while recent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= config.connection_window)
+        {
+            recent.pop_front();
+        }
+        !recent.is_empty()
+    });
+    let recent = attempts.entry(ip).or_default();
+    if recent.len() >= config.connection_limit {
+        warn!(%ip, "refusing an address connecting too often");
+        let wait = config.connection_window - now.duration_since(recent[0]);
+        return Some(Status::resource_exhausted(format!(
+            "too many connections, try again in {}s",
+            wait.as_secs().max(1)
+        )));
+    }
+    recent.push_back(now);
+    None
+}
+
+/// How long `client` still has to wait before posting with `send_message` again, if at all.
+async fn bot_throttle(state: &ServerState, client: String) -> Option<Duration> {
+    let now = Instant::now();
+    let mut posts = state.bot_messages.lock().await;
+    let interval = state.config.bot_message_interval;
+    posts.retain(|_, last| now.duration_since(*last) < interval);
+    if let Some(last) = posts.get(&client) {
+        return Some(interval - now.duration_since(*last));
+    }
+    posts.insert(client, now);
+    None
+}
+
+/// How long `client` still has to wait before reading the history with `get_history` again, if
+/// at all. Each read goes through the room's part of the history file, so they're held to the
+/// same `flood_messages` per `flood_window` as chat messages.
+async fn history_throttle(state: &ServerState, client: String) -> Option<Duration> {
+    let config = &state.config;
+    if config.flood_messages == 0 {
+        return None;
+    }
+    let now = Instant::now();
+    let mut requests = state.history_requests.lock().await;
+    requests.retain(|_, recent| {
+        recent
+            .back()
+            .is_some_and(|at| now.duration_since(*at) < config.flood_window)
+    });
+    let recent = requests.entry(client).or_default();
+    while recent
+        .front()
+        .is_some_and(|at| now.duration_since(*at) >= config.flood_window)
+    {
+        recent.pop_front();
+    }
+    if recent.len() >= config.flood_messages {
+        return Some(config.flood_window - now.duration_since(recent[0]));
+    }
+    recent.push_back(now);
+    None
+}
+
+/// How much longer `user` is muted for flooding, starting a mute if this message is one too
+/// many within the flood window. `None` lets the message through.
+async fn flood_mute(state: &ServerState, user: &str) -> Option<Duration> {
+    let config = &state.config;
+    if config.flood_messages == 0 {
+        return None;
+    }
+    let now = Instant::now();
+    let mut mutes = state.mutes.lock().await;
+    mutes.retain(|_, until| *until > now);
+    if let Some(until) = mutes.get(user) {
+        return Some(*until - now);
+    }
+    let mut recent_messages = state.recent_messages.lock().await;
+    let recent = recent_messages.entry(user.to_string()).or_default();
+    while recent
+        .front()
+        .is_some_and(|at| now.duration_since(*at) >= config.flood_window)
+    {
+        recent.pop_front();
+    }
+    recent.push_back(now);
+    if recent.len() > config.flood_messages {
+        recent.clear();
+        warn!(%user, "muting user for flooding");
+        mutes.insert(user.to_string(), now + config.flood_mute);
+        return Some(config.flood_mute);
+    }
+    None
 }
 
-fn send_disconnect_message(room: &String, user: &String) {
-    let _ = TX.send(
-        ChatMessage { 
-            sender: "server".to_string(), 
-            timestamp: timestamp(), 
-            chatroom: room.to_string(), 
-            content: format!("-- {} has left.", user).truecolor(153, 140, 139).to_string(),
-            target: String::new()
+/// Whether a typing event from `user` comes within `TYPING_INTERVAL` of their last relayed one.
+async fn typing_too_fast(state: &ServerState, user: &str) -> bool {
+    let now = Instant::now();
+    let mut typing_events = state.typing_events.lock().await;
+    if typing_events
+        .get(user)
+        .is_some_and(|last| now.duration_since(*last) < TYPING_INTERVAL)
+    {
+        return true;
+    }
+    typing_events.insert(user.to_string(), now);
+    false
+}
+
+async fn count_message(state: &ServerState, message: &ChatMessage) {
+    match message.kind() {
+        Kind::Chat | Kind::Action => {
+            state.total_messages.fetch_add(1, Ordering::Relaxed);
+            let mut room_messages = state.room_messages.lock().await;
+            *room_messages.entry(message.chatroom.clone()).or_default() += 1;
+        }
+        Kind::DirectMessage => {
+            state.direct_messages.fetch_add(1, Ordering::Relaxed);
         }
+        _ => {}
+    }
+}
+
+/// Keeps chat messages for replaying to users joining the room and, along with edits and
+/// deletions, in the persistent history. The recent messages are already changed by `!edit`
+/// and `!delete` themselves.
+async fn record_history(state: &ServerState, message: &ChatMessage) {
+    let is_chat = matches!(message.kind(), Kind::Chat | Kind::Action);
+    if is_chat {
+        let mut history = state.room_history.lock().await;
+        let room_history = history.entry(message.chatroom.clone()).or_default();
+        if room_history.len() >= HISTORY_LENGTH {
+            if let Some(dropped) = room_history.pop_front() {
+                state.reactions.lock().await.remove(&dropped.id);
+            }
+        }
+        room_history.push_back(message.clone());
+    }
+    if is_chat || matches!(message.kind(), Kind::Edit | Kind::Delete) {
+        if let Some(store) = state.history_store.as_ref() {
+            if let Err(e) = store.record(message) {
+                error!(error = %e, "couldn't persist message");
+            }
+        }
+    }
+}
+
+async fn replay_history(state: &ServerState, room: &str, user: &str) {
+    let history = state.room_history.lock().await;
+    let reactions = state.reactions.lock().await;
+    for message in history.get(room).into_iter().flatten() {
+        let reactions = reactions.get(&message.id).cloned().unwrap_or_default();
+        state.router.send_to_user(
+            user,
+            ChatMessage {
+                reactions,
+                ..message.clone()
+            },
+        );
+    }
+}
+
+fn send_motd(state: &ServerState, room: &String, user: &String) {
+    if let Some(motd) = state.config.motd.as_ref() {
+        state.router.send(ChatMessage {
+            sender: "server".to_string(),
+            timestamp: timestamp(),
+            chatroom: room.to_string(),
+            content: motd.to_string(),
+            target: user.to_string(),
+            style: Style::Highlight as i32,
+            kind: Kind::System as i32,
+            ..Default::default()
+        });
+    }
+}
+
+async fn flush_offline_messages(state: &ServerState, user: &String) {
+    let mut guard = state.offline_dms.lock().await;
+    if let Some(queued) = guard.remove(user) {
+        for message in queued {
+            state.router.send(message);
+        }
+    }
+}
+
+async fn remove_user_from_map(state: &ServerState, user: &String) {
+    let mut guard = state.users.write().await;
+    if let Some(room) = guard.remove(user) {
+        forget_room_if_empty(&room, &guard, state).await;
+    }
+    state.statuses.lock().await.remove(user);
+    state.recent_messages.lock().await.remove(user);
+    state.typing_events.lock().await.remove(user);
+    state.disconnects.lock().await.remove(user);
+}
+
+/// Marks an idle user as away unless they set a status themselves, true if it did.
+async fn set_away(state: &ServerState, user: &str) -> bool {
+    let mut statuses = state.statuses.lock().await;
+    if user.is_empty() || statuses.contains_key(user) {
+        return false;
+    }
+    statuses.insert(user.to_string(), AWAY.to_string());
+    true
+}
+
+fn send_notice(state: &ServerState, room: &str, user: &str, notice: &str) {
+    state.router.send_to_user(
+        user,
+        ChatMessage {
+            sender: "server".to_string(),
+            timestamp: timestamp(),
+            // the current room, an empty one would tell the client it was moved to the lobby
+            chatroom: room.to_string(),
+            content: notice.to_string(),
+            target: user.to_string(),
+            style: Style::Notice as i32,
+            kind: Kind::System as i32,
+            ..Default::default()
+        },
     );
 }
 
+fn send_disconnect_message(state: &ServerState, room: &String, user: &String) {
+    state.router.send(ChatMessage {
+        sender: "server".to_string(),
+        timestamp: timestamp(),
+        chatroom: room.to_string(),
+        content: format!("-- {} has left.", user),
+        target: String::new(),
+        style: Style::Notice as i32,
+        kind: Kind::Leave as i32,
+        ..Default::default()
+    });
+}
+
 pub fn timestamp() -> i64 {
     let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time broke..");
-    time.as_millis() as i64    
+        .duration_since(UNIX_EPOCH)
+        .expect("Time broke..");
+    time.as_millis() as i64
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::1]:50051".parse().unwrap();
-    let service = ChatService::default();
-    
-    print!("\x1B[2J\x1B[1;1H");
-    println!("GrpcServer listening on {}", addr);
+    logging::init();
+    // the config file is the only argument
+    let config = match Config::load(env::args().nth(1).as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!(error = %e, "invalid configuration");
+            std::process::exit(1);
+        }
+    };
+    let addr = config.bind_addr;
+    let metrics_addr = config.metrics_addr;
+    let state = Arc::new(ServerState::new(config));
+    let max_decoding_message_size = state.config.max_message_chars * 4 + 1024;
+    let reflection = state
+        .config
+        .reflection
+        .then(|| ServerReflectionServer::new(Reflection::default()));
+    let compression = state.config.compression;
+    let metrics = state.metrics.clone();
+    let service = ChatService::new(state);
+    info!(%addr, "GrpcServer listening");
+
+    info!(%metrics_addr, "metrics listening");
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_addr, metrics).await {
+            error!(error = %e, "metrics server stopped");
+        }
+    });
 
     Server::builder()
-        .add_service(ChatServer::new(service))
+        .http2_keepalive_interval(Some(KEEPALIVE_INTERVAL))
+        .http2_keepalive_timeout(Some(KEEPALIVE_TIMEOUT))
+        .add_service(Gzip::new(
+            // a char is at most 4 bytes in UTF-8, the rest is headroom for the other fields
+            ChatServer::new(service).max_decoding_message_size(max_decoding_message_size),
+            compression,
+            max_decoding_message_size,
+        ))
+        .add_optional_service(reflection)
         .serve(addr)
         .await?;
 
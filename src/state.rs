@@ -0,0 +1,680 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Mutex, Notify, RwLock};
+use tracing::{error, warn};
+
+use super::ban_list::BanList;
+use super::chat::Reaction;
+use super::config_file::ConfigFile;
+use super::history_store::HistoryStore;
+use super::message_parser::RoomPassword;
+use super::metrics::Metrics;
+use super::router::Router;
+use super::word_filter::WordFilter;
+use super::ChatMessage;
+
+/// Where the chat service and the Prometheus metrics are served by default.
+const DEFAULT_BIND_ADDR: &str = "[::1]:50051";
+const DEFAULT_METRICS_ADDR: &str = "[::1]:9100";
+
+/// Default number of messages a room or user channel buffers for a slow connection.
+const DEFAULT_CHANNEL_CAPACITY: usize = 100;
+
+/// Default cap on the number of characters in a single message's content.
+const DEFAULT_MAX_MESSAGE_CHARS: usize = 2000;
+
+/// Default cap on the number of users in a single room.
+const DEFAULT_MAX_ROOM_USERS: usize = 50;
+
+/// How long a connection may go without sending anything unless `NC_IDLE_TIMEOUT_SECS` says
+/// otherwise, `0` disables the timeout.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Where room messages are persisted unless `NC_HISTORY_PATH` says otherwise.
+const DEFAULT_HISTORY_PATH: &str = "chat_history.jsonl";
+
+/// Where the names banned with `!ban` are kept unless `NC_BAN_LIST_PATH` says otherwise.
+const DEFAULT_BAN_LIST_PATH: &str = "banned_users.txt";
+
+/// How long a user has to wait between two `!value` commands by default, so a single user can't
+/// get the server rate limited by Binance.
+const DEFAULT_VALUE_COOLDOWN: Duration = Duration::from_secs(5);
+
+/// How often a single client address may post with `send_message` by default.
+const DEFAULT_BOT_MESSAGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Users sending more than this many messages within `DEFAULT_FLOOD_WINDOW` are muted for
+/// `DEFAULT_FLOOD_MUTE` by default.
+const DEFAULT_FLOOD_MESSAGES: usize = 10;
+const DEFAULT_FLOOD_WINDOW: Duration = Duration::from_secs(5);
+const DEFAULT_FLOOD_MUTE: Duration = Duration::from_secs(30);
+
+/// Name checks and chat connections a single address may open within
+/// `DEFAULT_CONNECTION_WINDOW` by default, a client opens one of each to connect.
+const DEFAULT_CONNECTION_LIMIT: usize = 20;
+const DEFAULT_CONNECTION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Settings of the config file, each overridden by the environment variable next to it.
+const SETTINGS: &[(&str, &str)] = &[
+    ("bind_addr", "NC_BIND_ADDR"),
+    ("metrics_addr", "NC_METRICS_ADDR"),
+    ("channel_capacity", "NC_CHANNEL_CAPACITY"),
+    ("max_message_chars", "NC_MAX_MESSAGE_CHARS"),
+    ("max_room_users", "NC_MAX_ROOM_USERS"),
+    ("idle_timeout_secs", "NC_IDLE_TIMEOUT_SECS"),
+    ("motd_path", "NC_MOTD_PATH"),
+    ("history_path", "NC_HISTORY_PATH"),
+    ("ban_list_path", "NC_BAN_LIST_PATH"),
+    ("reflection", "NC_REFLECTION"),
+    ("compression", "NC_COMPRESSION"),
+    ("value_cooldown_secs", "NC_VALUE_COOLDOWN_SECS"),
+    ("bot_message_interval_secs", "NC_BOT_MESSAGE_INTERVAL_SECS"),
+    ("flood_messages", "NC_FLOOD_MESSAGES"),
+    ("flood_window_secs", "NC_FLOOD_WINDOW_SECS"),
+    ("flood_mute_secs", "NC_FLOOD_MUTE_SECS"),
+    ("wiki_extract_chars", "NC_WIKI_EXTRACT_CHARS"),
+    ("banned_words_path", "NC_BANNED_WORDS_PATH"),
+    ("word_filter", "NC_WORD_FILTER"),
+    ("admins", "NC_ADMINS"),
+    ("banned_ips", "NC_BANNED_IPS"),
+    ("connection_limit", "NC_CONNECTION_LIMIT"),
+    ("connection_window_secs", "NC_CONNECTION_WINDOW_SECS"),
+    ("command_prefix", "NC_COMMAND_PREFIX"),
+];
+
+/// Base URLs of the external APIs behind `!value`, `!news`, `!weather`, `!joke`, `!wiki` and
+/// `!crates`.
+const BINANCE_API: &str = "https://api4.binance.com";
+const HACKER_NEWS_API: &str = "https://hacker-news.firebaseio.com";
+const GEOCODING_API: &str = "https://geocoding-api.open-meteo.com";
+const WEATHER_API: &str = "https://api.open-meteo.com";
+const JOKES_API: &str = "https://official-joke-api.appspot.com";
+const WIKIPEDIA_API: &str = "https://en.wikipedia.org";
+const CRATES_API: &str = "https://crates.io";
+
+/// Sent with every request to an external API, crates.io refuses requests without one that
+/// says who is asking.
+const USER_AGENT: &str = "nc_server (https://github.com/sbraitsch/conveRS)";
+
+/// Default number of characters of a Wikipedia extract shown by `!wiki`.
+const DEFAULT_WIKI_EXTRACT_CHARS: usize = 300;
+
+/// Settings of a server instance. The defaults don't persist anything, `Config::load` is what
+/// the binary runs with.
+pub struct Config {
+    /// Address the chat service listens on.
+    pub bind_addr: SocketAddr,
+    /// Address the Prometheus metrics are served on.
+    pub metrics_addr: SocketAddr,
+    /// Messages buffered per room and user channel, a connection lagging further behind
+    /// misses some.
+    pub channel_capacity: usize,
+    /// Character limit for message content.
+    pub max_message_chars: usize,
+    /// User cap per room.
+    pub max_room_users: usize,
+    /// Idle period after which a connection is closed, `None` never closes idle ones.
+    pub idle_timeout: Option<Duration>,
+    /// Message of the day shown to users entering their first room.
+    pub motd: Option<String>,
+    /// File the message history is persisted to.
+    pub history_path: Option<String>,
+    /// File the ban list is persisted to, bans are forgotten on restart without one.
+    pub ban_list_path: Option<String>,
+    /// Base URL of the Binance API, tests point this and the other APIs at a local mock.
+    pub binance_api: String,
+    pub hacker_news_api: String,
+    /// Base URLs of Open-Meteo's geocoding and forecast APIs.
+    pub geocoding_api: String,
+    pub weather_api: String,
+    /// Base URL of the Official Joke API.
+    pub jokes_api: String,
+    /// Base URL of Wikipedia, whose REST API serves the article summaries.
+    pub wikipedia_api: String,
+    /// Base URL of crates.io.
+    pub crates_api: String,
+    /// Characters of an article's extract shown by `!wiki`, longer ones are cut off.
+    pub wiki_extract_chars: usize,
+    /// Whether the gRPC reflection service is served, for exploring the API with tools like
+    /// `grpcurl`.
+    pub reflection: bool,
+    /// Whether messages to clients that accept gzip are sent compressed.
+    pub compression: bool,
+    /// How long a user waits between two `!value` lookups.
+    pub value_cooldown: Duration,
+    /// How often a single client address may post with `send_message`.
+    pub bot_message_interval: Duration,
+    /// Messages a user may send within `flood_window` before being muted for `flood_mute`,
+    /// zero turns the flood protection off. Also how often a client address may call
+    /// `get_history` within the window.
+    pub flood_messages: usize,
+    pub flood_window: Duration,
+    pub flood_mute: Duration,
+    /// Words kept out of chat, `None` unless a wordlist is configured.
+    pub word_filter: Option<WordFilter>,
+    /// Names allowed to use admin commands like `!announce`.
+    pub admins: Vec<String>,
+    /// Addresses whose requests are all refused.
+    pub banned_ips: Vec<IpAddr>,
+    /// Name checks and chat connections an address may open within `connection_window`, zero
+    /// turns the limit off.
+    pub connection_limit: usize,
+    pub connection_window: Duration,
+    /// What commands start with instead of `!`, clients learn it from `check_for_name`.
+    pub command_prefix: char,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind_addr: DEFAULT_BIND_ADDR.parse().unwrap(),
+            metrics_addr: DEFAULT_METRICS_ADDR.parse().unwrap(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            max_message_chars: DEFAULT_MAX_MESSAGE_CHARS,
+            max_room_users: DEFAULT_MAX_ROOM_USERS,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            motd: None,
+            history_path: None,
+            ban_list_path: None,
+            binance_api: BINANCE_API.to_string(),
+            hacker_news_api: HACKER_NEWS_API.to_string(),
+            geocoding_api: GEOCODING_API.to_string(),
+            weather_api: WEATHER_API.to_string(),
+            jokes_api: JOKES_API.to_string(),
+            wikipedia_api: WIKIPEDIA_API.to_string(),
+            crates_api: CRATES_API.to_string(),
+            wiki_extract_chars: DEFAULT_WIKI_EXTRACT_CHARS,
+            reflection: false,
+            compression: true,
+            value_cooldown: DEFAULT_VALUE_COOLDOWN,
+            bot_message_interval: DEFAULT_BOT_MESSAGE_INTERVAL,
+            flood_messages: DEFAULT_FLOOD_MESSAGES,
+            flood_window: DEFAULT_FLOOD_WINDOW,
+            flood_mute: DEFAULT_FLOOD_MUTE,
+            word_filter: None,
+            admins: Vec::new(),
+            banned_ips: Vec::new(),
+            connection_limit: DEFAULT_CONNECTION_LIMIT,
+            connection_window: DEFAULT_CONNECTION_WINDOW,
+            command_prefix: '!',
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file at `path`, if any, with each setting overridden by its environment
+    /// variable, e.g. `NC_MAX_ROOM_USERS` for `max_room_users`. Unset ones keep their defaults,
+    /// history is persisted to `chat_history.jsonl`, reflection is off and compression on. Any
+    /// value that doesn't make sense is an error, so the server doesn't start with settings it
+    /// ignores.
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        let file = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("couldn't read {}: {}", path, e))?;
+                ConfigFile::parse(&text).map_err(|e| format!("{}: {}", path, e))?
+            }
+            None => ConfigFile::default(),
+        };
+        Config::from_settings(&Settings {
+            file: &file,
+            env: &|var| env::var(var).ok(),
+        })
+    }
+
+    fn from_settings(settings: &Settings) -> Result<Config, String> {
+        let known: Vec<&str> = SETTINGS.iter().map(|(key, _)| *key).collect();
+        let unknown = settings.file.unknown_keys(&known);
+        if !unknown.is_empty() {
+            return Err(format!("unknown settings {}", unknown.join(", ")));
+        }
+        let defaults = Config::default();
+        // a motd that can't be read is left out, it's not worth refusing to start over
+        let motd = match settings.string("motd_path")? {
+            Some(path) => match fs::read_to_string(&path) {
+                Ok(motd) => Some(motd.trim_end().to_string()).filter(|motd| !motd.is_empty()),
+                Err(e) => {
+                    warn!(error = %e, %path, "couldn't read the motd, going without");
+                    None
+                }
+            },
+            None => None,
+        };
+        // banned words are masked unless `word_filter` is "reject"
+        let reject = match settings.string("word_filter")?.as_deref() {
+            None | Some("mask") => false,
+            Some("reject") => true,
+            Some(other) => {
+                return Err(format!(
+                    "word_filter is {:?}, it must be \"mask\" or \"reject\"",
+                    other
+                ))
+            }
+        };
+        let word_filter = match settings.string("banned_words_path")? {
+            Some(path) => {
+                let wordlist = fs::read_to_string(&path)
+                    .map_err(|e| format!("couldn't read the banned words {}: {}", path, e))?;
+                Some(WordFilter::new(&wordlist, reject))
+            }
+            None => None,
+        };
+        // a comma-separated list like the admins
+        let banned_ips = match settings.string("banned_ips")? {
+            Some(ips) => ips
+                .split(',')
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty())
+                .map(|ip| {
+                    ip.parse()
+                        .map_err(|_| format!("{} in banned_ips isn't an IP address", ip))
+                })
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        // a single character that can't start a word, or every message with it would be one
+        let command_prefix = match settings.string("command_prefix")? {
+            Some(prefix) => {
+                let mut chars = prefix.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if !c.is_alphanumeric() && !c.is_whitespace() => c,
+                    _ => {
+                        return Err(format!(
+                            "command_prefix is {:?}, it must be a single symbol like ! or /",
+                            prefix
+                        ))
+                    }
+                }
+            }
+            None => defaults.command_prefix,
+        };
+        let config = Config {
+            bind_addr: settings.parsed("bind_addr")?.unwrap_or(defaults.bind_addr),
+            metrics_addr: settings
+                .parsed("metrics_addr")?
+                .unwrap_or(defaults.metrics_addr),
+            channel_capacity: settings
+                .number("channel_capacity")?
+                .unwrap_or(defaults.channel_capacity),
+            max_message_chars: settings
+                .number("max_message_chars")?
+                .unwrap_or(defaults.max_message_chars),
+            max_room_users: settings
+                .number("max_room_users")?
+                .unwrap_or(defaults.max_room_users),
+            // zero disables the timeout
+            idle_timeout: match settings.number("idle_timeout_secs")? {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs as u64)),
+                None => defaults.idle_timeout,
+            },
+            motd,
+            history_path: Some(
+                settings
+                    .string("history_path")?
+                    .unwrap_or(DEFAULT_HISTORY_PATH.to_string()),
+            ),
+            ban_list_path: Some(
+                settings
+                    .string("ban_list_path")?
+                    .unwrap_or(DEFAULT_BAN_LIST_PATH.to_string()),
+            ),
+            reflection: settings.boolean("reflection")?.unwrap_or(false),
+            compression: settings
+                .boolean("compression")?
+                .unwrap_or(defaults.compression),
+            value_cooldown: settings
+                .number("value_cooldown_secs")?
+                .map_or(defaults.value_cooldown, |secs| {
+                    Duration::from_secs(secs as u64)
+                }),
+            bot_message_interval: settings
+                .number("bot_message_interval_secs")?
+                .map_or(defaults.bot_message_interval, |secs| {
+                    Duration::from_secs(secs as u64)
+                }),
+            flood_messages: settings
+                .number("flood_messages")?
+                .unwrap_or(defaults.flood_messages),
+            flood_window: settings
+                .number("flood_window_secs")?
+                .map_or(defaults.flood_window, |secs| {
+                    Duration::from_secs(secs as u64)
+                }),
+            flood_mute: settings
+                .number("flood_mute_secs")?
+                .map_or(defaults.flood_mute, |secs| Duration::from_secs(secs as u64)),
+            wiki_extract_chars: settings
+                .number("wiki_extract_chars")?
+                .unwrap_or(defaults.wiki_extract_chars),
+            word_filter,
+            // a comma-separated list of names
+            admins: settings
+                .string("admins")?
+                .map(|admins| {
+                    admins
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|admin| !admin.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            banned_ips,
+            connection_limit: settings
+                .number("connection_limit")?
+                .unwrap_or(defaults.connection_limit),
+            connection_window: settings
+                .number("connection_window_secs")?
+                .map_or(defaults.connection_window, |secs| {
+                    Duration::from_secs(secs as u64)
+                }),
+            command_prefix,
+            ..defaults
+        };
+        for (key, value) in [
+            ("channel_capacity", config.channel_capacity),
+            ("max_message_chars", config.max_message_chars),
+            ("max_room_users", config.max_room_users),
+            ("wiki_extract_chars", config.wiki_extract_chars),
+        ] {
+            if value == 0 {
+                return Err(format!("{} must be at least 1", key));
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Where `Config::from_settings` looks up settings: the environment first, then the file.
+struct Settings<'a> {
+    file: &'a ConfigFile,
+    env: &'a dyn Fn(&str) -> Option<String>,
+}
+
+impl Settings<'_> {
+    fn var(key: &str) -> &'static str {
+        SETTINGS
+            .iter()
+            .find(|(setting, _)| *setting == key)
+            .map(|(_, var)| *var)
+            .expect("every setting has an environment variable")
+    }
+
+    fn string(&self, key: &str) -> Result<Option<String>, String> {
+        match (self.env)(Settings::var(key)) {
+            Some(value) => Ok(Some(value)),
+            None => self.file.string(key),
+        }
+    }
+
+    fn parsed<T: FromStr>(&self, key: &str) -> Result<Option<T>, String> {
+        self.string(key)?
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("{} isn't a valid {}", value, key))
+            })
+            .transpose()
+    }
+
+    fn number(&self, key: &str) -> Result<Option<usize>, String> {
+        let var = Settings::var(key);
+        let number = match (self.env)(var) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| format!("{} must be a whole number, not {:?}", var, value))?,
+            None => match self.file.integer(key)? {
+                Some(number) => {
+                    usize::try_from(number).map_err(|_| format!("{} can't be negative", key))?
+                }
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(number))
+    }
+
+    fn boolean(&self, key: &str) -> Result<Option<bool>, String> {
+        let var = Settings::var(key);
+        match (self.env)(var).as_deref() {
+            Some("1" | "true") => Ok(Some(true)),
+            Some("0" | "false") => Ok(Some(false)),
+            Some(value) => Err(format!("{} must be true or false, not {:?}", var, value)),
+            None => self.file.boolean(key),
+        }
+    }
+}
+
+/// Everything a server instance shares between its connections, so several instances can
+/// run side by side in one process.
+pub struct ServerState {
+    pub config: Config,
+    /// When the server started, for `!uptime`.
+    pub started: Instant,
+    pub router: Router,
+    pub metrics: Arc<Metrics>,
+    /// Persistent message history, `None` if there's no path or the store couldn't be opened.
+    pub history_store: Option<Arc<HistoryStore>>,
+    /// Shared by all requests to external APIs, setting up a client loads the TLS certificates
+    /// and would block the runtime for every command.
+    pub http_client: reqwest::Client,
+    /// Room of each connected user, empty while they are in the lobby. Most messages only read
+    /// it, so it's write locked just for joining, kicking, and claiming or releasing names.
+    pub users: RwLock<HashMap<String, String>>,
+    /// Names reserved by `check_for_name` that no connection has claimed yet.
+    pub reservations: Mutex<HashMap<String, Instant>>,
+    /// Topic of each room that has one set, keyed by room name.
+    pub topics: Mutex<HashMap<String, String>>,
+    /// Password hashes of the rooms that were created with `!join <room> <password>`.
+    pub room_passwords: Mutex<HashMap<String, RoomPassword>>,
+    /// Presence of each user that isn't simply online, like `away` or `busy`.
+    pub statuses: Mutex<HashMap<String, String>>,
+    /// Moderator of each room, the first user to join it while it was empty.
+    pub moderators: Mutex<HashMap<String, String>>,
+    /// The last `HISTORY_LENGTH` chat messages of each room, DMs and server notices excluded.
+    pub room_history: Mutex<HashMap<String, VecDeque<ChatMessage>>>,
+    /// Reactions to the messages in `room_history`, keyed by message id. Dropped along with
+    /// their message.
+    pub reactions: Mutex<HashMap<String, Vec<Reaction>>>,
+    /// Direct messages addressed to users that weren't connected, keyed by recipient.
+    pub offline_dms: Mutex<HashMap<String, Vec<ChatMessage>>>,
+    /// Whose direct messages each user blocked with `!block`, keyed by the blocking user. Kept
+    /// in memory only, like the user names they're about.
+    pub blocks: Mutex<HashMap<String, HashSet<String>>>,
+    /// When each user last asked Binance for a price with `!value`.
+    pub value_requests: Mutex<HashMap<String, Instant>>,
+    /// Until when Binance is left alone after it rate limited the server.
+    pub binance_backoff: Mutex<Option<Instant>>,
+    /// When each client address last posted with `send_message`.
+    pub bot_messages: Mutex<HashMap<String, Instant>>,
+    /// When each client address read the history with `get_history` within the last
+    /// `flood_window`.
+    pub history_requests: Mutex<HashMap<String, VecDeque<Instant>>>,
+    /// Messages sent to each room since startup, for `!stats`. Only chat and `!me` actions count,
+    /// commands and what the server says don't.
+    pub room_messages: Mutex<HashMap<String, u64>>,
+    /// The same across all rooms, and the direct messages, which aren't broken down by room.
+    pub total_messages: AtomicU64,
+    pub direct_messages: AtomicU64,
+    /// When each connected user sent their messages within the last `flood_window`.
+    pub recent_messages: Mutex<HashMap<String, VecDeque<Instant>>>,
+    /// When each connected user last had a typing event relayed.
+    pub typing_events: Mutex<HashMap<String, Instant>>,
+    /// Until when each user caught flooding is muted.
+    pub mutes: Mutex<HashMap<String, Instant>>,
+    pub bans: Mutex<BanList>,
+    /// When each address recently opened a name check or chat connection, oldest first.
+    pub connection_attempts: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    /// Wakes the input loop of each connected user to close their connection, e.g. after a ban.
+    pub disconnects: Mutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl ServerState {
+    /// Whether `user` is one of the configured admins. Names aren't authenticated, so this only
+    /// holds as long as nobody else can connect under an admin's name.
+    pub fn is_admin(&self, user: &str) -> bool {
+        self.config.admins.iter().any(|admin| admin == user)
+    }
+
+    pub fn new(config: Config) -> ServerState {
+        let history_store = config.history_path.as_ref().and_then(|path| {
+            HistoryStore::open(path)
+                .map_err(|e| {
+                    error!(%path, error = %e, "couldn't open history store, history won't be persisted");
+                })
+                .ok()
+                .map(Arc::new)
+        });
+        let path = config.ban_list_path.as_ref().map(PathBuf::from);
+        let bans = BanList::open(path).unwrap_or_else(|e| {
+            error!(error = %e, "couldn't read the ban list, bans won't be persisted");
+            BanList::open(None).expect("an unsaved ban list always opens")
+        });
+        ServerState {
+            router: Router::new(config.channel_capacity),
+            config,
+            started: Instant::now(),
+
+            metrics: Arc::default(),
+            history_store,
+            http_client: reqwest::Client::builder()
+                .user_agent(USER_AGENT)
+                .build()
+                .expect("the HTTP client's TLS backend is available"),
+            users: RwLock::default(),
+            reservations: Mutex::default(),
+            topics: Mutex::default(),
+            room_passwords: Mutex::default(),
+            moderators: Mutex::default(),
+            statuses: Mutex::default(),
+            room_history: Mutex::default(),
+            reactions: Mutex::default(),
+            offline_dms: Mutex::default(),
+            blocks: Mutex::default(),
+            value_requests: Mutex::default(),
+            binance_backoff: Mutex::default(),
+            bot_messages: Mutex::default(),
+            history_requests: Mutex::default(),
+            room_messages: Mutex::default(),
+            total_messages: AtomicU64::default(),
+            direct_messages: AtomicU64::default(),
+            recent_messages: Mutex::default(),
+            typing_events: Mutex::default(),
+            mutes: Mutex::default(),
+            bans: Mutex::new(bans),
+            connection_attempts: Mutex::default(),
+            disconnects: Mutex::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(file: &str, env: &[(&str, &str)]) -> Result<Config, String> {
+        let file = ConfigFile::parse(file)?;
+        let env = |var: &str| {
+            env.iter()
+                .find(|(name, _)| *name == var)
+                .map(|(_, value)| value.to_string())
+        };
+        Config::from_settings(&Settings {
+            file: &file,
+            env: &env,
+        })
+    }
+
+    #[test]
+    fn reads_settings_from_the_file() {
+        let config = load(
+            "bind_addr = \"0.0.0.0:6000\"\nmax_room_users = 10\nidle_timeout_secs = 0\nreflection = true\n",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.bind_addr, "0.0.0.0:6000".parse().unwrap());
+        assert_eq!(config.max_room_users, 10);
+        assert_eq!(config.idle_timeout, None);
+        assert!(config.reflection);
+        assert_eq!(config.max_message_chars, DEFAULT_MAX_MESSAGE_CHARS);
+        assert_eq!(config.history_path.as_deref(), Some(DEFAULT_HISTORY_PATH));
+    }
+
+    #[test]
+    fn goes_without_a_motd_that_cant_be_read() {
+        let config = load("motd_path = \"/nonexistent/motd.txt\"", &[]).unwrap();
+        assert_eq!(config.motd, None);
+    }
+
+    #[test]
+    fn prefers_the_environment() {
+        let config = load(
+            "max_room_users = 10\nvalue_cooldown_secs = 30",
+            &[
+                ("NC_MAX_ROOM_USERS", "20"),
+                ("NC_REFLECTION", "1"),
+                ("NC_ADMINS", "alice, ,bob"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.max_room_users, 20);
+        assert_eq!(config.value_cooldown, Duration::from_secs(30));
+        assert!(config.reflection);
+        assert_eq!(config.admins, ["alice", "bob"]);
+        assert_eq!(config.command_prefix, '!');
+        let config = load("banned_ips = \"203.0.113.7, 2001:db8::1\"", &[]).unwrap();
+        assert_eq!(
+            config.banned_ips,
+            [
+                "203.0.113.7".parse::<IpAddr>().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        let error = |file, env| load(file, env).err().unwrap();
+        assert_eq!(
+            error("max_room_users = 0", &[]),
+            "max_room_users must be at least 1"
+        );
+        assert_eq!(
+            error("", &[("NC_MAX_ROOM_USERS", "lots")]),
+            "NC_MAX_ROOM_USERS must be a whole number, not \"lots\""
+        );
+        assert_eq!(
+            error("bind_addr = \"localhost\"", &[]),
+            "localhost isn't a valid bind_addr"
+        );
+        assert_eq!(error("max_users = 5", &[]), "unknown settings max_users");
+        assert_eq!(
+            error("banned_ips = \"10.0.0.1, localhost\"", &[]),
+            "localhost in banned_ips isn't an IP address"
+        );
+        assert_eq!(
+            error("channel_capacity = -1", &[]),
+            "channel_capacity can't be negative"
+        );
+        assert_eq!(
+            error("", &[("NC_COMMAND_PREFIX", "cmd")]),
+            "command_prefix is \"cmd\", it must be a single symbol like ! or /"
+        );
+        assert_eq!(
+            error("word_filter = \"block\"", &[]),
+            "word_filter is \"block\", it must be \"mask\" or \"reject\""
+        );
+        assert_eq!(
+            error("reflection = 1", &[]),
+            "reflection must be true or false"
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::HashSet;
+
+/// Banned words to keep out of chat, matched case-insensitively as whole words only, so banning
+/// `ass` leaves `class` and `assess` alone.
+pub struct WordFilter {
+    words: HashSet<String>,
+    /// Bounce messages with a banned word back to their sender instead of masking the word.
+    pub reject: bool,
+}
+
+impl WordFilter {
+    /// Reads a wordlist of one word per line, skipping blank lines and `#` comments.
+    pub fn new(wordlist: &str, reject: bool) -> WordFilter {
+        let words = wordlist
+            .lines()
+            .map(str::trim)
+            .filter(|word| !word.is_empty() && !word.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect();
+        WordFilter { words, reject }
+    }
+
+    /// `text` with every banned word replaced by as many asterisks, `None` if it has none.
+    pub fn mask(&self, text: &str) -> Option<String> {
+        let mut masked = String::with_capacity(text.len());
+        let mut found = false;
+        let mut rest = text;
+        while !rest.is_empty() {
+            let boundary = rest
+                .find(|c: char| c.is_alphanumeric() != rest.starts_with(char::is_alphanumeric))
+                .unwrap_or(rest.len());
+            let (piece, tail) = rest.split_at(boundary);
+            if self.words.contains(&piece.to_lowercase()) {
+                found = true;
+                masked.extend(piece.chars().map(|_| '*'));
+            } else {
+                masked.push_str(piece);
+            }
+            rest = tail;
+        }
+        found.then_some(masked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> WordFilter {
+        WordFilter::new("# banned\ndarn\n\n  HECK \n", false)
+    }
+
+    #[test]
+    fn masks_whole_words_regardless_of_case() {
+        assert_eq!(
+            filter().mask("Darn it, what the heck!"),
+            Some("**** it, what the ****!".to_string())
+        );
+        assert_eq!(filter().mask("darn-darn"), Some("****-****".to_string()));
+    }
+
+    #[test]
+    fn leaves_words_containing_banned_ones() {
+        assert_eq!(filter().mask("darning socks in Heckington"), None);
+        assert_eq!(filter().mask(""), None);
+    }
+}